@@ -0,0 +1,253 @@
+use crate::binary_copy_file_reader::{BinaryCopyFileReader, CopyField};
+use crate::copy_schema::{type_from_name, CopySchema};
+use crate::errors::{binary_copy_file_error, phase_error};
+use crate::gvl::{without_gvl, CancelToken};
+use crate::ruby_options::option_string;
+use log::{debug, error, info};
+use magnus::{
+    exception::arg_error, function, method, prelude::*, scan_args::scan_args, Error, RArray, RHash,
+    RModule, Ruby, Symbol, Value,
+};
+use postgres::types::Type;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// A PostgreSQL binary COPY file, e.g. one written by `write_binary_postgres_file`,
+/// read back row by row
+#[magnus::wrap(class = "CsvUtils::BinaryCopyFile")]
+pub struct BinaryCopyFile {
+    path: String,
+    types: Vec<Type>,
+}
+
+impl BinaryCopyFile {
+    // Open a file and check its header. The file doesn't record its column types, so
+    // they come from `types:`, or from the `columns:` it was written with.
+    pub fn open(args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(String,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (path,) = args.required;
+        let (options,) = args.optional;
+        let types = column_types(options)?;
+
+        info!(
+            target: "csv_utils::binary_copy_file",
+            "Opening binary COPY file {} with types {:?}",
+            path,
+            types
+        );
+
+        let result = Self { path, types };
+        result.reader()?;
+        Ok(result)
+    }
+
+    // A reader positioned after the header
+    fn reader(&self) -> Result<BinaryCopyFileReader<File>, Error> {
+        let file = File::open(&self.path).map_err(|e| {
+            Error::new(
+                magnus::exception::runtime_error(),
+                format!("Failed to open binary COPY file {}: {}", self.path, e),
+            )
+        })?;
+        let mut reader = BinaryCopyFileReader::new(self.types.clone(), file);
+        reader
+            .read_header()
+            .map_err(|e| self.read_error("Error reading binary COPY file", e))?;
+        Ok(reader)
+    }
+
+    // A malformed or truncated file raises `BinaryCopyFileError`, anything else the usual
+    // phase errors
+    fn read_error(&self, context: &str, e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                error!(
+                    target: "csv_utils::binary_copy_file",
+                    "Invalid binary COPY file {}: {}", self.path, e
+                );
+                Error::new(
+                    binary_copy_file_error(),
+                    format!("Invalid binary COPY file {}: {}", self.path, e),
+                )
+            }
+            _ => phase_error(context, e),
+        }
+    }
+
+    // Yield each row as an array of Ruby values, see `field_value`
+    pub fn each(&self) -> Result<(), Error> {
+        let ruby = Ruby::get().unwrap();
+        let block = ruby.block_proc()?;
+        let context = "Error reading binary COPY file";
+
+        let mut reader = self.reader()?;
+        let mut rows = 0;
+        while reader.read_row().map_err(|e| self.read_error(context, e))? {
+            let row = RArray::with_capacity(self.types.len());
+            for index in 0..self.types.len() {
+                let field = reader
+                    .get::<CopyField>(index)
+                    .map_err(|e| self.read_error(context, e))?;
+                row.push(field_value(&ruby, field))?;
+            }
+            block.call::<_, Value>((row,))?;
+            rows += 1;
+        }
+
+        debug!(
+            target: "csv_utils::binary_copy_file",
+            "Yielded {} rows from {}", rows, self.path
+        );
+        Ok(())
+    }
+
+    // Convert the file to CSV, with each field in PostgreSQL's text format (see
+    // `CopyField::to_text`) and NULL as `null:` (default: an empty field). `headers:`
+    // names the columns in a first line.
+    pub fn to_csv(&self, args: &[Value]) -> Result<RHash, Error> {
+        let args = scan_args::<(String,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (csv_path,) = args.required;
+        let (options,) = args.optional;
+        let (headers, null) = match options {
+            Some(options) => (
+                options.lookup::<_, Option<Vec<String>>>(Symbol::new("headers"))?,
+                option_string(options, "null")?,
+            ),
+            None => (None, None),
+        };
+        if let Some(headers) = &headers {
+            if headers.len() != self.types.len() {
+                return Err(Error::new(
+                    arg_error(),
+                    format!(
+                        "headers has {} names for {} columns",
+                        headers.len(),
+                        self.types.len()
+                    ),
+                ));
+            }
+        }
+
+        info!(
+            target: "csv_utils::binary_copy_file",
+            "Writing {} as CSV to {}", self.path, csv_path
+        );
+
+        let mut reader = self.reader()?;
+        let cancel = CancelToken::default();
+        let rows = without_gvl(&cancel, || {
+            write_csv(
+                &mut reader,
+                &csv_path,
+                headers.as_deref(),
+                null.as_deref().unwrap_or(""),
+                &cancel,
+            )
+        })
+        .and_then(|result| result.map_err(|e| self.read_error("Error writing CSV", e)))?;
+
+        let result = RHash::new();
+        result.aset(Symbol::new("total_rows"), rows)?;
+        Ok(result)
+    }
+}
+
+// `types:` as type names, or the types of `columns:` (see `CopySchema::from_options`),
+// which default to `write_binary_postgres_file`'s default layout
+fn column_types(options: Option<RHash>) -> Result<Vec<Type>, Error> {
+    let names = match options {
+        Some(options) => options.lookup::<_, Option<RArray>>(Symbol::new("types"))?,
+        None => None,
+    };
+    let names = match names {
+        Some(names) => names,
+        None => return Ok(CopySchema::from_options(options)?.types()),
+    };
+
+    if options.is_some_and(|options| options.get(Symbol::new("columns")).is_some()) {
+        return Err(Error::new(
+            arg_error(),
+            "Give either types: or columns:, not both",
+        ));
+    }
+    if names.is_empty() {
+        return Err(Error::new(arg_error(), "types must not be empty"));
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            let name = name.to_string();
+            type_from_name(&name).ok_or_else(|| {
+                error!(target: "csv_utils::binary_copy_file", "Invalid type: {}", name);
+                Error::new(arg_error(), format!("Invalid type: {}", name))
+            })
+        })
+        .collect()
+}
+
+// Integers, floats and booleans become their Ruby counterparts, timestamps `Time`,
+// BYTEA a binary string, arrays an array of strings, and everything else its text, see
+// `CopyField::to_text`
+fn field_value(ruby: &Ruby, field: CopyField) -> Value {
+    match field {
+        CopyField::Null => ruby.qnil().as_value(),
+        CopyField::Text(text) => ruby.into_value(text),
+        CopyField::Bytes(bytes) => ruby.str_from_slice(&bytes).as_value(),
+        CopyField::Int(value) => ruby.into_value(value),
+        CopyField::Float(value) => ruby.into_value(value),
+        CopyField::Bool(value) => ruby.into_value(value),
+        CopyField::Timestamp { value, .. } => ruby.into_value(SystemTime::from(value)),
+        CopyField::TextArray(values) => ruby.into_value(values),
+    }
+}
+
+// Write every row as a CSV line, returning the number of rows
+fn write_csv(
+    reader: &mut BinaryCopyFileReader<File>,
+    path: &str,
+    headers: Option<&[String]>,
+    null: &str,
+    cancel: &CancelToken,
+) -> io::Result<u64> {
+    let mut writer = csv::Writer::from_writer(File::create(Path::new(path))?);
+    if let Some(headers) = headers {
+        writer.write_record(headers)?;
+    }
+
+    let column_count = reader.types().len();
+    let mut record = Vec::with_capacity(column_count);
+    let mut rows = 0u64;
+    while reader.read_row()? {
+        record.clear();
+        for index in 0..column_count {
+            let field = reader.get::<CopyField>(index)?;
+            record.push(field.to_text().unwrap_or_else(|| null.to_string()));
+        }
+        writer.write_record(&record)?;
+
+        rows += 1;
+        if rows % 10000 == 0 {
+            cancel.check()?;
+        }
+    }
+
+    writer.flush()?;
+    debug!(
+        target: "csv_utils::binary_copy_file",
+        "Wrote {} rows to {}", rows, path
+    );
+    Ok(rows)
+}
+
+pub fn register(ruby: &Ruby, module: &RModule) -> Result<(), Error> {
+    let class = module.define_class("BinaryCopyFile", ruby.class_object())?;
+    class.include_module(ruby.module_enumerable())?;
+    class.define_singleton_method("open", function!(BinaryCopyFile::open, -1))?;
+    class.define_method("each", method!(BinaryCopyFile::each, 0))?;
+    class.define_method("to_csv", method!(BinaryCopyFile::to_csv, -1))?;
+
+    Ok(())
+}
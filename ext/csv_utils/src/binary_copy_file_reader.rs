@@ -0,0 +1,414 @@
+use crate::copy_convert::PG_EPOCH_UNIX_SECONDS;
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use faster_hex::hex_string;
+use postgis::ewkb::Point;
+use postgres::types::{FromSql, Type};
+use std::error::Error;
+use std::io::{self, BufReader, Read};
+use std::ops::Range;
+
+const HEADER_MAGIC: &[u8] = b"PGCOPY\n\xff\r\n\0";
+// Flag bit 16: each row starts with an OID field
+const FLAG_HAS_OIDS: i32 = 1 << 16;
+// Bits 16-31 mark format changes a reader can't ignore, bits 0-15 ones it can
+const CRITICAL_FLAGS: i32 = !0xFFFF;
+const NUMERIC_POSITIVE: u16 = 0x0000;
+const NUMERIC_NEGATIVE: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_PINF: u16 = 0xD000;
+const NUMERIC_NINF: u16 = 0xF000;
+const JSONB_VERSION: u8 = 1;
+
+/// A reader for PostgreSQL binary-copy streams, the counterpart of
+/// `BinaryCopyFileWriter`
+pub struct BinaryCopyFileReader<R: Read> {
+    types: Vec<Type>,
+    reader: BufReader<R>,
+    has_oids: bool,
+    // Fields of the last row read: where their bytes are in `buf`, or None for NULL
+    buf: Vec<u8>,
+    fields: Vec<Option<Range<usize>>>,
+}
+
+impl<R: Read> BinaryCopyFileReader<R> {
+    pub fn new<I>(types: I, reader: R) -> Self
+    where
+        I: IntoIterator<Item = Type>,
+    {
+        BinaryCopyFileReader {
+            types: types.into_iter().collect(),
+            reader: BufReader::with_capacity(5 * 1024 * 1024, reader),
+            has_oids: false,
+            buf: Vec::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn read_header(&mut self) -> io::Result<()> {
+        let mut magic = [0u8; HEADER_MAGIC.len()];
+        self.reader.read_exact(&mut magic)?;
+        if magic != HEADER_MAGIC {
+            return Err(invalid_data("not a binary COPY file".to_string()));
+        }
+
+        let flags = self.reader.read_i32::<BigEndian>()?;
+        if flags & CRITICAL_FLAGS & !FLAG_HAS_OIDS != 0 {
+            return Err(invalid_data(format!("unsupported flags {:#010x}", flags)));
+        }
+        self.has_oids = flags & FLAG_HAS_OIDS != 0;
+
+        // Nothing is defined for the header extension area yet, so it is skipped
+        let extension_length = self.reader.read_i32::<BigEndian>()?;
+        let extension_length = u64::try_from(extension_length).map_err(|_| {
+            invalid_data(format!(
+                "invalid header extension length {}",
+                extension_length
+            ))
+        })?;
+        let skipped = io::copy(
+            &mut (&mut self.reader).take(extension_length),
+            &mut io::sink(),
+        )?;
+        if skipped < extension_length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(())
+    }
+
+    /// Read the next row, returning false at the trailer
+    pub fn read_row(&mut self) -> io::Result<bool> {
+        let field_count = self.reader.read_i16::<BigEndian>()?;
+        if field_count == -1 {
+            return Ok(false);
+        }
+        if field_count as usize != self.types.len() {
+            return Err(invalid_data(format!(
+                "row has {} fields, expected {}",
+                field_count,
+                self.types.len()
+            )));
+        }
+
+        self.buf.clear();
+        self.fields.clear();
+        if self.has_oids {
+            // Not counted in the field count, and not needed
+            self.read_field()?;
+            self.buf.clear();
+        }
+        for _ in 0..field_count {
+            let field = self.read_field()?;
+            self.fields.push(field);
+        }
+
+        Ok(true)
+    }
+
+    // Append one length-prefixed field to `buf`. The buffer only grows as the bytes
+    // arrive, so a corrupt length can't allocate more than is left in the stream.
+    fn read_field(&mut self) -> io::Result<Option<Range<usize>>> {
+        let length = self.reader.read_i32::<BigEndian>()?;
+        if length == -1 {
+            return Ok(None);
+        }
+        let length = u64::try_from(length)
+            .map_err(|_| invalid_data(format!("invalid field length {}", length)))?;
+
+        let start = self.buf.len();
+        let read = (&mut self.reader).take(length).read_to_end(&mut self.buf)?;
+        if (read as u64) < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some(start..start + read))
+    }
+
+    pub fn types(&self) -> &[Type] {
+        &self.types
+    }
+
+    /// Decode a field of the last row read
+    pub fn get<'a, T: FromSql<'a>>(&'a self, index: usize) -> io::Result<T> {
+        let pg_type = &self.types[index];
+        if !T::accepts(pg_type) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't read field {} ({})", index + 1, pg_type.name()),
+            ));
+        }
+
+        let raw = self.fields[index].clone().map(|range| &self.buf[range]);
+        T::from_sql_nullable(pg_type, raw).map_err(|e| {
+            invalid_data(format!(
+                "invalid {} in field {}: {}",
+                pg_type.name(),
+                index + 1,
+                e
+            ))
+        })
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A field read back from a binary COPY stream. Types without a natural Rust value
+/// (NUMERIC, DATE, UUID, JSONB and PostGIS points) are kept as text: PostgreSQL's text
+/// for them, or EWKT for points.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CopyField {
+    Null,
+    Text(String),
+    Bytes(Vec<u8>),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    // TIMESTAMP or, with `time_zone`, TIMESTAMPTZ
+    Timestamp {
+        value: DateTime<Utc>,
+        time_zone: bool,
+    },
+    TextArray(Vec<Option<String>>),
+}
+
+impl<'a> FromSql<'a> for CopyField {
+    fn from_sql(pg_type: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(match *pg_type {
+            Type::VARCHAR | Type::TEXT => CopyField::Text(String::from_sql(pg_type, raw)?),
+            Type::BYTEA => CopyField::Bytes(Vec::<u8>::from_sql(pg_type, raw)?),
+            Type::INT4 => CopyField::Int(i32::from_sql(pg_type, raw)? as i64),
+            Type::INT8 => CopyField::Int(i64::from_sql(pg_type, raw)?),
+            Type::FLOAT8 => CopyField::Float(f64::from_sql(pg_type, raw)?),
+            Type::BOOL => CopyField::Bool(bool::from_sql(pg_type, raw)?),
+            Type::NUMERIC => CopyField::Text(decode_numeric(raw)?),
+            Type::DATE => match i32::from_be_bytes(fixed(raw)?) {
+                i32::MAX => CopyField::Text("infinity".to_string()),
+                i32::MIN => CopyField::Text("-infinity".to_string()),
+                days => CopyField::Text(decode_date(days)?),
+            },
+            Type::TIMESTAMP | Type::TIMESTAMPTZ => match i64::from_be_bytes(fixed(raw)?) {
+                i64::MAX => CopyField::Text("infinity".to_string()),
+                i64::MIN => CopyField::Text("-infinity".to_string()),
+                micros => CopyField::Timestamp {
+                    value: decode_timestamp(micros)?,
+                    time_zone: *pg_type == Type::TIMESTAMPTZ,
+                },
+            },
+            Type::UUID => CopyField::Text(decode_uuid(raw)?),
+            Type::JSONB => CopyField::Text(decode_jsonb(raw)?),
+            Type::VARCHAR_ARRAY | Type::TEXT_ARRAY => {
+                CopyField::TextArray(Vec::<Option<String>>::from_sql(pg_type, raw)?)
+            }
+            _ => {
+                let point = Point::from_sql(pg_type, raw)?;
+                CopyField::Text(match point.srid {
+                    Some(srid) => format!("SRID={};POINT({} {})", srid, point.x, point.y),
+                    None => format!("POINT({} {})", point.x, point.y),
+                })
+            }
+        })
+    }
+
+    fn from_sql_null(_: &Type) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(CopyField::Null)
+    }
+
+    fn accepts(pg_type: &Type) -> bool {
+        matches!(
+            *pg_type,
+            Type::VARCHAR
+                | Type::TEXT
+                | Type::BYTEA
+                | Type::INT4
+                | Type::INT8
+                | Type::FLOAT8
+                | Type::BOOL
+                | Type::NUMERIC
+                | Type::DATE
+                | Type::TIMESTAMP
+                | Type::TIMESTAMPTZ
+                | Type::UUID
+                | Type::JSONB
+                | Type::VARCHAR_ARRAY
+                | Type::TEXT_ARRAY
+        ) || <Point as FromSql>::accepts(pg_type)
+    }
+}
+
+impl CopyField {
+    /// The field in PostgreSQL's text format, as `COPY ... TO` writes it, or `None` for
+    /// NULL. Points are EWKT rather than PostGIS's hex EWKB.
+    pub fn to_text(&self) -> Option<String> {
+        Some(match self {
+            CopyField::Null => return None,
+            CopyField::Text(text) => text.clone(),
+            CopyField::Bytes(bytes) => format!("\\x{}", hex_string(bytes)),
+            CopyField::Int(value) => value.to_string(),
+            CopyField::Float(value) => float_text(*value),
+            CopyField::Bool(value) => if *value { "t" } else { "f" }.to_string(),
+            CopyField::Timestamp { value, time_zone } => {
+                // Microseconds without trailing zeros, like PostgreSQL
+                let mut text = value.format("%Y-%m-%d %H:%M:%S").to_string();
+                let micros = value.timestamp_subsec_micros();
+                if micros > 0 {
+                    text.push_str(format!(".{:06}", micros).trim_end_matches('0'));
+                }
+                if *time_zone {
+                    text.push_str("+00");
+                }
+                text
+            }
+            CopyField::TextArray(values) => {
+                let elements: Vec<String> = values
+                    .iter()
+                    .map(|value| match value {
+                        Some(value) => array_element_text(value),
+                        None => "NULL".to_string(),
+                    })
+                    .collect();
+                format!("{{{}}}", elements.join(","))
+            }
+        })
+    }
+}
+
+// The shortest text that reads back as the same value, in exponent form outside
+// 1e-4 <= |value| < 1e15, as PostgreSQL's float8out prints it
+fn float_text(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+
+    // Rust's `{:e}` is also the shortest round-trip form, e.g. "1.5e-5"
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-4..15).contains(&exponent) {
+        return value.to_string();
+    }
+    format!(
+        "{}e{}{:02}",
+        mantissa,
+        if exponent < 0 { '-' } else { '+' },
+        exponent.unsigned_abs()
+    )
+}
+
+// Quoted only when it has to be, as PostgreSQL's array_out does: when empty, "NULL" in
+// any case, or containing quotes, backslashes, braces, commas or whitespace
+fn array_element_text(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value.eq_ignore_ascii_case("NULL")
+        || value.chars().any(|c| {
+            matches!(
+                c,
+                '"' | '\\' | '{' | '}' | ',' | ' ' | '\t' | '\n' | '\r' | '\x0B' | '\x0C'
+            )
+        });
+    if !needs_quotes {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn fixed<const N: usize>(raw: &[u8]) -> Result<[u8; N], Box<dyn Error + Sync + Send>> {
+    raw.try_into()
+        .map_err(|_| format!("expected {} bytes, got {}", N, raw.len()).into())
+}
+
+// Digit count, weight of the first digit, sign and display scale, then the base 10000
+// digits; the reverse of `copy_convert::encode_numeric`
+fn decode_numeric(mut raw: &[u8]) -> Result<String, Box<dyn Error + Sync + Send>> {
+    let digit_count = raw.read_i16::<BigEndian>()?;
+    let weight = raw.read_i16::<BigEndian>()? as i64;
+    let sign = raw.read_u16::<BigEndian>()?;
+    let dscale = raw.read_u16::<BigEndian>()? as usize;
+    match sign {
+        NUMERIC_NAN => return Ok("NaN".to_string()),
+        NUMERIC_PINF => return Ok("Infinity".to_string()),
+        NUMERIC_NINF => return Ok("-Infinity".to_string()),
+        NUMERIC_POSITIVE | NUMERIC_NEGATIVE => {}
+        _ => return Err(invalid_data(format!("invalid numeric sign {:#06x}", sign)).into()),
+    }
+    let digits = (0..digit_count)
+        .map(|_| raw.read_i16::<BigEndian>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let digit = |index: i64| {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| digits.get(index))
+            .copied()
+            .unwrap_or(0)
+    };
+
+    let mut text = String::new();
+    if sign == NUMERIC_NEGATIVE {
+        text.push('-');
+    }
+    if weight < 0 {
+        text.push('0');
+    } else {
+        text.push_str(&digit(0).to_string());
+        for index in 1..=weight {
+            text.push_str(&format!("{:04}", digit(index)));
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut index = weight + 1;
+        while fraction.len() < dscale {
+            fraction.push_str(&format!("{:04}", digit(index)));
+            index += 1;
+        }
+        fraction.truncate(dscale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    Ok(text)
+}
+
+// Days since 2000-01-01
+fn decode_date(days: i32) -> Result<String, Box<dyn Error + Sync + Send>> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+    let date = if days < 0 {
+        epoch.checked_sub_days(Days::new(days.unsigned_abs() as u64))
+    } else {
+        epoch.checked_add_days(Days::new(days as u64))
+    };
+    date.map(|date| date.format("%Y-%m-%d").to_string())
+        .ok_or_else(|| format!("date out of range: {} days", days).into())
+}
+
+// Microseconds since 2000-01-01 UTC
+fn decode_timestamp(micros: i64) -> Result<DateTime<Utc>, Box<dyn Error + Sync + Send>> {
+    micros
+        .checked_add(PG_EPOCH_UNIX_SECONDS * 1_000_000)
+        .and_then(DateTime::from_timestamp_micros)
+        .ok_or_else(|| format!("timestamp out of range: {}", micros).into())
+}
+
+fn decode_uuid(raw: &[u8]) -> Result<String, Box<dyn Error + Sync + Send>> {
+    let hex = hex_string(&fixed::<16>(raw)?);
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+// A version byte followed by the JSON text
+fn decode_jsonb(raw: &[u8]) -> Result<String, Box<dyn Error + Sync + Send>> {
+    match raw.split_first() {
+        Some((&JSONB_VERSION, json)) => Ok(std::str::from_utf8(json)?.to_string()),
+        Some((version, _)) => Err(format!("unsupported jsonb version {}", version).into()),
+        None => Err("empty jsonb value".into()),
+    }
+}
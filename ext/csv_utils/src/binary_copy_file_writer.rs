@@ -66,4 +66,9 @@ impl<W: Write> BinaryCopyFileWriter<W> {
         self.writer.flush()?;
         Ok(())
     }
+
+    /// Flush and hand back the underlying writer
+    pub fn into_inner(self) -> io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
 }
@@ -0,0 +1,221 @@
+use crate::copy_schema::CopyValue;
+use crate::sort_spec::{parse_timestamp, DATE_FORMAT};
+use chrono::NaiveDate;
+use postgres::types::Type;
+
+// PostgreSQL counts dates and timestamps from 2000-01-01
+pub const PG_EPOCH_UNIX_SECONDS: i64 = 946_684_800;
+// NUMERIC digits are base 10000, i.e. four decimal digits each
+const NUMERIC_DIGITS: usize = 4;
+const NUMERIC_POSITIVE: u16 = 0x0000;
+const NUMERIC_NEGATIVE: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+// Largest exponent accepted in NUMERIC input like 1.5e3
+const MAX_NUMERIC_EXPONENT: i64 = 1000;
+const JSONB_VERSION: u8 = 1;
+
+/// Types a CSV field can be converted to, besides text
+pub fn converted_types() -> Vec<Type> {
+    vec![
+        Type::INT4,
+        Type::INT8,
+        Type::NUMERIC,
+        Type::FLOAT8,
+        Type::BOOL,
+        Type::DATE,
+        Type::TIMESTAMPTZ,
+        Type::UUID,
+        Type::JSONB,
+    ]
+}
+
+/// Whether `format:` applies to a type
+pub fn takes_format(pg_type: &Type) -> bool {
+    *pg_type == Type::DATE || *pg_type == Type::TIMESTAMPTZ
+}
+
+/// Convert a CSV field to one of `converted_types`, or `None` if it doesn't parse
+pub fn convert(value: &str, pg_type: &Type, format: Option<&str>) -> Option<CopyValue<'static>> {
+    let value = value.trim();
+    match *pg_type {
+        Type::INT4 => value.parse().ok().map(CopyValue::Int4),
+        Type::INT8 => value.parse().ok().map(CopyValue::Int8),
+        Type::NUMERIC => encode_numeric(value).map(CopyValue::Encoded),
+        Type::FLOAT8 => value.parse().ok().map(CopyValue::Float8),
+        Type::BOOL => parse_bool(value).map(CopyValue::Bool),
+        Type::DATE => {
+            parse_date(value, format).map(|days| CopyValue::Encoded(days.to_be_bytes().to_vec()))
+        }
+        Type::TIMESTAMPTZ => parse_timestamptz(value, format)
+            .map(|micros| CopyValue::Encoded(micros.to_be_bytes().to_vec())),
+        Type::UUID => parse_uuid(value).map(|uuid| CopyValue::Encoded(uuid.to_vec())),
+        Type::JSONB => encode_jsonb(value).map(CopyValue::Encoded),
+        _ => None,
+    }
+}
+
+// The spellings PostgreSQL accepts for booleans, ignoring case
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "t" | "true" | "y" | "yes" | "on" | "1" => Some(true),
+        "f" | "false" | "n" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// Days since 2000-01-01
+fn parse_date(value: &str, format: Option<&str>) -> Option<i32> {
+    let date = NaiveDate::parse_from_str(value, format.unwrap_or(DATE_FORMAT)).ok()?;
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    i32::try_from((date - epoch).num_days()).ok()
+}
+
+// Microseconds since 2000-01-01 UTC. A format with an offset (%z) is honoured, other
+// values without a time zone are taken as UTC.
+fn parse_timestamptz(value: &str, format: Option<&str>) -> Option<i64> {
+    parse_timestamp(value, format)?.checked_sub(PG_EPOCH_UNIX_SECONDS * 1_000_000)
+}
+
+// 32 hex digits, optionally in braces and with hyphens between them
+fn parse_uuid(value: &str) -> Option<[u8; 16]> {
+    let value = value
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or(value);
+    let digits: Vec<u8> = value.bytes().filter(|&b| b != b'-').collect();
+    if digits.len() != 32 || value.starts_with('-') || value.ends_with('-') {
+        return None;
+    }
+
+    let mut uuid = [0u8; 16];
+    for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+// JSONB's binary format is a version byte followed by the JSON text
+fn encode_jsonb(value: &str) -> Option<Vec<u8>> {
+    let json = serde_json::from_str::<serde_json::Value>(value).ok()?;
+    if has_nul(&json) {
+        return None;
+    }
+    let mut buf = Vec::with_capacity(value.len() + 1);
+    buf.push(JSONB_VERSION);
+    buf.extend_from_slice(value.as_bytes());
+    Some(buf)
+}
+
+// PostgreSQL's jsonb can't hold \u0000 in strings or keys, although JSON allows it
+fn has_nul(json: &serde_json::Value) -> bool {
+    match json {
+        serde_json::Value::String(text) => text.contains('\0'),
+        serde_json::Value::Array(values) => values.iter().any(has_nul),
+        serde_json::Value::Object(fields) => fields
+            .iter()
+            .any(|(key, value)| key.contains('\0') || has_nul(value)),
+        _ => false,
+    }
+}
+
+// Encode a decimal like -12.50 or 1.5e3 (or NaN) in NUMERIC's binary format: digit
+// count, weight of the first digit, sign and display scale, then the base 10000 digits
+fn encode_numeric(value: &str) -> Option<Vec<u8>> {
+    if value.eq_ignore_ascii_case("nan") {
+        return Some(numeric_bytes(&[], 0, NUMERIC_NAN, 0));
+    }
+
+    let (negative, unsigned) = match value.as_bytes().first()? {
+        b'-' => (true, &value[1..]),
+        b'+' => (false, &value[1..]),
+        _ => (false, value),
+    };
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(index) => (
+            &unsigned[..index],
+            unsigned[index + 1..].parse::<i64>().ok()?,
+        ),
+        None => (unsigned, 0),
+    };
+    if exponent.abs() > MAX_NUMERIC_EXPONENT {
+        return None;
+    }
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if int_part.is_empty() && frac_part.is_empty()
+        || !int_part
+            .bytes()
+            .chain(frac_part.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    // Shift the decimal point by the exponent
+    let mut digits = format!("{}{}", int_part, frac_part);
+    let mut point = int_part.len() as i64 + exponent;
+    if point < 0 {
+        digits.insert_str(0, &"0".repeat((-point) as usize));
+        point = 0;
+    }
+    if point as usize > digits.len() {
+        digits.push_str(&"0".repeat(point as usize - digits.len()));
+    }
+    let (int_digits, frac_digits) = digits.split_at(point as usize);
+    let int_digits = int_digits.trim_start_matches('0');
+    let dscale = frac_digits.len();
+
+    // Pad to whole base 10000 digits: zeros before the integer part, after the fraction
+    let int_pad = (NUMERIC_DIGITS - int_digits.len() % NUMERIC_DIGITS) % NUMERIC_DIGITS;
+    let frac_pad = (NUMERIC_DIGITS - frac_digits.len() % NUMERIC_DIGITS) % NUMERIC_DIGITS;
+    let padded = format!(
+        "{}{}{}{}",
+        "0".repeat(int_pad),
+        int_digits,
+        frac_digits,
+        "0".repeat(frac_pad)
+    );
+    let mut groups: Vec<i16> = padded
+        .as_bytes()
+        .chunks(NUMERIC_DIGITS)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .fold(0i16, |group, digit| group * 10 + (digit - b'0') as i16)
+        })
+        .collect();
+    let mut weight = ((int_pad + int_digits.len()) / NUMERIC_DIGITS) as i64 - 1;
+
+    let leading_zeros = groups.iter().take_while(|&&group| group == 0).count();
+    groups.drain(..leading_zeros);
+    weight -= leading_zeros as i64;
+    while groups.last() == Some(&0) {
+        groups.pop();
+    }
+    if groups.is_empty() {
+        return Some(numeric_bytes(&[], 0, NUMERIC_POSITIVE, dscale));
+    }
+
+    let sign = if negative {
+        NUMERIC_NEGATIVE
+    } else {
+        NUMERIC_POSITIVE
+    };
+    Some(numeric_bytes(
+        &groups,
+        i16::try_from(weight).ok()?,
+        sign,
+        dscale,
+    ))
+}
+
+fn numeric_bytes(groups: &[i16], weight: i16, sign: u16, dscale: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + groups.len() * 2);
+    buf.extend_from_slice(&(groups.len() as i16).to_be_bytes());
+    buf.extend_from_slice(&weight.to_be_bytes());
+    buf.extend_from_slice(&sign.to_be_bytes());
+    buf.extend_from_slice(&(dscale as u16).to_be_bytes());
+    for group in groups {
+        buf.extend_from_slice(&group.to_be_bytes());
+    }
+    buf
+}
@@ -0,0 +1,384 @@
+use crate::copy_convert::{convert, converted_types, takes_format};
+use crate::ruby_options::option_string;
+use crate::sorter::SortRecord;
+use bytes::BytesMut;
+use log::{debug, error};
+use magnus::{exception::arg_error, prelude::*, Error, RArray, RHash, Symbol, Value};
+use postgis::ewkb::Point;
+use postgres::types::{to_sql_checked, IsNull, Kind, ToSql, Type};
+use std::borrow::Cow;
+use std::io;
+use std::time::SystemTime;
+
+/// What an output column holds for each sorted row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnSource {
+    SourceKey,
+    // Hex digest, or the raw digest for BYTEA
+    TargetKey,
+    Position,
+    GeoPoint,
+    // Time the copy started, the same for every row
+    Timestamp,
+    // Every CSV field as an array
+    Record,
+    // One CSV field, NULL when the row is too short. Converted from text for types
+    // other than VARCHAR and TEXT.
+    Column(usize),
+}
+
+impl ColumnSource {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "source_key" => Some(ColumnSource::SourceKey),
+            "target_key" => Some(ColumnSource::TargetKey),
+            "position" => Some(ColumnSource::Position),
+            "geo_point" => Some(ColumnSource::GeoPoint),
+            "timestamp" => Some(ColumnSource::Timestamp),
+            "record" => Some(ColumnSource::Record),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> String {
+        match self {
+            ColumnSource::SourceKey => "source_key".to_string(),
+            ColumnSource::TargetKey => "target_key".to_string(),
+            ColumnSource::Position => "position".to_string(),
+            ColumnSource::GeoPoint => "geo_point".to_string(),
+            ColumnSource::Timestamp => "timestamp".to_string(),
+            ColumnSource::Record => "record".to_string(),
+            ColumnSource::Column(index) => format!("column {}", index),
+        }
+    }
+
+    // Types the value can be written as, the first being the default
+    fn types(self) -> Vec<Type> {
+        match self {
+            ColumnSource::SourceKey => vec![Type::VARCHAR, Type::TEXT],
+            ColumnSource::Column(_) => {
+                let mut types = vec![Type::VARCHAR, Type::TEXT];
+                types.extend(converted_types());
+                types
+            }
+            ColumnSource::TargetKey => vec![Type::VARCHAR, Type::TEXT, Type::BYTEA],
+            ColumnSource::Position => vec![Type::INT8, Type::INT4],
+            ColumnSource::GeoPoint => vec![geometry_type()],
+            ColumnSource::Timestamp => vec![Type::TIMESTAMP, Type::TIMESTAMPTZ],
+            ColumnSource::Record => vec![Type::VARCHAR_ARRAY, Type::TEXT_ARRAY],
+        }
+    }
+}
+
+/// One column of the COPY output
+#[derive(Debug, Clone)]
+pub struct CopyColumn {
+    pub source: ColumnSource,
+    pub pg_type: Type,
+    // strftime format for DATE and TIMESTAMPTZ fields
+    pub format: Option<String>,
+    // Field values written as NULL
+    pub nulls: Vec<String>,
+}
+
+/// Why a column has no value for a row
+#[derive(Debug)]
+pub enum ValueError {
+    // The CSV field doesn't parse as the column's type
+    Conversion { column: usize },
+    Io(io::Error),
+}
+
+impl From<io::Error> for ValueError {
+    fn from(e: io::Error) -> Self {
+        ValueError::Io(e)
+    }
+}
+
+/// The sorted row a `CopyColumn` takes its value from
+pub struct CopyRow<'a> {
+    pub source_key: &'a str,
+    pub record: &'a SortRecord,
+    pub geo_point: Option<Point>,
+    pub now: SystemTime,
+}
+
+impl CopyColumn {
+    pub fn value<'a>(&self, row: &CopyRow<'a>) -> Result<CopyValue<'a>, ValueError> {
+        Ok(match self.source {
+            ColumnSource::SourceKey => CopyValue::Text(Cow::Borrowed(row.source_key)),
+            ColumnSource::TargetKey if self.pg_type == Type::BYTEA => {
+                CopyValue::Encoded(row.record.key.value.as_slice().to_vec())
+            }
+            ColumnSource::TargetKey => CopyValue::Text(Cow::Owned(row.record.key.value.to_hex())),
+            ColumnSource::Position if self.pg_type == Type::INT4 => {
+                let position = i32::try_from(row.record.key.position).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Position {} doesn't fit in INT4", row.record.key.position),
+                    )
+                })?;
+                CopyValue::Int4(position)
+            }
+            ColumnSource::Position => CopyValue::Int8(row.record.key.position as i64),
+            ColumnSource::GeoPoint => match row.geo_point {
+                Some(point) => CopyValue::Point(point),
+                None => CopyValue::Null,
+            },
+            ColumnSource::Timestamp => CopyValue::Timestamp(row.now),
+            ColumnSource::Record => CopyValue::TextArray(&row.record.record),
+            ColumnSource::Column(index) => match row.record.record.get(index) {
+                Some(value) if self.nulls.contains(value) => CopyValue::Null,
+                Some(value) if self.pg_type == Type::VARCHAR || self.pg_type == Type::TEXT => {
+                    CopyValue::Text(Cow::Borrowed(value))
+                }
+                Some(value) => convert(value, &self.pg_type, self.format.as_deref())
+                    .ok_or(ValueError::Conversion { column: index })?,
+                None => CopyValue::Null,
+            },
+        })
+    }
+}
+
+/// Which columns `PostgresCopier` writes for each row, in order
+#[derive(Debug, Clone)]
+pub struct CopySchema {
+    pub columns: Vec<CopyColumn>,
+}
+
+impl Default for CopySchema {
+    // The original fixed layout: source key, hex target key, geo point, the CSV fields
+    // as VARCHAR[], and two timestamps
+    fn default() -> Self {
+        let column = |source, pg_type| CopyColumn {
+            source,
+            pg_type,
+            format: None,
+            nulls: Vec::new(),
+        };
+        Self {
+            columns: vec![
+                column(ColumnSource::SourceKey, Type::VARCHAR),
+                column(ColumnSource::TargetKey, Type::VARCHAR),
+                column(ColumnSource::GeoPoint, geometry_type()),
+                column(ColumnSource::Record, Type::VARCHAR_ARRAY),
+                column(ColumnSource::Timestamp, Type::TIMESTAMP),
+                column(ColumnSource::Timestamp, Type::TIMESTAMP),
+            ],
+        }
+    }
+}
+
+impl CopySchema {
+    // Read the `columns:` option, e.g.
+    // `[{ value: :target_key, type: :bytea }, { column: 2, type: :int4 }]`, falling back
+    // to the default layout when it isn't given. `null:` gives the field values written
+    // as NULL, for all columns or (inside a column) for one.
+    pub fn from_options(options: Option<RHash>) -> Result<Self, Error> {
+        let (columns, nulls) = match options {
+            Some(options) => (
+                options.lookup::<_, Option<RArray>>(Symbol::new("columns"))?,
+                null_tokens(options)?,
+            ),
+            None => (None, None),
+        };
+        let columns = match columns {
+            Some(columns) => columns,
+            None => return Ok(Self::default()),
+        };
+
+        if columns.is_empty() {
+            return Err(Error::new(arg_error(), "columns must not be empty"));
+        }
+
+        let columns = columns
+            .to_vec::<RHash>()?
+            .into_iter()
+            .map(|column| CopyColumn::from_ruby(column, nulls.as_deref()))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        debug!(target: "csv_utils::copy_schema", "Using copy columns: {:?}", columns);
+
+        Ok(Self { columns })
+    }
+
+    pub fn types(&self) -> Vec<Type> {
+        self.columns
+            .iter()
+            .map(|column| column.pg_type.clone())
+            .collect()
+    }
+}
+
+impl CopyColumn {
+    fn from_ruby(column: RHash, default_nulls: Option<&[String]>) -> Result<Self, Error> {
+        let value = option_string(column, "value")?;
+        let index = column.lookup::<_, Option<usize>>(Symbol::new("column"))?;
+        let source = match (value, index) {
+            (Some(value), None) => ColumnSource::from_name(&value).ok_or_else(|| {
+                error!(target: "csv_utils::copy_schema", "Invalid column value: {}", value);
+                Error::new(arg_error(), format!("Invalid column value: {}", value))
+            })?,
+            (None, Some(index)) => ColumnSource::Column(index),
+            _ => {
+                return Err(Error::new(
+                    arg_error(),
+                    "Each column needs either value: or column:",
+                ))
+            }
+        };
+
+        let types = source.types();
+        let pg_type = match option_string(column, "type")? {
+            Some(name) => types
+                .into_iter()
+                .find(|pg_type| type_name(pg_type) == name)
+                .ok_or_else(|| {
+                    error!(
+                        target: "csv_utils::copy_schema",
+                        "Invalid type {} for {}",
+                        name,
+                        source.label()
+                    );
+                    Error::new(
+                        arg_error(),
+                        format!("{} can't be written as {}", source.label(), name),
+                    )
+                })?,
+            None => types.into_iter().next().unwrap(),
+        };
+
+        let format = option_string(column, "format")?;
+        if format.is_some() && !takes_format(&pg_type) {
+            return Err(Error::new(
+                arg_error(),
+                format!(
+                    "format is only supported for date and timestamptz ({})",
+                    source.label()
+                ),
+            ));
+        }
+
+        let nulls = null_tokens(column)?;
+        let text = pg_type == Type::VARCHAR || pg_type == Type::TEXT;
+        let nulls = match (source, nulls) {
+            (ColumnSource::Column(_), Some(nulls)) => nulls,
+            (_, Some(_)) => {
+                return Err(Error::new(
+                    arg_error(),
+                    format!(
+                        "null is only supported for CSV columns ({})",
+                        source.label()
+                    ),
+                ))
+            }
+            // Text keeps empty fields as they are, other types can't parse them
+            (ColumnSource::Column(_), None) => match default_nulls {
+                Some(nulls) => nulls.to_vec(),
+                None if text => Vec::new(),
+                None => vec![String::new()],
+            },
+            (_, None) => Vec::new(),
+        };
+
+        Ok(Self {
+            source,
+            pg_type,
+            format,
+            nulls,
+        })
+    }
+}
+
+// `null:` as one string or an array of them
+fn null_tokens(options: RHash) -> Result<Option<Vec<String>>, Error> {
+    match options.lookup::<_, Option<Value>>(Symbol::new("null"))? {
+        Some(value) => match RArray::from_value(value) {
+            Some(tokens) => Ok(Some(tokens.to_vec::<String>()?)),
+            None => Ok(Some(vec![String::try_convert(value)?])),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Find a type by the name used in the `type:` option, among all the types a column
+/// can be written as
+pub fn type_from_name(name: &str) -> Option<Type> {
+    [
+        ColumnSource::SourceKey,
+        ColumnSource::TargetKey,
+        ColumnSource::Position,
+        ColumnSource::GeoPoint,
+        ColumnSource::Timestamp,
+        ColumnSource::Record,
+        ColumnSource::Column(0),
+    ]
+    .into_iter()
+    .flat_map(ColumnSource::types)
+    .find(|pg_type| type_name(pg_type) == name)
+}
+
+// Name used for a type in the `type:` option
+pub fn type_name(pg_type: &Type) -> String {
+    match pg_type.kind() {
+        Kind::Array(member) => format!("{}[]", member.name()),
+        _ => pg_type.name().to_string(),
+    }
+}
+
+// PostGIS geometry, written as EWKB
+pub fn geometry_type() -> Type {
+    Type::new(
+        "geometry".to_string(),
+        Type::POINT.oid(),
+        Kind::Simple,
+        "public".to_string(),
+    )
+}
+
+/// A value for one column of a row, encoded as the column's type
+#[derive(Debug)]
+pub enum CopyValue<'a> {
+    Null,
+    Text(Cow<'a, str>),
+    // Already in the column type's binary format
+    Encoded(Vec<u8>),
+    Int4(i32),
+    Int8(i64),
+    Float8(f64),
+    Bool(bool),
+    Point(Point),
+    Timestamp(SystemTime),
+    TextArray(&'a [String]),
+}
+
+impl ToSql for CopyValue<'_> {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        match self {
+            CopyValue::Null => Ok(IsNull::Yes),
+            CopyValue::Text(value) => value.to_sql(ty, out),
+            CopyValue::Encoded(value) => {
+                out.extend_from_slice(value);
+                Ok(IsNull::No)
+            }
+            CopyValue::Int4(value) => value.to_sql(ty, out),
+            CopyValue::Int8(value) => value.to_sql(ty, out),
+            CopyValue::Float8(value) => value.to_sql(ty, out),
+            CopyValue::Bool(value) => value.to_sql(ty, out),
+            CopyValue::Point(value) => value.to_sql(ty, out),
+            CopyValue::Timestamp(value) => value.to_sql(ty, out),
+            CopyValue::TextArray(value) => value.to_sql(ty, out),
+        }
+    }
+
+    // `CopySchema` only pairs values with types they can be written as
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
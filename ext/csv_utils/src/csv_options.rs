@@ -0,0 +1,105 @@
+use crate::ruby_options::option_string;
+use csv::{ReaderBuilder, Trim};
+use log::{debug, error};
+use magnus::{exception::arg_error, Error, RHash, Symbol};
+
+/// CSV dialect settings used when parsing input files
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    delimiter: u8,
+    quote: u8,
+    escape: Option<u8>,
+    has_headers: bool,
+    comment: Option<u8>,
+    flexible: bool,
+    trim: Trim,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            escape: None,
+            has_headers: true,
+            comment: None,
+            flexible: false,
+            trim: Trim::None,
+        }
+    }
+}
+
+impl CsvOptions {
+    // Build options from a Ruby hash such as `{ delimiter: "\t", headers: false }`.
+    // Missing keys keep the defaults, which match `csv::Reader::from_reader`.
+    pub fn from_ruby(options: RHash) -> Result<Self, Error> {
+        let mut result = Self::default();
+
+        if let Some(delimiter) = option_string(options, "delimiter")? {
+            result.delimiter = single_byte("delimiter", &delimiter)?;
+        }
+        if let Some(quote) = option_string(options, "quote")? {
+            result.quote = single_byte("quote", &quote)?;
+        }
+        if let Some(escape) = option_string(options, "escape")? {
+            result.escape = Some(single_byte("escape", &escape)?);
+        }
+        if let Some(comment) = option_string(options, "comment")? {
+            result.comment = Some(single_byte("comment", &comment)?);
+        }
+        if let Some(headers) = options.lookup::<_, Option<bool>>(Symbol::new("headers"))? {
+            result.has_headers = headers;
+        }
+        if let Some(flexible) = options.lookup::<_, Option<bool>>(Symbol::new("flexible"))? {
+            result.flexible = flexible;
+        }
+        if let Some(trim) = option_string(options, "trim")? {
+            result.trim = match trim.as_str() {
+                "none" => Trim::None,
+                "headers" => Trim::Headers,
+                "fields" => Trim::Fields,
+                "all" => Trim::All,
+                _ => {
+                    error!(target: "csv_utils::csv_options", "Invalid trim option: {}", trim);
+                    return Err(Error::new(
+                        arg_error(),
+                        format!("Invalid trim option: {}", trim),
+                    ));
+                }
+            };
+        }
+
+        debug!(target: "csv_utils::csv_options", "Using CSV options: {:?}", result);
+
+        Ok(result)
+    }
+
+    pub fn reader_builder(&self) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .escape(self.escape)
+            .has_headers(self.has_headers)
+            .comment(self.comment)
+            .flexible(self.flexible)
+            .trim(self.trim);
+        builder
+    }
+}
+
+fn single_byte(name: &str, value: &str) -> Result<u8, Error> {
+    match value.as_bytes() {
+        [byte] => Ok(*byte),
+        _ => {
+            error!(
+                target: "csv_utils::csv_options",
+                "Invalid {} option: {:?}", name, value
+            );
+            Err(Error::new(
+                arg_error(),
+                format!("{} must be a single byte character, got {:?}", name, value),
+            ))
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use log::debug;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+// Start of the first block, or of the end of the stream when there are no blocks
+const BZIP2_BLOCK_MAGIC: &[u8] = &[0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+const BZIP2_END_MAGIC: &[u8] = &[0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+
+// Enough bytes to recognize every supported magic number
+const SNIFF_LENGTH: usize = 10;
+
+/// Compression formats recognized on input streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputCompression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl InputCompression {
+    // Magic bytes win when there are enough of them to be sure; the file extension is
+    // only consulted for streams too short to sniff
+    pub fn detect(header: &[u8], path: Option<&Path>) -> Self {
+        if header.starts_with(GZIP_MAGIC) {
+            return InputCompression::Gzip;
+        }
+        if header.starts_with(ZSTD_MAGIC) {
+            return InputCompression::Zstd;
+        }
+        if is_bzip2(header) {
+            return InputCompression::Bzip2;
+        }
+        if header.len() >= SNIFF_LENGTH {
+            return InputCompression::None;
+        }
+
+        match path
+            .and_then(|p| p.extension())
+            .and_then(|ext| ext.to_str())
+        {
+            Some("gz") | Some("gzip") => InputCompression::Gzip,
+            Some("zst") | Some("zstd") => InputCompression::Zstd,
+            Some("bz2") => InputCompression::Bzip2,
+            _ => InputCompression::None,
+        }
+    }
+}
+
+// "BZh" alone starts too many CSV files, so the block size digit and the magic number
+// that follows it must match too
+fn is_bzip2(header: &[u8]) -> bool {
+    header.len() >= SNIFF_LENGTH
+        && header.starts_with(BZIP2_MAGIC)
+        && (b'1'..=b'9').contains(&header[3])
+        && (&header[4..10] == BZIP2_BLOCK_MAGIC || &header[4..10] == BZIP2_END_MAGIC)
+}
+
+/// Wrap `reader` in a streaming decoder if it holds compressed data
+pub fn decompressing_reader<'a, R: BufRead + 'a>(
+    mut reader: R,
+    path: Option<&Path>,
+) -> io::Result<Box<dyn Read + 'a>> {
+    // Read the header rather than peek at the buffer, which may hold fewer bytes than
+    // the stream has, and put it back in front of the rest
+    let mut header = Vec::with_capacity(SNIFF_LENGTH);
+    (&mut reader)
+        .take(SNIFF_LENGTH as u64)
+        .read_to_end(&mut header)?;
+    let compression = InputCompression::detect(&header, path);
+    let reader = io::Cursor::new(header).chain(reader);
+
+    debug!(target: "csv_utils::decompress", "Detected input compression: {:?}", compression);
+
+    Ok(match compression {
+        InputCompression::None => Box::new(reader),
+        // Multi-member decoders handle concatenated streams such as `cat a.gz b.gz`
+        InputCompression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        InputCompression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        InputCompression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+    })
+}
@@ -0,0 +1,127 @@
+use crate::sorter::{KeyData, SortRecord};
+use std::io;
+
+/// How rows sharing a targeting key are collapsed in the sorted output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupStrategy {
+    // Keep every row (the default)
+    All,
+    // Keep the row added first
+    First,
+    // Keep the row added last
+    Last,
+    // Keep the row added last, filling its empty fields from earlier rows
+    Merge,
+}
+
+impl DedupStrategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "all" => Some(DedupStrategy::All),
+            "first" => Some(DedupStrategy::First),
+            "last" => Some(DedupStrategy::Last),
+            "merge" => Some(DedupStrategy::Merge),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DedupStrategy::All => "all",
+            DedupStrategy::First => "first",
+            DedupStrategy::Last => "last",
+            DedupStrategy::Merge => "merge",
+        }
+    }
+}
+
+/// Collapses each key's rows as they stream out of the merge.
+///
+/// Rows arrive grouped by key with the most recently added first, so only the row
+/// being built for the current key is held back.
+pub struct Deduplicator {
+    strategy: DedupStrategy,
+    pending: Option<(KeyData, Vec<u8>)>,
+    // Decoded copy of the pending row, only needed once Merge sees a duplicate
+    merged: Option<SortRecord>,
+    pub collapsed_rows: usize,
+}
+
+impl Deduplicator {
+    pub fn new(strategy: DedupStrategy) -> Self {
+        Self {
+            strategy,
+            pending: None,
+            merged: None,
+            collapsed_rows: 0,
+        }
+    }
+
+    /// Add the next row, handing any finished rows to `emit`
+    pub fn push<F>(&mut self, key: &KeyData, record_bytes: &[u8], emit: &mut F) -> io::Result<()>
+    where
+        F: FnMut(&KeyData, &[u8]) -> io::Result<()>,
+    {
+        if self.strategy == DedupStrategy::All {
+            return emit(key, record_bytes);
+        }
+
+        let same_key = matches!(&self.pending, Some((pending, _)) if pending.value == key.value);
+        if !same_key {
+            self.finish(emit)?;
+            self.pending = Some((key.clone(), record_bytes.to_vec()));
+            return Ok(());
+        }
+
+        self.collapsed_rows += 1;
+        match self.strategy {
+            DedupStrategy::All | DedupStrategy::Last => {}
+            // Each row is older than the last, so the final one seen was added first
+            DedupStrategy::First => self.pending = Some((key.clone(), record_bytes.to_vec())),
+            DedupStrategy::Merge => {
+                let older = decode(record_bytes)?;
+                if self.merged.is_none() {
+                    let (_, pending_bytes) = self.pending.as_ref().unwrap();
+                    self.merged = Some(decode(pending_bytes)?);
+                }
+                let merged = self.merged.as_mut().unwrap();
+
+                for (i, value) in older.record.into_iter().enumerate() {
+                    match merged.record.get_mut(i) {
+                        Some(field) if field.is_empty() => *field = value,
+                        Some(_) => {}
+                        None => merged.record.push(value),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emit the row held back for the last key
+    pub fn finish<F>(&mut self, emit: &mut F) -> io::Result<()>
+    where
+        F: FnMut(&KeyData, &[u8]) -> io::Result<()>,
+    {
+        let (key, record_bytes) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        match self.merged.take() {
+            Some(merged) => {
+                let merged_bytes = bincode::encode_to_vec(&merged, bincode::config::legacy())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                emit(&key, &merged_bytes)
+            }
+            None => emit(&key, &record_bytes),
+        }
+    }
+}
+
+fn decode(record_bytes: &[u8]) -> io::Result<SortRecord> {
+    bincode::decode_from_slice(record_bytes, bincode::config::legacy())
+        .map(|(record, _)| record)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,265 @@
+use crate::errors::phase_error;
+use crate::group_reader::{Group, GroupReader};
+use crate::gvl::{without_gvl, CancelToken};
+use crate::ruby_options::option_string;
+use crate::run_file::RunCompression;
+use crate::sorted_file::{check_compatible, SortedFile, SortedFileHeader};
+use crate::sorter::{SortRecord, Sorter};
+use crate::targeting_key::TargetKey;
+use log::{debug, info};
+use magnus::{
+    exception::arg_error, prelude::*, scan_args::scan_args, Error, Proc, RArray, RHash, Ruby,
+    Symbol, Value,
+};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const BUFFER_CAPACITY: usize = 4 * 1024 * 1024;
+// Keys compared between checks for cancellation
+const CANCEL_CHECK_INTERVAL: u64 = 10_000;
+
+/// Sorted output in targeting key order, from a `Sorter` or a `SortedFile`
+pub struct SortedSource {
+    pub header: SortedFileHeader,
+    pub data: Box<dyn Read>,
+    pub compression: RunCompression,
+}
+
+impl SortedSource {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        if let Ok(sorter) = <&Sorter>::try_convert(value) {
+            return sorter.sorted_source();
+        }
+        if let Ok(sorted_file) = <&SortedFile>::try_convert(value) {
+            return sorted_file.sorted_source();
+        }
+        Err(Error::new(
+            arg_error(),
+            "Expected a CsvUtils::Sorter or CsvUtils::SortedFile",
+        ))
+    }
+}
+
+/// How a targeting key differs between the previous and current output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    // Only in the current output
+    Added,
+    // Only in the previous output
+    Removed,
+    // In both, with different rows
+    Changed,
+}
+
+impl Change {
+    pub fn name(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+/// Targeting key counts reported by `diff`
+#[derive(Debug, Default)]
+pub struct DiffStats {
+    pub added_keys: u64,
+    pub removed_keys: u64,
+    pub changed_keys: u64,
+    pub unchanged_keys: u64,
+}
+
+/// Compare two sorted outputs key by key, handing every key that differs to `emit` with
+/// its previous and current rows.
+///
+/// Only one key's rows are held from each side at a time. A key has changed when its rows
+/// differ in content or order; positions aren't compared, since they depend on where the
+/// rows were in each feed.
+pub fn diff<P: Read, C: Read, F>(
+    previous: GroupReader<P>,
+    current: GroupReader<C>,
+    cancel: &CancelToken,
+    mut emit: F,
+) -> io::Result<DiffStats>
+where
+    F: FnMut(Change, TargetKey, Vec<Vec<String>>, Vec<Vec<String>>) -> io::Result<()>,
+{
+    let (mut previous, mut current) = (previous, current);
+    let mut stats = DiffStats::default();
+    let mut keys = 0;
+
+    loop {
+        let (key, order) = match (previous.peek_key(), current.peek_key()) {
+            (None, None) => break,
+            (Some(key), None) => (key, Ordering::Less),
+            (None, Some(key)) => (key, Ordering::Greater),
+            (Some(previous_key), Some(current_key)) => (
+                previous_key.min(current_key),
+                previous_key.cmp(&current_key),
+            ),
+        };
+
+        match order {
+            Ordering::Less => {
+                stats.removed_keys += 1;
+                emit(
+                    Change::Removed,
+                    key,
+                    rows(previous.next_group()?)?,
+                    Vec::new(),
+                )?;
+            }
+            Ordering::Greater => {
+                stats.added_keys += 1;
+                emit(Change::Added, key, Vec::new(), rows(current.next_group()?)?)?;
+            }
+            Ordering::Equal => {
+                let previous_rows = rows(previous.next_group()?)?;
+                let current_rows = rows(current.next_group()?)?;
+                if previous_rows == current_rows {
+                    stats.unchanged_keys += 1;
+                } else {
+                    stats.changed_keys += 1;
+                    emit(Change::Changed, key, previous_rows, current_rows)?;
+                }
+            }
+        }
+
+        keys += 1;
+        if keys % CANCEL_CHECK_INTERVAL == 0 {
+            cancel.check()?;
+        }
+    }
+
+    Ok(stats)
+}
+
+fn rows(group: Group) -> io::Result<Vec<Vec<String>>> {
+    group
+        .into_iter()
+        .map(|(_, record_bytes)| {
+            bincode::decode_from_slice::<SortRecord, _>(&record_bytes, bincode::config::legacy())
+                .map(|(record, _)| record.record)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+/// `diff` for Ruby: compare `current` with the previous output given as the first
+/// argument, yielding each change to the block or writing them to `report:` as CSV.
+/// `cancel` is the token of the object `diff` was called on.
+pub fn diff_with(
+    current: SortedSource,
+    args: &[Value],
+    cancel: &CancelToken,
+) -> Result<RHash, Error> {
+    let args = scan_args::<(Value,), (Option<RHash>,), (), (), (), ()>(args)?;
+    let (previous,) = args.required;
+    let (options,) = args.optional;
+    let report_path = match options {
+        Some(options) => option_string(options, "report")?,
+        None => None,
+    };
+
+    let previous = SortedSource::from_value(previous)?;
+    check_compatible(&previous.header, &current.header)?;
+
+    let previous = GroupReader::new(previous.data, previous.compression, BUFFER_CAPACITY)
+        .map_err(|e| phase_error("Error reading sorted output", e))?;
+    let current = GroupReader::new(current.data, current.compression, BUFFER_CAPACITY)
+        .map_err(|e| phase_error("Error reading sorted output", e))?;
+
+    let stats = match report_path {
+        Some(report_path) => {
+            info!(target: "csv_utils::diff", "Writing diff report to {}", report_path);
+            without_gvl(cancel, || {
+                write_report(previous, current, cancel, &report_path)
+            })
+            .and_then(|result| result.map_err(|e| phase_error("Error writing diff report", e)))?
+        }
+        None => {
+            let ruby = Ruby::get().unwrap();
+            let block = ruby.block_proc()?;
+            yield_changes(previous, current, cancel, block)?
+        }
+    };
+
+    debug!(target: "csv_utils::diff", "Diff finished: {:?}", stats);
+
+    let result = RHash::new();
+    result.aset(Symbol::new("added_keys"), stats.added_keys)?;
+    result.aset(Symbol::new("removed_keys"), stats.removed_keys)?;
+    result.aset(Symbol::new("changed_keys"), stats.changed_keys)?;
+    result.aset(Symbol::new("unchanged_keys"), stats.unchanged_keys)?;
+    Ok(result)
+}
+
+// One CSV line per differing key: `change,key,previous_rows,rows`
+fn write_report<P: Read, C: Read>(
+    previous: GroupReader<P>,
+    current: GroupReader<C>,
+    cancel: &CancelToken,
+    report_path: &str,
+) -> io::Result<DiffStats> {
+    let mut writer = csv::Writer::from_writer(File::create(Path::new(report_path))?);
+    writer.write_record(["change", "key", "previous_rows", "rows"])?;
+
+    let stats = diff(
+        previous,
+        current,
+        cancel,
+        |change, key, previous_rows, current_rows| {
+            writer.write_record([
+                change.name().to_string(),
+                key.to_hex(),
+                previous_rows.len().to_string(),
+                current_rows.len().to_string(),
+            ])?;
+            Ok(())
+        },
+    )?;
+
+    writer.flush()?;
+    Ok(stats)
+}
+
+fn yield_changes<P: Read, C: Read>(
+    previous: GroupReader<P>,
+    current: GroupReader<C>,
+    cancel: &CancelToken,
+    block: Proc,
+) -> Result<DiffStats, Error> {
+    // An exception raised by the block, returned in place of the I/O error that ends the
+    // diff
+    let failure = RefCell::new(None);
+
+    let result = diff(
+        previous,
+        current,
+        cancel,
+        |change, key, previous_rows, current_rows| {
+            block
+                .call::<_, Value>((
+                    Symbol::new(change.name()),
+                    key.to_hex(),
+                    RArray::from_vec(previous_rows),
+                    RArray::from_vec(current_rows),
+                ))
+                .map_err(|e| {
+                    *failure.borrow_mut() = Some(e);
+                    io::Error::new(io::ErrorKind::Other, "Diff block raised an exception")
+                })?;
+            Ok(())
+        },
+    );
+
+    match (result, failure.into_inner()) {
+        (_, Some(e)) => Err(e),
+        (Ok(stats), None) => Ok(stats),
+        (Err(e), None) => Err(phase_error("Error comparing sorted output", e)),
+    }
+}
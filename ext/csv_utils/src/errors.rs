@@ -0,0 +1,89 @@
+use crate::spill::SpillLimitExceeded;
+use magnus::{exception::ExceptionClass, prelude::*, Error, RModule, Ruby};
+use std::io;
+
+// Look up an exception class defined in lib/csv_utils/error.rb, falling back to
+// RuntimeError if it is somehow missing
+fn csv_utils_error(name: &str) -> ExceptionClass {
+    Ruby::get()
+        .ok()
+        .and_then(|ruby| {
+            ruby.class_object()
+                .const_get::<_, RModule>("CsvUtils")
+                .and_then(|module| module.const_get::<_, ExceptionClass>(name))
+                .ok()
+        })
+        .unwrap_or_else(magnus::exception::runtime_error)
+}
+
+/// `CsvUtils::CancelledError`, raised when a long-running phase is cancelled
+pub fn cancelled_error() -> ExceptionClass {
+    csv_utils_error("CancelledError")
+}
+
+/// `CsvUtils::MissingKeyColumnError`, raised for rows without every key column when
+/// the sorter is configured with `missing_key_columns: :error`
+pub fn missing_key_column_error() -> ExceptionClass {
+    csv_utils_error("MissingKeyColumnError")
+}
+
+/// `CsvUtils::SorterBusyError`, raised when a sorter is called while another call is
+/// still using it, e.g. from another thread during `sort!`
+pub fn sorter_busy_error() -> ExceptionClass {
+    csv_utils_error("SorterBusyError")
+}
+
+/// `CsvUtils::SortedFileError`, raised when `SortedFile.open` is given a file it can't
+/// read
+pub fn sorted_file_error() -> ExceptionClass {
+    csv_utils_error("SortedFileError")
+}
+
+/// `CsvUtils::BinaryCopyFileError`, raised when `BinaryCopyFile` reads a file that isn't
+/// valid binary COPY data for its column types
+pub fn binary_copy_file_error() -> ExceptionClass {
+    csv_utils_error("BinaryCopyFileError")
+}
+
+/// `CsvUtils::WorkDirError`, raised when a sorter's working directory can't be used,
+/// e.g. because it holds runs from a sorter with different settings
+pub fn work_dir_error() -> ExceptionClass {
+    csv_utils_error("WorkDirError")
+}
+
+/// `CsvUtils::DiskLimitError`, raised when run files reach `max_spill_bytes` or the
+/// disk they are written to fills up
+pub fn disk_limit_error() -> ExceptionClass {
+    csv_utils_error("DiskLimitError")
+}
+
+/// `CsvUtils::PostgresError`, raised when `copy_to_postgres` can't connect or the server
+/// rejects a statement
+pub fn postgres_error() -> ExceptionClass {
+    csv_utils_error("PostgresError")
+}
+
+/// Convert an I/O error from a sorter phase into a Ruby exception
+pub fn phase_error(context: &str, e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::Interrupted {
+        return Error::new(cancelled_error(), format!("{} cancelled", context));
+    }
+
+    let limit_exceeded = e
+        .get_ref()
+        .is_some_and(|inner| inner.is::<SpillLimitExceeded>());
+    if limit_exceeded || e.kind() == io::ErrorKind::StorageFull {
+        return Error::new(disk_limit_error(), format!("{}: {}", context, e));
+    }
+
+    if e.get_ref()
+        .is_some_and(|inner| inner.is::<postgres::Error>())
+    {
+        return Error::new(postgres_error(), format!("{}: {}", context, e));
+    }
+
+    Error::new(
+        magnus::exception::runtime_error(),
+        format!("{}: {}", context, e),
+    )
+}
@@ -0,0 +1,48 @@
+use std::borrow::Borrow;
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+
+/// Reads a byte range of a file with positional reads, keeping its own position rather
+/// than the file's offset, which is shared by every clone of the file. Any number of
+/// sections of the same file can be read at once, e.g. both sides of `x.diff(x)`, or a
+/// lookup from inside a diff block.
+pub struct FileSection<F: Borrow<File>> {
+    file: F,
+    position: u64,
+    end: u64,
+}
+
+impl<F: Borrow<File>> FileSection<F> {
+    pub fn new(file: F, range: Range<u64>) -> Self {
+        Self {
+            file,
+            position: range.start,
+            end: range.end.max(range.start),
+        }
+    }
+}
+
+impl<F: Borrow<File>> Read for FileSection<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.end - self.position;
+        let len = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = read_at(self.file.borrow(), &mut buf[..len], self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
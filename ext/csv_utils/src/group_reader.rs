@@ -0,0 +1,56 @@
+use crate::run_file::{RunCompression, RunReader};
+use crate::sorter::KeyData;
+use crate::targeting_key::TargetKey;
+use std::io::{self, Read};
+
+/// Reads sorted output one targeting key's rows at a time, keeping each row's key and
+/// encoded record
+pub struct GroupReader<R: Read> {
+    reader: RunReader<R>,
+    next: Option<(KeyData, Vec<u8>)>,
+}
+
+pub type Group = Vec<(KeyData, Vec<u8>)>;
+
+impl<R: Read> GroupReader<R> {
+    pub fn new(inner: R, compression: RunCompression, capacity: usize) -> io::Result<Self> {
+        let mut reader = Self {
+            reader: RunReader::new(inner, compression, capacity),
+            next: None,
+        };
+        reader.next = reader.read_entry()?;
+        Ok(reader)
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<(KeyData, Vec<u8>)>> {
+        let mut bytes = Vec::new();
+        if !self.reader.read_record(&mut bytes)? {
+            return Ok(None);
+        }
+
+        // Records start with their key, so it can be decoded on its own
+        let (key, _) = bincode::decode_from_slice(&bytes, bincode::config::legacy())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some((key, bytes)))
+    }
+
+    /// Targeting key of the next group, `None` at the end of the output
+    pub fn peek_key(&self) -> Option<TargetKey> {
+        self.next.as_ref().map(|(key, _)| key.value)
+    }
+
+    /// Read every row of the next targeting key, or nothing at the end of the output
+    pub fn next_group(&mut self) -> io::Result<Group> {
+        let mut group = Vec::new();
+        let target_key = match self.peek_key() {
+            Some(target_key) => target_key,
+            None => return Ok(group),
+        };
+
+        while self.peek_key() == Some(target_key) {
+            group.push(self.next.take().unwrap());
+            self.next = self.read_entry()?;
+        }
+        Ok(group)
+    }
+}
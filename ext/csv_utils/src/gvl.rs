@@ -0,0 +1,144 @@
+use log::debug;
+use magnus::Error;
+use rb_sys::{rb_thread_call_with_gvl, rb_thread_call_without_gvl, VALUE};
+use std::ffi::c_void;
+use std::io;
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Cancellation flag shared between Ruby and long-running work done without the GVL.
+///
+/// An explicit `cancel` stays in effect for every later phase. An interrupt from Ruby
+/// only stops the phase it arrives in, since it may be a signal that a `trap` handler
+/// deals with and the thread carries on. The phases that poll the token bail out with
+/// an `Interrupted` I/O error.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<CancelState>);
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    // Set by Ruby's unblocking function, cleared as each phase starts
+    interrupted: AtomicBool,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed) || self.0.interrupted.load(Ordering::Relaxed)
+    }
+
+    // Polled from inside long loops
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            debug!(target: "csv_utils::gvl", "Operation cancelled");
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Operation cancelled",
+            ));
+        }
+        Ok(())
+    }
+}
+
+extern "C" {
+    // Exported by Ruby but not part of rb-sys' bindings
+    fn ruby_thread_has_gvl_p() -> c_int;
+}
+
+struct Call<F, R> {
+    func: Option<F>,
+    result: Option<std::thread::Result<R>>,
+}
+
+unsafe extern "C" fn call_func<F, R>(data: *mut c_void) -> *mut c_void
+where
+    F: FnOnce() -> R,
+{
+    let call = &mut *(data as *mut Call<F, R>);
+    if let Some(func) = call.func.take() {
+        // Panics must not unwind into Ruby's C frames; resume them once we're back
+        call.result = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+    }
+    std::ptr::null_mut()
+}
+
+// Called by Ruby (with or without the GVL) when the thread is interrupted, e.g. by
+// Thread#kill, Thread#raise or Timeout
+unsafe extern "C" fn unblock(data: *mut c_void) {
+    let interrupted = &*(data as *const AtomicBool);
+    interrupted.store(true, Ordering::SeqCst);
+}
+
+/// Run `func` with the GVL released so other Ruby threads keep running.
+///
+/// `func` must not touch any Ruby objects. An interrupt from Ruby cancels `token` until
+/// the next call, and `func` is expected to poll it; the interrupt itself is re-raised
+/// once the GVL has been reacquired.
+pub fn without_gvl<F, R>(token: &CancelToken, func: F) -> Result<R, Error>
+where
+    F: FnOnce() -> R,
+{
+    let mut call = Call {
+        func: Some(func),
+        result: None,
+    };
+    let data = &mut call as *mut Call<F, R> as *mut c_void;
+    token.0.interrupted.store(false, Ordering::SeqCst);
+    let interrupted = &token.0.interrupted as *const AtomicBool as *mut c_void;
+
+    // Ruby may raise a pending interrupt on the way in or out of the blocking region;
+    // protect turns that into an Error instead of unwinding through Rust frames
+    let protected = magnus::rb_sys::protect(|| unsafe {
+        rb_thread_call_without_gvl(Some(call_func::<F, R>), data, Some(unblock), interrupted)
+            as VALUE
+    });
+
+    // Code holding the GVL, such as a block yielded to between phases, isn't stopped
+    // by an interrupt that has already been dealt with
+    token.0.interrupted.store(false, Ordering::SeqCst);
+    let result = call.result.take();
+    protected?;
+
+    match result {
+        Some(Ok(value)) => Ok(value),
+        Some(Err(panic)) => panic::resume_unwind(panic),
+        // Ruby skipped the call because an interrupt was already pending
+        None => Err(Error::new(
+            crate::errors::cancelled_error(),
+            "Operation cancelled",
+        )),
+    }
+}
+
+/// Run `func` with the GVL held, reacquiring it if the current thread released it via
+/// `without_gvl`.
+///
+/// Must be called from a Ruby thread; returns `None` if Ruby refused to hand the GVL
+/// back, e.g. on a thread Ruby doesn't know about.
+pub fn with_gvl<F, R>(func: F) -> Option<R>
+where
+    F: FnOnce() -> R,
+{
+    if unsafe { ruby_thread_has_gvl_p() } != 0 {
+        return Some(func());
+    }
+
+    let mut call = Call {
+        func: Some(func),
+        result: None,
+    };
+    let data = &mut call as *mut Call<F, R> as *mut c_void;
+    unsafe { rb_thread_call_with_gvl(Some(call_func::<F, R>), data) };
+
+    match call.result.take() {
+        Some(Ok(value)) => Some(value),
+        Some(Err(panic)) => panic::resume_unwind(panic),
+        None => None,
+    }
+}
@@ -0,0 +1,79 @@
+use crate::file_section::FileSection;
+use crate::run_file::{RunCompression, RunReader};
+use crate::sorter::SortRecord;
+use crate::targeting_key::TargetKey;
+use bincode::{Decode, Encode};
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+
+// Minimum bytes of sorted output between index entries
+const INDEX_INTERVAL: u64 = 64 * 1024;
+// Read buffer for lookups, which only read about one interval
+const LOOKUP_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Sparse index over the sorted output, built while it is written.
+///
+/// Every `INDEX_INTERVAL` bytes or so, the key of the next entry and the offset a
+/// reader can start from are recorded. A lookup binary-searches the index and scans
+/// forward from there, so it reads roughly one interval plus the key's own rows.
+#[derive(Encode, Decode, Debug, Clone, Default)]
+pub struct KeyIndex {
+    entries: Vec<(TargetKey, u64)>,
+}
+
+impl KeyIndex {
+    /// Called before each entry is written, with the writer's `entry_offset`
+    pub fn record(&mut self, key: &TargetKey, offset: Option<u64>) {
+        let offset = match offset {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        match self.entries.last() {
+            Some((_, last)) if offset < last + INDEX_INTERVAL => {}
+            _ => self.entries.push((*key, offset)),
+        }
+    }
+
+    // Offset to scan from for `key`: the last indexed entry with a smaller key. An
+    // entry with the same key may be partway through that key's rows.
+    fn seek_offset(&self, key: &TargetKey) -> u64 {
+        let index = self.entries.partition_point(|(k, _)| k < key);
+        match index {
+            0 => 0,
+            _ => self.entries[index - 1].1,
+        }
+    }
+
+    /// Read every row with targeting key `key` from sorted output stored at `data` in
+    /// the file
+    pub fn lookup(
+        &self,
+        file: &File,
+        data: Range<u64>,
+        compression: RunCompression,
+        key: &TargetKey,
+    ) -> io::Result<Vec<Vec<String>>> {
+        let offset = data.start + self.seek_offset(key);
+        let input = FileSection::new(file, offset..data.end);
+        let mut reader = RunReader::new(input, compression, LOOKUP_BUFFER_CAPACITY);
+
+        let mut rows = Vec::new();
+        let mut bytes = Vec::new();
+        while reader.read_record(&mut bytes)? {
+            let record: SortRecord = bincode::decode_from_slice(&bytes, bincode::config::legacy())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .0;
+
+            if record.key.value > *key {
+                break;
+            }
+            if record.key.value == *key {
+                rows.push(record.record);
+            }
+        }
+
+        Ok(rows)
+    }
+}
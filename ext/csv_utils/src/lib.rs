@@ -1,10 +1,36 @@
 use log::info;
 use magnus::{Error, Ruby};
 
+mod binary_copy_file;
+mod binary_copy_file_reader;
 mod binary_copy_file_writer;
+mod copy_convert;
+mod copy_schema;
+mod csv_options;
+mod decompress;
+mod dedup;
+mod diff;
+mod errors;
+mod file_section;
+mod group_reader;
+mod gvl;
+mod key_index;
 mod postgres_copier;
+mod postgres_loader;
+mod progress;
+mod row_limit;
+mod ruby_io;
+mod ruby_options;
+mod run_file;
+mod sort_spec;
+mod sorted_file;
 mod sorter;
+mod sorter_options;
+mod spill;
+mod targeting_key;
+mod upsert;
 mod validator;
+mod work_dir;
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
@@ -19,6 +45,8 @@ fn init(ruby: &Ruby) -> Result<(), Error> {
     let module = ruby.define_module("CsvUtils")?;
 
     sorter::register(ruby, &module)?;
+    sorted_file::register(ruby, &module)?;
+    binary_copy_file::register(ruby, &module)?;
     validator::register(ruby, &module)?;
 
     Ok(())
@@ -1,13 +1,15 @@
 use crate::binary_copy_file_writer::BinaryCopyFileWriter;
+use crate::copy_schema::{CopyRow, CopySchema, CopyValue, ValueError};
+use crate::gvl::CancelToken;
+use crate::progress::ProgressTracker;
+use crate::run_file::{RunCompression, RunReader};
 use crate::sorter::SortRecord;
-use faster_hex::hex_encode;
+use crate::validator::Validator;
 use log::{debug, error, info, trace, warn};
 use postgis::ewkb::Point;
-use postgres::types::Kind;
 use postgres::types::ToSql;
-use postgres::types::Type;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{Read, Write};
 use std::path::Path;
 use std::time::SystemTime;
 
@@ -15,21 +17,33 @@ pub type GeoIndexes = (usize, usize);
 
 const BUFFER_CAPACITY: usize = 5 * 1024 * 1024;
 
-pub struct PostgresCopier {
-    reader: BufReader<File>,
+pub struct PostgresCopier<R: Read> {
+    reader: RunReader<R>,
     geo_indexes: Option<GeoIndexes>,
     source_key: String,
+    schema: CopySchema,
 }
 
-type RowItem = ([u8; 20], Option<Point>, Vec<String>);
+/// Counts reported by `write_binary_postgres_file`
+#[derive(Debug, Default)]
+pub struct CopyStats {
+    pub rows: u64,
+    // CSV fields written as NULL because they didn't convert to their column's type
+    pub conversion_errors: usize,
+}
+
+// Sorted record, geo point and the size of the encoded record
+type RowItem = (SortRecord, Option<Point>, usize);
 
-impl PostgresCopier {
+impl<R: Read> PostgresCopier<R> {
     pub fn new(
-        input_file: File,
+        input_file: R,
+        compression: RunCompression,
         geo_indexes: Option<GeoIndexes>,
         source_key: String,
+        schema: CopySchema,
     ) -> Result<Self, std::io::Error> {
-        let reader = BufReader::with_capacity(BUFFER_CAPACITY, input_file);
+        let reader = RunReader::new(input_file, compression, BUFFER_CAPACITY);
 
         info!(
             target: "csv_utils::postgres_copier",
@@ -54,6 +68,7 @@ impl PostgresCopier {
             reader,
             geo_indexes,
             source_key,
+            schema,
         })
     }
 
@@ -63,24 +78,25 @@ impl PostgresCopier {
             "Starting to iterate through records"
         );
 
-        std::iter::from_fn(move || {
-            let mut len_bytes = [0u8; 4];
-            if self.reader.read_exact(&mut len_bytes).is_err() {
-                debug!(
-                    target: "csv_utils::postgres_copier",
-                    "Reached end of input file"
-                );
-                return None; // EOF
-            }
-            let len = u32::from_le_bytes(len_bytes) as usize;
+        let mut bytes = Vec::new();
 
-            let mut bytes = vec![0u8; len];
-            if let Err(e) = self.reader.read_exact(&mut bytes) {
-                error!(
-                    target: "csv_utils::postgres_copier",
-                    "Error reading record bytes: {}", e
-                );
-                return Some(Err(e));
+        std::iter::from_fn(move || {
+            match self.reader.read_record(&mut bytes) {
+                Ok(true) => {}
+                Ok(false) => {
+                    debug!(
+                        target: "csv_utils::postgres_copier",
+                        "Reached end of input file"
+                    );
+                    return None; // EOF
+                }
+                Err(e) => {
+                    error!(
+                        target: "csv_utils::postgres_copier",
+                        "Error reading record bytes: {}", e
+                    );
+                    return Some(Err(e));
+                }
             }
 
             let record: SortRecord =
@@ -95,7 +111,6 @@ impl PostgresCopier {
                     }
                 };
 
-            let target_key = record.key.value;
             let geo_key = if self.geo_indexes.is_some() {
                 self.generate_geo_key(&record.record)
             } else {
@@ -105,10 +120,10 @@ impl PostgresCopier {
             trace!(
                 target: "csv_utils::postgres_copier",
                 "Processed record with key: {}",
-                std::str::from_utf8(&target_key).unwrap_or("<invalid utf8>")
+                record.key.value.to_hex()
             );
 
-            Some(Ok((target_key, geo_key, record.record)))
+            Some(Ok((record, geo_key, bytes.len())))
         })
     }
 
@@ -168,31 +183,21 @@ impl PostgresCopier {
         Some(point)
     }
 
-    pub fn copy(&mut self, output_file_path: &Path) -> Result<(), std::io::Error> {
+    pub fn copy(
+        &mut self,
+        output_file_path: &Path,
+        cancel: &CancelToken,
+        progress: &mut ProgressTracker,
+        validator: Option<&mut Validator>,
+    ) -> Result<CopyStats, std::io::Error> {
+        cancel.check()?;
+
         info!(
             target: "csv_utils::postgres_copier",
             "Starting COPY to PostgreSQL binary format: {}",
             output_file_path.display()
         );
 
-        let now = SystemTime::now();
-        let source_key = self.source_key.clone();
-
-        let geometry_type = Self::make_geometry_type();
-        debug!(
-            target: "csv_utils::postgres_copier",
-            "Using geometry type: {}", geometry_type
-        );
-
-        let types = vec![
-            Type::VARCHAR,
-            Type::VARCHAR,
-            geometry_type,
-            Type::VARCHAR_ARRAY,
-            Type::TIMESTAMP,
-            Type::TIMESTAMP,
-        ];
-
         let output_file = match File::create(output_file_path) {
             Ok(f) => f,
             Err(e) => {
@@ -205,7 +210,36 @@ impl PostgresCopier {
             }
         };
 
-        let mut writer = BinaryCopyFileWriter::new(types, output_file);
+        let (stats, _) = self.write_to(output_file, cancel, progress, validator)?;
+        Ok(stats)
+    }
+
+    /// Write every row in binary COPY format to `output`, e.g. a file or a `COPY FROM
+    /// STDIN` stream, handing it back flushed
+    pub fn write_to<W: Write>(
+        &mut self,
+        output: W,
+        cancel: &CancelToken,
+        progress: &mut ProgressTracker,
+        mut validator: Option<&mut Validator>,
+    ) -> Result<(CopyStats, W), std::io::Error> {
+        cancel.check()?;
+        // The validation results returned count this write's conversion errors only
+        if let Some(validator) = validator.as_deref_mut() {
+            validator.conversion_error_count = 0;
+        }
+
+        let now = SystemTime::now();
+        let source_key = self.source_key.clone();
+
+        let schema = self.schema.clone();
+        let types = schema.types();
+        debug!(
+            target: "csv_utils::postgres_copier",
+            "Using column types: {:?}", types
+        );
+
+        let mut writer = BinaryCopyFileWriter::new(types, output);
         debug!(
             target: "csv_utils::postgres_copier",
             "Created binary copy file writer"
@@ -213,30 +247,61 @@ impl PostgresCopier {
 
         writer.write_header()?;
         let mut row_count = 0;
-        let mut hex_target_key = [0u8; 40];
+        let mut bytes_read = 0;
+        let mut conversion_errors = 0;
 
         for result in self.iter_records() {
             match result {
-                Ok((target_key, geo_key, record)) => {
-                    hex_encode(&target_key, &mut hex_target_key).unwrap();
-                    let hex_target_key_str = String::from_utf8(hex_target_key.to_vec()).unwrap();
-
-                    let row: Vec<&(dyn ToSql + Sync)> = vec![
-                        &source_key,
-                        &hex_target_key_str,
-                        &geo_key,
-                        &record,
-                        &now,
-                        &now,
-                    ];
+                Ok((record, geo_point, record_size)) => {
+                    let copy_row = CopyRow {
+                        source_key: &source_key,
+                        record: &record,
+                        geo_point,
+                        now,
+                    };
+                    let mut values = Vec::with_capacity(schema.columns.len());
+                    let mut row_failed = false;
+                    for column in &schema.columns {
+                        let value = match column.value(&copy_row) {
+                            Ok(value) => value,
+                            // Written as NULL rather than failing the whole file
+                            Err(ValueError::Conversion { column: index }) => {
+                                conversion_errors += 1;
+                                row_failed = true;
+                                report_conversion_error(
+                                    validator.as_deref_mut(),
+                                    record.key.position,
+                                    index,
+                                    column.pg_type.name(),
+                                );
+                                CopyValue::Null
+                            }
+                            Err(ValueError::Io(e)) => return Err(e),
+                        };
+                        values.push(value);
+                    }
+                    // Validation counts rows, however many of their fields failed
+                    if row_failed {
+                        if let Some(validator) = validator.as_deref_mut() {
+                            validator.conversion_error_count += 1;
+                        }
+                    }
+
+                    let row: Vec<&(dyn ToSql + Sync)> = values
+                        .iter()
+                        .map(|value| value as &(dyn ToSql + Sync))
+                        .collect();
                     writer.write_row(&row)?;
                     row_count += 1;
+                    bytes_read += record_size as u64;
+                    progress.update(row_count, bytes_read)?;
 
                     if row_count % 10000 == 0 {
                         debug!(
                             target: "csv_utils::postgres_copier",
                             "Processed {} rows", row_count
                         );
+                        cancel.check()?;
                     }
                 }
                 Err(e) => {
@@ -250,20 +315,44 @@ impl PostgresCopier {
         }
 
         writer.write_footer()?;
+        progress.finish(row_count, bytes_read)?;
+        if conversion_errors > 0 {
+            warn!(
+                target: "csv_utils::postgres_copier",
+                "Wrote {} fields that could not be converted as NULL", conversion_errors
+            );
+        }
         info!(
             target: "csv_utils::postgres_copier",
             "Completed PostgreSQL binary copy with {} rows", row_count
         );
 
-        Ok(())
+        let stats = CopyStats {
+            rows: row_count,
+            conversion_errors,
+        };
+        Ok((stats, writer.into_inner()?))
     }
+}
 
-    fn make_geometry_type() -> Type {
-        Type::new(
-            "geometry".to_string(),
-            Type::POINT.oid(),
-            Kind::Simple,
-            "public".to_string(),
-        )
+// Log a CSV field that didn't convert to its column's type to the validator's error log
+fn report_conversion_error(
+    validator: Option<&mut Validator>,
+    position: usize,
+    column: usize,
+    type_name: &str,
+) {
+    debug!(
+        target: "csv_utils::postgres_copier",
+        "Row {} column {} could not be converted to {}", position, column, type_name
+    );
+
+    if let Some(validator) = validator {
+        let _ = validator.add_error_to_file(
+            "conversion",
+            position,
+            column,
+            &format!("Column {} ({})", column + 1, type_name),
+        );
     }
 }
@@ -0,0 +1,479 @@
+use crate::gvl::CancelToken;
+use crate::postgres_copier::{CopyStats, PostgresCopier};
+use crate::progress::ProgressTracker;
+use crate::ruby_options::option_string;
+use crate::validator::Validator;
+use log::{debug, error, info, warn};
+use magnus::{exception::arg_error, Error, RArray, RHash, Symbol};
+use postgres::{Client, Config, NoTls, Transaction};
+use std::io::{self, Read};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Longer identifiers are truncated by PostgreSQL
+const MAX_IDENTIFIER_BYTES: usize = 63;
+// Used when the connection string doesn't set connect_timeout
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// How often `CancelWatcher` checks whether the load was cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where `copy_to_postgres` loads rows and how long it may take
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    // Table to COPY into, optionally schema-qualified
+    pub table: String,
+    // Table columns the COPY columns go to, in order; all of them when not given
+    pub target_columns: Option<Vec<String>>,
+    pub statement_timeout: Option<Duration>,
+}
+
+impl LoadOptions {
+    pub fn from_ruby(table: String, options: Option<RHash>) -> Result<Self, Error> {
+        if table.is_empty() || table.split('.').any(str::is_empty) {
+            return Err(Error::new(
+                arg_error(),
+                format!("Invalid table name: {:?}", table),
+            ));
+        }
+
+        let mut result = Self {
+            table,
+            target_columns: None,
+            statement_timeout: None,
+        };
+        let options = match options {
+            Some(options) => options,
+            None => return Ok(result),
+        };
+
+        if let Some(columns) = options.lookup::<_, Option<RArray>>(Symbol::new("target_columns"))? {
+            let columns = columns.to_vec::<String>()?;
+            if columns.is_empty() {
+                return Err(Error::new(arg_error(), "target_columns must not be empty"));
+            }
+            result.target_columns = Some(columns);
+        }
+
+        if let Some(seconds) = options.lookup::<_, Option<f64>>(Symbol::new("statement_timeout"))? {
+            if !(seconds > 0.0 && seconds.is_finite()) {
+                error!(
+                    target: "csv_utils::postgres_loader",
+                    "Invalid statement_timeout: {}", seconds
+                );
+                return Err(Error::new(
+                    arg_error(),
+                    "statement_timeout must be a positive number of seconds",
+                ));
+            }
+            result.statement_timeout = Some(Duration::from_secs_f64(seconds));
+        }
+
+        debug!(target: "csv_utils::postgres_loader", "Using load options: {:?}", result);
+
+        Ok(result)
+    }
+
+    // The target columns, quoted and comma separated
+    fn quoted_columns(&self) -> Option<String> {
+        self.target_columns.as_ref().map(|columns| {
+            columns
+                .iter()
+                .map(|column| quote_identifier(column))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+    }
+}
+
+/// How `load_into_postgres` puts the staged rows in place of the old ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replace {
+    // Delete the target's rows for this source key and insert the staged rows
+    SourceKey,
+    // Truncate the target and insert the staged rows
+    Table,
+}
+
+/// Settings for the staging table used by `load_into_postgres`
+#[derive(Debug, Clone)]
+pub struct StagingOptions {
+    pub replace: Replace,
+    // ANALYZE the target once the rows are in
+    pub analyze: bool,
+    // Target column holding the source key, for `Replace::SourceKey`
+    pub source_key_column: String,
+}
+
+impl StagingOptions {
+    pub fn from_ruby(options: Option<RHash>) -> Result<Self, Error> {
+        let mut result = Self {
+            replace: Replace::SourceKey,
+            analyze: false,
+            source_key_column: "source_key".to_string(),
+        };
+        let options = match options {
+            Some(options) => options,
+            None => return Ok(result),
+        };
+
+        if let Some(replace) = option_string(options, "replace")? {
+            result.replace = match replace.as_str() {
+                "source_key" => Replace::SourceKey,
+                "table" => Replace::Table,
+                _ => {
+                    error!(target: "csv_utils::postgres_loader", "Invalid replace: {}", replace);
+                    return Err(Error::new(
+                        arg_error(),
+                        format!("Invalid replace: {}", replace),
+                    ));
+                }
+            };
+        }
+        if let Some(analyze) = options.lookup::<_, Option<bool>>(Symbol::new("analyze"))? {
+            result.analyze = analyze;
+        }
+        if let Some(column) = option_string(options, "source_key_column")? {
+            result.source_key_column = column;
+        }
+
+        debug!(target: "csv_utils::postgres_loader", "Using staging options: {:?}", result);
+
+        Ok(result)
+    }
+}
+
+/// What `load_into_postgres` did
+#[derive(Debug)]
+pub struct LoadStats {
+    pub copy: CopyStats,
+    // Rows deleted from the target, for the source key or all of them
+    pub removed_rows: u64,
+}
+
+/// Stream the copier's rows into a table with `COPY ... FROM STDIN` over a new
+/// connection. It runs in a transaction that is only committed once every row has been
+/// sent, so a failure or cancellation leaves the table as it was.
+pub fn copy_to_postgres<R: Read>(
+    copier: &mut PostgresCopier<R>,
+    conninfo: &str,
+    options: &LoadOptions,
+    cancel: &CancelToken,
+    progress: &mut ProgressTracker,
+    validator: Option<&mut Validator>,
+) -> io::Result<CopyStats> {
+    cancel.check()?;
+
+    let mut client = connect(conninfo)?;
+    let _watcher = CancelWatcher::start(&client, cancel);
+    copy_over(&mut client, copier, options, cancel, progress, validator)
+        .map_err(|e| cancelled_or(cancel, e))
+}
+
+fn copy_over<R: Read>(
+    client: &mut Client,
+    copier: &mut PostgresCopier<R>,
+    options: &LoadOptions,
+    cancel: &CancelToken,
+    progress: &mut ProgressTracker,
+    validator: Option<&mut Validator>,
+) -> io::Result<CopyStats> {
+    let mut transaction = begin(client, options)?;
+    let table = quote_table(&options.table);
+    let stats = copy_in(
+        &mut transaction,
+        &table,
+        options,
+        copier,
+        cancel,
+        progress,
+        validator,
+    )?;
+
+    cancel.check()?;
+    transaction.commit().map_err(pg_error)?;
+    Ok(stats)
+}
+
+/// Load the copier's rows through a staging table: create an unlogged table with the
+/// target's columns, COPY into it, then replace either the target's rows for `source_key`
+/// or all of them with its rows.
+///
+/// Everything happens in one transaction over a new connection, so other sessions see
+/// the old rows until the new ones are in place, and a failure or cancellation leaves
+/// the target as it was and no staging table behind. The target itself is only ever
+/// deleted from and inserted into, so its grants, owner, triggers, policies, foreign keys
+/// and sequences stay as they are.
+#[allow(clippy::too_many_arguments)]
+pub fn load_into_postgres<R: Read>(
+    copier: &mut PostgresCopier<R>,
+    conninfo: &str,
+    source_key: &str,
+    options: &LoadOptions,
+    staging: &StagingOptions,
+    cancel: &CancelToken,
+    progress: &mut ProgressTracker,
+    validator: Option<&mut Validator>,
+) -> io::Result<LoadStats> {
+    cancel.check()?;
+
+    let mut client = connect(conninfo)?;
+    let _watcher = CancelWatcher::start(&client, cancel);
+    load_over(
+        &mut client,
+        copier,
+        source_key,
+        options,
+        staging,
+        cancel,
+        progress,
+        validator,
+    )
+    .map_err(|e| cancelled_or(cancel, e))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn load_over<R: Read>(
+    client: &mut Client,
+    copier: &mut PostgresCopier<R>,
+    source_key: &str,
+    options: &LoadOptions,
+    staging: &StagingOptions,
+    cancel: &CancelToken,
+    progress: &mut ProgressTracker,
+    validator: Option<&mut Validator>,
+) -> io::Result<LoadStats> {
+    let target = quote_table(&options.table);
+    let mut transaction = begin(client, options)?;
+
+    // Put the staging table in the target's schema, wherever the search path found it
+    let row = transaction
+        .query_one(
+            "SELECT relnamespace::regnamespace::text, relname::text FROM pg_class \
+             WHERE oid = $1::text::regclass",
+            &[&target],
+        )
+        .map_err(pg_error)?;
+    let (schema, name): (String, String) = (row.get(0), row.get(1));
+    let staging_table = format!(
+        "{}.{}",
+        schema,
+        quote_identifier(&staging_table_name(&name))
+    );
+
+    // Only the columns being loaded, without indexes, constraints or defaults: the
+    // target checks the rows when they are inserted into it. The staging table is
+    // dropped in this transaction, so it needn't survive a crash, and only the insert
+    // into the target is written to the WAL.
+    let (columns, select) = match options.quoted_columns() {
+        Some(columns) => (format!(" ({})", columns), columns),
+        None => (String::new(), "*".to_string()),
+    };
+    info!(
+        target: "csv_utils::postgres_loader",
+        "Creating staging table {} with the columns of {}", staging_table, target
+    );
+    transaction
+        .batch_execute(&format!(
+            "CREATE UNLOGGED TABLE {} AS SELECT {} FROM {} WITH NO DATA",
+            staging_table, select, target
+        ))
+        .map_err(pg_error)?;
+
+    let copy = copy_in(
+        &mut transaction,
+        &staging_table,
+        options,
+        copier,
+        cancel,
+        progress,
+        validator,
+    )?;
+    cancel.check()?;
+
+    let removed_rows = match staging.replace {
+        Replace::SourceKey => {
+            let removed = transaction
+                .execute(
+                    format!(
+                        "DELETE FROM {} WHERE {} = $1",
+                        target,
+                        quote_identifier(&staging.source_key_column)
+                    )
+                    .as_str(),
+                    &[&source_key],
+                )
+                .map_err(pg_error)?;
+            info!(
+                target: "csv_utils::postgres_loader",
+                "Deleted {} rows for source key {} from {}", removed, source_key, target
+            );
+            removed
+        }
+        Replace::Table => {
+            // Not TRUNCATE, which would lock readers out until the commit
+            let removed = transaction
+                .execute(format!("DELETE FROM {}", target).as_str(), &[])
+                .map_err(pg_error)?;
+            info!(
+                target: "csv_utils::postgres_loader",
+                "Deleted all {} rows from {}", removed, target
+            );
+            removed
+        }
+    };
+
+    transaction
+        .batch_execute(&format!(
+            "INSERT INTO {}{} SELECT {} FROM {}; DROP TABLE {}",
+            target, columns, select, staging_table, staging_table
+        ))
+        .map_err(pg_error)?;
+    if staging.analyze {
+        transaction
+            .batch_execute(&format!("ANALYZE {}", target))
+            .map_err(pg_error)?;
+    }
+    info!(
+        target: "csv_utils::postgres_loader",
+        "Inserted {} rows into {}", copy.rows, target
+    );
+
+    cancel.check()?;
+    transaction.commit().map_err(pg_error)?;
+    Ok(LoadStats { copy, removed_rows })
+}
+
+// COPY every row into `table` (already quoted) as part of `transaction`
+fn copy_in<R: Read>(
+    transaction: &mut Transaction,
+    table: &str,
+    options: &LoadOptions,
+    copier: &mut PostgresCopier<R>,
+    cancel: &CancelToken,
+    progress: &mut ProgressTracker,
+    validator: Option<&mut Validator>,
+) -> io::Result<CopyStats> {
+    let columns = match options.quoted_columns() {
+        Some(columns) => format!(" ({})", columns),
+        None => String::new(),
+    };
+    let statement = format!("COPY {}{} FROM STDIN (FORMAT binary)", table, columns);
+    info!(target: "csv_utils::postgres_loader", "Starting {}", statement);
+
+    let sink = transaction.copy_in(&statement).map_err(pg_error)?;
+    let (stats, sink) = copier.write_to(sink, cancel, progress, validator)?;
+    let copied = sink.finish().map_err(pg_error)?;
+    info!(
+        target: "csv_utils::postgres_loader",
+        "Copied {} rows into {}", copied, table
+    );
+
+    Ok(stats)
+}
+
+// Connect without TLS, giving up on a server that can't be reached after the connection
+// string's connect_timeout, or `DEFAULT_CONNECT_TIMEOUT`
+fn connect(conninfo: &str) -> io::Result<Client> {
+    let mut config = conninfo.parse::<Config>().map_err(pg_error)?;
+    if config.get_connect_timeout().is_none() {
+        config.connect_timeout(DEFAULT_CONNECT_TIMEOUT);
+    }
+    config.connect(NoTls).map_err(pg_error)
+}
+
+/// Sends PostgreSQL a cancel request for the connection's running statement once the
+/// load is cancelled, so `cancel!` and interrupts also stop a statement the server is
+/// working on or waiting in, e.g. for a lock, rather than only the rows still to send
+struct CancelWatcher {
+    // Dropped to stop the watching thread
+    done: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl CancelWatcher {
+    fn start(client: &Client, cancel: &CancelToken) -> Self {
+        let token = client.cancel_token();
+        let cancel = cancel.clone();
+        let (done, finished) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(CANCEL_POLL_INTERVAL) {
+                if cancel.is_cancelled() {
+                    debug!(target: "csv_utils::postgres_loader", "Cancelling running statement");
+                    if let Err(e) = token.cancel_query(NoTls) {
+                        warn!(
+                            target: "csv_utils::postgres_loader",
+                            "Failed to send cancel request: {}", e
+                        );
+                    }
+                    return;
+                }
+            }
+        });
+        Self {
+            done: Some(done),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for CancelWatcher {
+    fn drop(&mut self) {
+        self.done.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// A statement stopped by `CancelWatcher` fails with a server error; report it as the
+// cancellation it was
+fn cancelled_or(cancel: &CancelToken, e: io::Error) -> io::Error {
+    match cancel.check() {
+        Err(cancelled) => cancelled,
+        Ok(()) => e,
+    }
+}
+
+// Start a transaction, with the statement timeout applied to everything in it
+fn begin<'a>(client: &'a mut Client, options: &LoadOptions) -> io::Result<Transaction<'a>> {
+    let mut transaction = client.transaction().map_err(pg_error)?;
+    if let Some(timeout) = options.statement_timeout {
+        transaction
+            .batch_execute(&format!(
+                "SET LOCAL statement_timeout = {}",
+                timeout.as_millis().max(1)
+            ))
+            .map_err(pg_error)?;
+    }
+    Ok(transaction)
+}
+
+// Kept inside the I/O error, so `phase_error` can raise `CsvUtils::PostgresError`
+fn pg_error(e: postgres::Error) -> io::Error {
+    error!(target: "csv_utils::postgres_loader", "PostgreSQL error: {}", e);
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Unique name for a staging table, kept within PostgreSQL's 63 byte identifier limit
+fn staging_table_name(name: &str) -> String {
+    let suffix = format!("_staging_{:08x}", rand::random::<u32>());
+    let mut end = name.len().min(MAX_IDENTIFIER_BYTES - suffix.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], suffix)
+}
+
+// `schema.table` or `table`, each part quoted
+fn quote_table(table: &str) -> String {
+    table
+        .split('.')
+        .map(quote_identifier)
+        .collect::<Vec<_>>()
+        .join(".")
+}
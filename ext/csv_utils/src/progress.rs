@@ -0,0 +1,213 @@
+use crate::gvl::with_gvl;
+use log::{debug, error};
+use magnus::{
+    exception::arg_error,
+    gc,
+    value::{InnerValue, Opaque},
+    Error, Proc, RHash, Ruby, Symbol, Value,
+};
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read};
+use std::rc::Rc;
+
+// Rows between reports when neither threshold is given
+const DEFAULT_EVERY_ROWS: u64 = 100_000;
+
+/// A block registered with `Sorter#on_progress` and how often to call it
+#[derive(Clone, Copy)]
+pub struct Progress {
+    callback: Opaque<Proc>,
+    every_rows: Option<u64>,
+    every_bytes: Option<u64>,
+}
+
+impl Progress {
+    // Build from the block and an options hash such as `{ every_rows: 10_000 }`
+    pub fn new(callback: Proc, options: Option<RHash>) -> Result<Self, Error> {
+        let (every_rows, every_bytes) = match options {
+            Some(options) => (
+                options.lookup::<_, Option<u64>>(Symbol::new("every_rows"))?,
+                options.lookup::<_, Option<u64>>(Symbol::new("every_bytes"))?,
+            ),
+            None => (None, None),
+        };
+
+        if every_rows == Some(0) || every_bytes == Some(0) {
+            error!(target: "csv_utils::progress", "Invalid progress interval");
+            return Err(Error::new(
+                arg_error(),
+                "every_rows and every_bytes must be at least 1",
+            ));
+        }
+
+        Ok(Self {
+            callback: callback.into(),
+            every_rows: match (every_rows, every_bytes) {
+                (None, None) => Some(DEFAULT_EVERY_ROWS),
+                _ => every_rows,
+            },
+            every_bytes,
+        })
+    }
+
+    pub fn mark(&self, marker: &gc::Marker) {
+        marker.mark(self.callback);
+    }
+}
+
+/// Hands progress from a Sorter method's phases to the registered block.
+///
+/// Phases may run without the GVL, so an exception raised by the block can't be
+/// returned through them directly. It is kept here instead, the phase is aborted with
+/// an I/O error, and `result` swaps the original exception back in.
+pub struct ProgressReporter {
+    progress: Option<Progress>,
+    failure: RefCell<Option<Error>>,
+}
+
+impl ProgressReporter {
+    pub fn new(progress: Option<Progress>) -> Self {
+        Self {
+            progress,
+            failure: RefCell::new(None),
+        }
+    }
+
+    // Start tracking a phase; totals are `None` when they aren't known up front
+    pub fn phase(
+        &self,
+        name: &'static str,
+        total_rows: Option<u64>,
+        total_bytes: Option<u64>,
+    ) -> ProgressTracker<'_> {
+        ProgressTracker {
+            reporter: self,
+            phase: name,
+            rows: 0,
+            bytes: 0,
+            total_rows,
+            total_bytes,
+            next_rows: self.progress.and_then(|p| p.every_rows).unwrap_or(u64::MAX),
+            next_bytes: self
+                .progress
+                .and_then(|p| p.every_bytes)
+                .unwrap_or(u64::MAX),
+        }
+    }
+
+    /// Prefer an exception raised by the block over the error it caused
+    pub fn result<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        match self.failure.borrow_mut().take() {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    fn call(&self, progress: &Progress, tracker: &ProgressTracker) -> io::Result<()> {
+        let called = with_gvl(|| -> Result<(), Error> {
+            let ruby = Ruby::get().unwrap();
+            let hash = RHash::new();
+            hash.aset(Symbol::new("phase"), Symbol::new(tracker.phase))?;
+            hash.aset(Symbol::new("rows"), tracker.rows)?;
+            hash.aset(Symbol::new("bytes"), tracker.bytes)?;
+            hash.aset(Symbol::new("total_rows"), tracker.total_rows)?;
+            hash.aset(Symbol::new("total_bytes"), tracker.total_bytes)?;
+            progress
+                .callback
+                .get_inner_with(&ruby)
+                .call::<_, Value>((hash,))?;
+            Ok(())
+        });
+
+        match called {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) => {
+                debug!(target: "csv_utils::progress", "Progress block raised: {}", e);
+                *self.failure.borrow_mut() = Some(e);
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Progress block raised an exception",
+                ))
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Could not reacquire the GVL to report progress",
+            )),
+        }
+    }
+}
+
+/// Counts for one phase, reported whenever a row or byte threshold is crossed
+pub struct ProgressTracker<'a> {
+    reporter: &'a ProgressReporter,
+    phase: &'static str,
+    rows: u64,
+    bytes: u64,
+    total_rows: Option<u64>,
+    total_bytes: Option<u64>,
+    next_rows: u64,
+    next_bytes: u64,
+}
+
+impl ProgressTracker<'_> {
+    /// Record the phase's running totals, calling the block if a threshold was crossed
+    pub fn update(&mut self, rows: u64, bytes: u64) -> io::Result<()> {
+        self.rows = rows;
+        self.bytes = bytes;
+
+        let progress = match self.reporter.progress {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        if rows < self.next_rows && bytes < self.next_bytes {
+            return Ok(());
+        }
+
+        if let Some(every_rows) = progress.every_rows {
+            self.next_rows = (rows / every_rows + 1) * every_rows;
+        }
+        if let Some(every_bytes) = progress.every_bytes {
+            self.next_bytes = (bytes / every_bytes + 1) * every_bytes;
+        }
+        self.reporter.call(&progress, self)
+    }
+
+    /// Report the final totals of the phase
+    pub fn finish(&mut self, rows: u64, bytes: u64) -> io::Result<()> {
+        self.rows = rows;
+        self.bytes = bytes;
+
+        match self.reporter.progress {
+            Some(progress) => self.reporter.call(&progress, self),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Counts the bytes read through it, for reporting progress through an input stream
+pub struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            count: Default::default(),
+        }
+    }
+
+    // Shared handle on the byte count that stays valid after the reader is moved
+    pub fn counter(&self) -> Rc<Cell<u64>> {
+        self.count.clone()
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.inner.read(buf)?;
+        self.count.set(self.count.get() + length as u64);
+        Ok(length)
+    }
+}
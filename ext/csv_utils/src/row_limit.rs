@@ -0,0 +1,82 @@
+use crate::targeting_key::TargetKey;
+use log::debug;
+
+// Overflowing keys listed individually in the sort! result; the rest are only counted
+const MAX_REPORTED_KEYS: usize = 100;
+
+/// A targeting key that had more rows than allowed, and how many of them were dropped
+pub struct KeyOverflow {
+    pub key: TargetKey,
+    pub dropped_rows: usize,
+}
+
+/// Applies `max_targeting_key_rows` while the sorted output is written.
+///
+/// Rows arrive grouped by targeting key, so only the current key's row count is kept.
+/// Within a key, later rows sort first, so the most recent rows are the ones kept.
+pub struct RowLimit {
+    max_rows: Option<usize>,
+    last_key: Option<TargetKey>,
+    run_length: usize,
+    pub dropped_rows: usize,
+    pub overflowed_key_count: usize,
+    pub overflowed_keys: Vec<KeyOverflow>,
+}
+
+impl RowLimit {
+    // `None` keeps every row, e.g. when rows aren't grouped by key
+    pub fn new(max_rows: Option<usize>) -> Self {
+        Self {
+            max_rows,
+            last_key: None,
+            run_length: 0,
+            dropped_rows: 0,
+            overflowed_key_count: 0,
+            overflowed_keys: Vec::new(),
+        }
+    }
+
+    /// Whether the next row, with targeting key `key`, should be kept
+    pub fn admit(&mut self, key: &TargetKey) -> bool {
+        let max_rows = match self.max_rows {
+            Some(max_rows) => max_rows,
+            None => return true,
+        };
+
+        if self.last_key.as_ref() == Some(key) {
+            self.run_length += 1;
+        } else {
+            self.last_key = Some(*key);
+            self.run_length = 1;
+        }
+
+        if self.run_length <= max_rows {
+            return true;
+        }
+
+        if self.run_length == max_rows + 1 {
+            debug!(
+                target: "csv_utils::sorter",
+                "Key {} hit max run length {}, dropping further rows",
+                key.to_hex(),
+                max_rows
+            );
+            self.overflowed_key_count += 1;
+            if self.overflowed_keys.len() < MAX_REPORTED_KEYS {
+                self.overflowed_keys.push(KeyOverflow {
+                    key: *key,
+                    dropped_rows: 0,
+                });
+            }
+        }
+
+        self.dropped_rows += 1;
+        // Keys don't repeat once passed, so a matching last entry is the current key's
+        if let Some(overflow) = self.overflowed_keys.last_mut() {
+            if overflow.key == *key {
+                overflow.dropped_rows += 1;
+            }
+        }
+        false
+    }
+}
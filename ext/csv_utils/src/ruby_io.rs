@@ -0,0 +1,69 @@
+use log::error;
+use magnus::{exception::arg_error, prelude::*, Error, RString, Value};
+use std::io::{self, Read};
+
+/// Adapts any Ruby object that responds to `read(length)` (IO, StringIO, Tempfile, ...)
+/// into a `std::io::Read`
+pub struct RubyReader {
+    io: Value,
+    // Bytes from a `read` that returned more than was asked for, not yet handed out
+    overflow: Vec<u8>,
+    overflow_start: usize,
+}
+
+impl RubyReader {
+    pub fn new(io: Value) -> Result<Self, Error> {
+        if !io.respond_to("read", false)? {
+            error!(target: "csv_utils::ruby_io", "Object does not respond to read");
+            return Err(Error::new(
+                arg_error(),
+                "Expected an IO-like object that responds to read",
+            ));
+        }
+
+        Ok(Self {
+            io,
+            overflow: Vec::new(),
+            overflow_start: 0,
+        })
+    }
+}
+
+impl Read for RubyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.overflow_start < self.overflow.len() {
+            let rest = &self.overflow[self.overflow_start..];
+            let length = rest.len().min(buf.len());
+            buf[..length].copy_from_slice(&rest[..length]);
+            self.overflow_start += length;
+            return Ok(length);
+        }
+
+        // IO#read(length) returns at most `length` bytes, or nil at EOF, though other
+        // objects may return more
+        let chunk: Option<RString> = self
+            .io
+            .funcall("read", (buf.len(),))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        match chunk {
+            Some(chunk) => {
+                // Copy out immediately; the slice is only valid until Ruby runs again
+                let bytes = unsafe { chunk.as_slice() };
+                let length = bytes.len().min(buf.len());
+                buf[..length].copy_from_slice(&bytes[..length]);
+                if bytes.len() > length {
+                    self.overflow.clear();
+                    self.overflow.extend_from_slice(&bytes[length..]);
+                    self.overflow_start = 0;
+                }
+                Ok(length)
+            }
+            None => Ok(0),
+        }
+    }
+}
@@ -0,0 +1,8 @@
+use magnus::{Error, RHash, Symbol, Value};
+
+// Read a string-ish option, accepting either a String or a Symbol
+pub fn option_string(options: RHash, key: &str) -> Result<Option<String>, Error> {
+    Ok(options
+        .lookup::<_, Option<Value>>(Symbol::new(key))?
+        .map(|value| value.to_string()))
+}
@@ -0,0 +1,265 @@
+use crate::sorter::{KeyData, RunKey};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::trace;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+// Uncompressed bytes collected before a block is compressed and written out
+const BLOCK_SIZE: usize = 256 * 1024;
+
+// zstd level for run files; they are short-lived, so favor speed over ratio
+const ZSTD_LEVEL: i32 = 1;
+
+/// Compression applied to run files and the sorted output file.
+///
+/// Compressed files are a sequence of independently compressed blocks, each laid out as
+/// `[u32 raw length][u32 compressed length][compressed bytes]`. Blocks always end on an
+/// entry boundary. Uncompressed files hold the entries back to back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl RunCompression {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "" | "none" => Some(RunCompression::None),
+            "lz4" => Some(RunCompression::Lz4),
+            "zstd" => Some(RunCompression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RunCompression::None => "none",
+            RunCompression::Lz4 => "lz4",
+            RunCompression::Zstd => "zstd",
+        }
+    }
+
+    fn compress(self, raw: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            RunCompression::None => Ok(raw.to_vec()),
+            RunCompression::Lz4 => Ok(lz4_flex::block::compress(raw)),
+            RunCompression::Zstd => zstd::bulk::compress(raw, ZSTD_LEVEL),
+        }
+    }
+
+    fn decompress(self, compressed: &[u8], raw_length: usize) -> io::Result<Vec<u8>> {
+        match self {
+            RunCompression::None => Ok(compressed.to_vec()),
+            RunCompression::Lz4 => lz4_flex::block::decompress(compressed, raw_length)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            RunCompression::Zstd => zstd::bulk::decompress(compressed, raw_length),
+        }
+    }
+}
+
+/// Writes length-prefixed entries, optionally preceded by their key, to a run or output file.
+///
+/// Run files hold `[key][u32 record length][record bytes]` entries, the sorted output file
+/// holds `[u32 record length][record bytes]` entries. A key is followed by its sort prefix
+/// when sorting by value.
+pub struct RunWriter<W: Write> {
+    writer: BufWriter<W>,
+    compression: RunCompression,
+    block: Vec<u8>,
+    // Bytes handed to the underlying writer so far
+    bytes_written: u64,
+}
+
+impl<W: Write> RunWriter<W> {
+    pub fn new(inner: W, compression: RunCompression, capacity: usize) -> Self {
+        Self {
+            writer: BufWriter::with_capacity(capacity, inner),
+            compression,
+            block: match compression {
+                RunCompression::None => Vec::new(),
+                _ => Vec::with_capacity(BLOCK_SIZE),
+            },
+            bytes_written: 0,
+        }
+    }
+
+    /// Offset from the start of the writer at which a reader can pick up the next entry.
+    ///
+    /// Uncompressed entries can be read from any entry boundary, compressed ones only
+    /// from the start of a block, so this is `None` partway through a block.
+    pub fn entry_offset(&self) -> Option<u64> {
+        match self.compression {
+            RunCompression::None => Some(self.bytes_written),
+            _ if self.block.is_empty() => Some(self.bytes_written),
+            _ => None,
+        }
+    }
+
+    pub fn write_entry(&mut self, key: Option<&RunKey>, record_bytes: &[u8]) -> io::Result<()> {
+        match self.compression {
+            RunCompression::None => {
+                self.bytes_written +=
+                    Self::encode_entry(&mut self.writer, key, record_bytes)? as u64;
+            }
+            _ => {
+                Self::encode_entry(&mut self.block, key, record_bytes)?;
+                if self.block.len() >= BLOCK_SIZE {
+                    self.flush_block()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the number of bytes written
+    fn encode_entry<T: Write>(
+        out: &mut T,
+        key: Option<&RunKey>,
+        record_bytes: &[u8],
+    ) -> io::Result<usize> {
+        let mut length = 4 + record_bytes.len();
+        if let Some(key) = key {
+            length += bincode::encode_into_std_write(&key.key, out, bincode::config::legacy())
+                .map_err(encode_error)?;
+            if let Some(sort_prefix) = &key.sort_prefix {
+                length +=
+                    bincode::encode_into_std_write(sort_prefix, out, bincode::config::legacy())
+                        .map_err(encode_error)?;
+            }
+        }
+        out.write_all(&(record_bytes.len() as u32).to_le_bytes())?;
+        out.write_all(record_bytes)?;
+        Ok(length)
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let compressed = self.compression.compress(&self.block)?;
+        trace!(
+            target: "csv_utils::run_file",
+            "Writing block: {} bytes compressed to {}",
+            self.block.len(),
+            compressed.len()
+        );
+
+        self.writer
+            .write_u32::<LittleEndian>(self.block.len() as u32)?;
+        self.writer
+            .write_u32::<LittleEndian>(compressed.len() as u32)?;
+        self.writer.write_all(&compressed)?;
+        self.bytes_written += 8 + compressed.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    // Flush any partial block and buffered bytes, handing back the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_block()?;
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+// Keep the original error when encoding fails writing to `out`, so that e.g. running
+// out of disk space isn't reported as an encoding error
+fn encode_error(e: bincode::error::EncodeError) -> io::Error {
+    match e {
+        bincode::error::EncodeError::Io { inner, .. } => inner,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+/// Reads entries written by `RunWriter`, decompressing blocks as needed
+pub struct RunReader<R: Read> {
+    reader: BufReader<R>,
+    compression: RunCompression,
+    block: Vec<u8>,
+    block_position: usize,
+}
+
+impl<R: Read> RunReader<R> {
+    pub fn new(inner: R, compression: RunCompression, capacity: usize) -> Self {
+        Self {
+            reader: BufReader::with_capacity(capacity, inner),
+            compression,
+            block: Vec::new(),
+            block_position: 0,
+        }
+    }
+
+    // Whether every entry has been consumed
+    fn at_eof(&mut self) -> io::Result<bool> {
+        match self.compression {
+            RunCompression::None => Ok(self.reader.fill_buf()?.is_empty()),
+            _ => Ok(self.block_position >= self.block.len() && !self.load_block()?),
+        }
+    }
+
+    fn load_block(&mut self) -> io::Result<bool> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+
+        let raw_length = self.reader.read_u32::<LittleEndian>()? as usize;
+        let compressed_length = self.reader.read_u32::<LittleEndian>()? as usize;
+        let mut compressed = vec![0u8; compressed_length];
+        self.reader.read_exact(&mut compressed)?;
+
+        self.block = self.compression.decompress(&compressed, raw_length)?;
+        self.block_position = 0;
+        Ok(true)
+    }
+
+    /// Read the key preceding the next run file entry, or `None` at the end of the file.
+    /// `sorted_by_value` says whether the run was written with sort prefixes.
+    pub fn read_key(&mut self, sorted_by_value: bool) -> io::Result<Option<RunKey>> {
+        if self.at_eof()? {
+            return Ok(None);
+        }
+
+        let key: KeyData = bincode::decode_from_std_read(self, bincode::config::legacy())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let sort_prefix = if sorted_by_value {
+            Some(
+                bincode::decode_from_std_read(self, bincode::config::legacy())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            )
+        } else {
+            None
+        };
+        Ok(Some(RunKey { sort_prefix, key }))
+    }
+
+    /// Read the next record's bytes into `buf`, returning false at the end of the file
+    pub fn read_record(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        if self.at_eof()? {
+            return Ok(false);
+        }
+
+        let length = self.read_u32::<LittleEndian>()? as usize;
+        buf.resize(length, 0);
+        self.read_exact(buf)?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for RunReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.compression == RunCompression::None {
+            return self.reader.read(buf);
+        }
+
+        if self.block_position >= self.block.len() && !self.load_block()? {
+            return Ok(0);
+        }
+
+        let available = &self.block[self.block_position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.block_position += length;
+        Ok(length)
+    }
+}
@@ -0,0 +1,218 @@
+use crate::ruby_options::option_string;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use log::{debug, error};
+use magnus::{exception::arg_error, Error, RArray, RHash, Symbol};
+use serde::{Deserialize, Serialize};
+
+// Leading byte of each encoded value. Nulls (missing columns, and numbers or dates
+// that are empty or don't parse) sort after every value when ascending and, since
+// descending columns invert their bytes, before every value when descending, like
+// PostgreSQL's defaults.
+const VALUE_TAG: u8 = 0x01;
+const NULL_TAG: u8 = 0x02;
+
+// Formats tried, in order, for date columns without an explicit format
+const DATETIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// How a sort column's values are compared
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortType {
+    // Byte-wise, i.e. by Unicode code point
+    String,
+    // As floating point numbers
+    Numeric,
+    // As dates or date-times, with the time zone applied when one is given
+    Date,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SortColumn {
+    column: usize,
+    sort_type: SortType,
+    descending: bool,
+    // strftime-style format for date columns
+    format: Option<String>,
+}
+
+/// Ordering by column values rather than by targeting key, given to `Sorter.new` as
+/// `sort_by: [{ column: 2, type: :numeric, order: :desc }, { column: 0 }]`.
+///
+/// Each row's sort columns are encoded into a byte string that compares the same way
+/// as the values themselves, so runs and merges only ever compare bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SortSpec {
+    columns: Vec<SortColumn>,
+}
+
+impl SortSpec {
+    pub fn from_ruby(spec: RArray) -> Result<Self, Error> {
+        let mut columns = Vec::with_capacity(spec.len());
+
+        for entry in spec.to_vec::<RHash>()? {
+            let column = entry
+                .lookup::<_, Option<usize>>(Symbol::new("column"))?
+                .ok_or_else(|| invalid("sort_by entries need a column index".to_string()))?;
+
+            let sort_type = match option_string(entry, "type")?.as_deref() {
+                None | Some("string") => SortType::String,
+                Some("numeric") => SortType::Numeric,
+                Some("date") => SortType::Date,
+                Some(other) => return Err(invalid(format!("Invalid sort type: {}", other))),
+            };
+
+            let descending = match option_string(entry, "order")?.as_deref() {
+                None | Some("asc") => false,
+                Some("desc") => true,
+                Some(other) => return Err(invalid(format!("Invalid sort order: {}", other))),
+            };
+
+            let format = option_string(entry, "format")?;
+            if format.is_some() && sort_type != SortType::Date {
+                return Err(invalid(format!(
+                    "format is only supported for date columns (column {})",
+                    column
+                )));
+            }
+
+            columns.push(SortColumn {
+                column,
+                sort_type,
+                descending,
+                format,
+            });
+        }
+
+        if columns.is_empty() {
+            return Err(invalid("sort_by needs at least one column".to_string()));
+        }
+
+        debug!(target: "csv_utils::sort_spec", "Using sort spec: {:?}", columns);
+
+        Ok(Self { columns })
+    }
+
+    /// Encode the row's sort columns into a byte string that orders like the row
+    pub fn encode(&self, row: &[String]) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        for column in self.columns.iter() {
+            let start = buf.len();
+            let value = row.get(column.column).map(|v| v.as_str());
+
+            match column.sort_type {
+                SortType::String => encode_string(&mut buf, value),
+                SortType::Numeric => {
+                    encode_ordered(&mut buf, value.and_then(parse_number).map(f64_key))
+                }
+                SortType::Date => encode_ordered(
+                    &mut buf,
+                    value
+                        .and_then(|v| parse_timestamp(v, column.format.as_deref()))
+                        .map(i64_key),
+                ),
+            }
+
+            if column.descending {
+                for byte in buf[start..].iter_mut() {
+                    *byte = !*byte;
+                }
+            }
+        }
+
+        buf
+    }
+}
+
+fn invalid(message: String) -> Error {
+    error!(target: "csv_utils::sort_spec", "{}", message);
+    Error::new(arg_error(), message)
+}
+
+// Strings are escaped so that no encoding is a prefix of another: 0x00 becomes
+// 0x00 0xFF and the value ends with 0x00 0x00
+fn encode_string(buf: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            buf.push(VALUE_TAG);
+            for &byte in value.as_bytes() {
+                buf.push(byte);
+                if byte == 0 {
+                    buf.push(0xFF);
+                }
+            }
+            buf.extend_from_slice(&[0, 0]);
+        }
+        None => buf.push(NULL_TAG),
+    }
+}
+
+fn encode_ordered(buf: &mut Vec<u8>, key: Option<[u8; 8]>) {
+    match key {
+        Some(key) => {
+            buf.push(VALUE_TAG);
+            buf.extend_from_slice(&key);
+        }
+        None => buf.push(NULL_TAG),
+    }
+}
+
+fn parse_number(value: &str) -> Option<f64> {
+    match value.trim().parse::<f64>() {
+        Ok(number) if !number.is_nan() => Some(number),
+        _ => None,
+    }
+}
+
+// Big-endian bytes that compare like the floats: negative numbers have every bit
+// flipped, positive ones only the sign bit
+fn f64_key(number: f64) -> [u8; 8] {
+    // -0.0 and 0.0 are equal
+    let bits = (number + 0.0).to_bits();
+    let ordered = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    };
+    ordered.to_be_bytes()
+}
+
+fn i64_key(value: i64) -> [u8; 8] {
+    ((value as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+// Microseconds since the epoch. Values without a time zone are taken as UTC.
+pub fn parse_timestamp(value: &str, format: Option<&str>) -> Option<i64> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    if let Some(format) = format {
+        // Formats with %z carry an offset, which is lost if parsed as naive
+        if let Ok(datetime) = DateTime::parse_from_str(value, format) {
+            return Some(datetime.timestamp_micros());
+        }
+        return NaiveDateTime::parse_from_str(value, format)
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(value, format)
+                    .ok()
+                    .and_then(|date| date.and_hms_opt(0, 0, 0))
+            })
+            .map(|datetime| datetime.and_utc().timestamp_micros());
+    }
+
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.timestamp_micros());
+    }
+    for format in DATETIME_FORMATS {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(datetime.and_utc().timestamp_micros());
+        }
+    }
+    NaiveDate::parse_from_str(value, DATE_FORMAT)
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| datetime.and_utc().timestamp_micros())
+}
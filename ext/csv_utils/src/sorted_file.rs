@@ -0,0 +1,505 @@
+use crate::copy_schema::CopySchema;
+use crate::diff::{diff_with, SortedSource};
+use crate::errors::{phase_error, sorted_file_error};
+use crate::file_section::FileSection;
+use crate::gvl::{without_gvl, CancelToken};
+use crate::key_index::KeyIndex;
+use crate::postgres_copier::{GeoIndexes, PostgresCopier};
+use crate::progress::{Progress, ProgressReporter};
+use crate::run_file::{RunCompression, RunReader};
+use crate::sorter::{
+    check_key_values, copy_result, group_max_rows, parse_hex_key, yield_batches, yield_groups,
+};
+use crate::targeting_key::{key_for_values, HashAlgorithm, KeyEncoding, TargetKey};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{debug, error, info};
+use magnus::{
+    function, gc, method, prelude::*, scan_args::scan_args, DataTypeFunctions, Error, RArray,
+    RHash, RModule, Ruby, Symbol, Value,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+// Identifies a file written by Sorter#save
+const MAGIC: &[u8; 8] = b"CSVUSORT";
+// Bumped whenever the layout changes incompatibly
+const FORMAT_VERSION: u32 = 1;
+const BUFFER_CAPACITY: usize = 4 * 1024 * 1024;
+// Spare room in the header for the counts filled in once the output is written: enough
+// for the three of them to grow from one digit to twenty
+const HEADER_RESERVE: usize = 64;
+// Far more than any header needs; a longer one means the file is corrupt
+const MAX_HEADER_BYTES: u32 = 1024 * 1024;
+
+/// What a sorted file holds and how it was built, stored as JSON at the start of the file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SortedFileHeader {
+    pub source_id: String,
+    pub source_key: String,
+    pub key_columns: Vec<usize>,
+    pub geo_columns: Option<GeoIndexes>,
+    pub hash_algorithm: String,
+    pub key_encoding: String,
+    pub compression: String,
+    // Rows are ordered by sort_by rather than grouped by targeting key
+    pub sorted_by_value: bool,
+    pub row_count: u64,
+    // Encoded record bytes, for progress totals
+    pub record_bytes: u64,
+    // Length of the sorted output that follows the header, set when the file is finished
+    pub data_length: u64,
+}
+
+/// Writes a sorted file: `[magic][u32 format version][u32 header length][header JSON]`,
+/// then the sorted output exactly as the sorter writes it, then the bincode key index.
+///
+/// The header is written up front with room to spare, and rewritten by `finish` once the
+/// row count and data length are known, so sorted output can be streamed straight in.
+pub struct SortedFileWriter {
+    file: File,
+    header_capacity: usize,
+}
+
+impl SortedFileWriter {
+    pub fn create(path: &Path, header: &SortedFileHeader) -> io::Result<Self> {
+        let header_json = encode_header(header)?;
+        let header_capacity = header_json.len() + HEADER_RESERVE;
+
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        file.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+        file.write_u32::<LittleEndian>(header_capacity as u32)?;
+        file.write_all(&padded(header_json, header_capacity))?;
+
+        Ok(Self {
+            file,
+            header_capacity,
+        })
+    }
+
+    /// The file, positioned where the sorted output goes
+    pub fn data(&self) -> &File {
+        &self.file
+    }
+
+    fn data_start(&self) -> u64 {
+        (MAGIC.len() + 8 + self.header_capacity) as u64
+    }
+
+    /// Append the key index after the sorted output written so far, and fill in the
+    /// header's data length
+    pub fn finish(mut self, mut header: SortedFileHeader, index: &KeyIndex) -> io::Result<()> {
+        header.data_length = self.file.stream_position()? - self.data_start();
+
+        let mut writer = BufWriter::new(&self.file);
+        bincode::encode_into_std_write(index, &mut writer, bincode::config::legacy())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        writer.flush()?;
+        drop(writer);
+
+        let header_json = encode_header(&header)?;
+        if header_json.len() > self.header_capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Sorted file header outgrew its reserved space",
+            ));
+        }
+        self.file.seek(SeekFrom::Start((MAGIC.len() + 8) as u64))?;
+        self.file
+            .write_all(&padded(header_json, self.header_capacity))?;
+        self.file.sync_all()
+    }
+}
+
+fn encode_header(header: &SortedFileHeader) -> io::Result<Vec<u8>> {
+    serde_json::to_vec(header).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+// JSON allows trailing whitespace, so the header is padded out with spaces
+fn padded(mut header_json: Vec<u8>, capacity: usize) -> Vec<u8> {
+    header_json.resize(capacity, b' ');
+    header_json
+}
+
+/// Save sorted output and its key index to `path`
+pub fn write(
+    path: &Path,
+    header: &SortedFileHeader,
+    data: &File,
+    index: &KeyIndex,
+) -> io::Result<()> {
+    let writer = SortedFileWriter::create(path, header)?;
+
+    let mut data = data;
+    data.rewind()?;
+    let mut output = BufWriter::with_capacity(BUFFER_CAPACITY, writer.data());
+    io::copy(&mut data, &mut output)?;
+    output.flush()?;
+    drop(output);
+
+    writer.finish(header.clone(), index)
+}
+
+/// Sorted output saved by `Sorter#save`, reopened without re-sorting
+#[magnus::wrap(class = "CsvUtils::SortedFile", mark)]
+pub struct SortedFile {
+    file: File,
+    header: SortedFileHeader,
+    // Where the sorted output sits in the file
+    data: Range<u64>,
+    index: KeyIndex,
+    hash_algorithm: HashAlgorithm,
+    key_encoding: KeyEncoding,
+    compression: RunCompression,
+    cancel: CancelToken,
+    // Block registered with on_progress, marked so the GC keeps it
+    progress: Cell<Option<Progress>>,
+}
+
+impl DataTypeFunctions for SortedFile {
+    fn mark(&self, marker: &gc::Marker) {
+        if let Some(progress) = self.progress.get() {
+            progress.mark(marker);
+        }
+    }
+}
+
+impl SortedFile {
+    pub fn open(path: String) -> Result<Self, Error> {
+        info!(target: "csv_utils::sorted_file", "Opening sorted file {}", path);
+
+        let file = File::open(&path).map_err(|e| {
+            Error::new(
+                magnus::exception::runtime_error(),
+                format!("Failed to open sorted file {}: {}", path, e),
+            )
+        })?;
+        let (header, data, index) = Self::read_layout(&file).map_err(|e| {
+            error!(target: "csv_utils::sorted_file", "Invalid sorted file {}: {}", path, e);
+            Error::new(
+                sorted_file_error(),
+                format!("Invalid sorted file {}: {}", path, e),
+            )
+        })?;
+
+        let invalid = |field: &str, value: &str| {
+            Error::new(
+                sorted_file_error(),
+                format!("Unsupported {} in sorted file {}: {}", field, path, value),
+            )
+        };
+        let hash_algorithm = HashAlgorithm::from_name(&header.hash_algorithm)
+            .ok_or_else(|| invalid("hash algorithm", &header.hash_algorithm))?;
+        let key_encoding = KeyEncoding::from_name(&header.key_encoding)
+            .ok_or_else(|| invalid("key encoding", &header.key_encoding))?;
+        let compression = RunCompression::from_name(&header.compression)
+            .ok_or_else(|| invalid("compression", &header.compression))?;
+
+        debug!(
+            target: "csv_utils::sorted_file",
+            "Opened sorted file with header {:?}",
+            header
+        );
+
+        Ok(Self {
+            file,
+            header,
+            data,
+            index,
+            hash_algorithm,
+            key_encoding,
+            compression,
+            cancel: CancelToken::default(),
+            progress: Cell::new(None),
+        })
+    }
+
+    fn read_layout(file: &File) -> io::Result<(SortedFileHeader, Range<u64>, KeyIndex)> {
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not written by Sorter#save",
+            ));
+        }
+
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported format version {}", version),
+            ));
+        }
+
+        let header_length = reader.read_u32::<LittleEndian>()?;
+        if header_length > MAX_HEADER_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("header length {} is too large", header_length),
+            ));
+        }
+        let mut header_json = vec![0u8; header_length as usize];
+        reader.read_exact(&mut header_json)?;
+        let header: SortedFileHeader = serde_json::from_slice(&header_json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let data_start = (MAGIC.len() + 8) as u64 + header_length as u64;
+        let data = data_start..data_start + header.data_length;
+
+        reader.seek(SeekFrom::Start(data.end))?;
+        let index = bincode::decode_from_std_read(&mut reader, bincode::config::legacy())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok((header, data, index))
+    }
+
+    // Reader over just the sorted output, independent of any other reader of the file
+    pub fn data_reader(&self) -> Result<FileSection<File>, Error> {
+        let file = self.file.try_clone().map_err(|e| {
+            Error::new(
+                magnus::exception::runtime_error(),
+                format!("Failed to clone sorted file: {}", e),
+            )
+        })?;
+        Ok(FileSection::new(file, self.data.clone()))
+    }
+
+    pub fn compression(&self) -> RunCompression {
+        self.compression
+    }
+
+    /// Check that rows with the same key values get the same targeting key here as in
+    /// the sorted output `header` describes, so the two can be merged
+    pub fn check_compatible(&self, header: &SortedFileHeader) -> Result<(), Error> {
+        check_compatible(&self.header, header)
+    }
+
+    // This file's side of a diff, read from the start of the sorted output
+    pub fn sorted_source(&self) -> Result<SortedSource, Error> {
+        Ok(SortedSource {
+            header: self.header.clone(),
+            data: Box::new(self.data_reader()?),
+            compression: self.compression,
+        })
+    }
+
+    fn check_grouped(&self, method: &str) -> Result<(), Error> {
+        if self.header.sorted_by_value {
+            return Err(Error::new(
+                magnus::exception::runtime_error(),
+                format!("{} needs rows sorted by targeting key, not sort_by", method),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn header(&self) -> Result<RHash, Error> {
+        let header = &self.header;
+        let hash = RHash::new();
+        hash.aset(Symbol::new("format_version"), FORMAT_VERSION)?;
+        hash.aset(Symbol::new("source_id"), header.source_id.as_str())?;
+        hash.aset(Symbol::new("source_key"), header.source_key.as_str())?;
+        hash.aset(
+            Symbol::new("key_columns"),
+            RArray::from_vec(header.key_columns.clone()),
+        )?;
+        hash.aset(
+            Symbol::new("geo_columns"),
+            header
+                .geo_columns
+                .map(|(latitude, longitude)| RArray::from_vec(vec![latitude, longitude])),
+        )?;
+        hash.aset(Symbol::new("hash"), Symbol::new(&header.hash_algorithm))?;
+        hash.aset(
+            Symbol::new("key_encoding"),
+            Symbol::new(&header.key_encoding),
+        )?;
+        hash.aset(Symbol::new("compression"), Symbol::new(&header.compression))?;
+        hash.aset(Symbol::new("sorted_by_value"), header.sorted_by_value)?;
+        hash.aset(Symbol::new("row_count"), header.row_count)?;
+        Ok(hash)
+    }
+
+    pub fn row_count(&self) -> u64 {
+        self.header.row_count
+    }
+
+    pub fn each_batch(&self, batch_size: usize) -> Result<(), Error> {
+        let ruby = Ruby::get().unwrap();
+        let block = ruby.block_proc()?;
+
+        let mut reader = RunReader::new(self.data_reader()?, self.compression, BUFFER_CAPACITY);
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "each_batch",
+            Some(self.header.row_count),
+            Some(self.header.record_bytes),
+        );
+        yield_batches(&mut reader, batch_size, block, &reporter, &mut tracker)
+    }
+
+    pub fn each_group(&self, args: &[Value]) -> Result<(), Error> {
+        let max_rows = group_max_rows(args)?;
+        self.check_grouped("each_group")?;
+
+        let ruby = Ruby::get().unwrap();
+        let block = ruby.block_proc()?;
+
+        let mut reader = RunReader::new(self.data_reader()?, self.compression, BUFFER_CAPACITY);
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "each_group",
+            Some(self.header.row_count),
+            Some(self.header.record_bytes),
+        );
+        yield_groups(&mut reader, max_rows, block, &reporter, &mut tracker)
+    }
+
+    pub fn lookup(&self, key_values: Vec<String>) -> Result<RArray, Error> {
+        check_key_values(&key_values, self.header.key_columns.len())?;
+        let key = key_for_values(
+            self.hash_algorithm,
+            self.key_encoding,
+            &self.header.source_id,
+            &key_values,
+        );
+        self.lookup_key(key)
+    }
+
+    pub fn lookup_hex(&self, hex: String) -> Result<RArray, Error> {
+        let key = parse_hex_key(&hex, self.hash_algorithm)?;
+        self.lookup_key(key)
+    }
+
+    fn lookup_key(&self, key: TargetKey) -> Result<RArray, Error> {
+        self.check_grouped("lookup")?;
+
+        let rows = self
+            .index
+            .lookup(&self.file, self.data.clone(), self.compression, &key)
+            .map_err(|e| {
+                Error::new(
+                    magnus::exception::runtime_error(),
+                    format!("Error reading sorted file: {}", e),
+                )
+            })?;
+
+        Ok(RArray::from_vec(rows))
+    }
+
+    // Write the sorted rows in PostgreSQL's binary COPY format, with the columns given by
+    // an optional `columns:` option, see `CopySchema::from_options`
+    pub fn write_binary_postgres_file(&self, args: &[Value]) -> Result<RHash, Error> {
+        let args = scan_args::<(String,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (file_path,) = args.required;
+        let (options,) = args.optional;
+        let schema = CopySchema::from_options(options)?;
+        info!(
+            target: "csv_utils::sorted_file",
+            "Writing binary PostgreSQL file to {}",
+            file_path
+        );
+
+        let mut copier = PostgresCopier::new(
+            self.data_reader()?,
+            self.compression,
+            self.header.geo_columns,
+            self.header.source_key.clone(),
+            schema,
+        )
+        .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "write_binary_postgres_file",
+            Some(self.header.row_count),
+            Some(self.header.record_bytes),
+        );
+
+        let stats = reporter.result(
+            without_gvl(&self.cancel, || {
+                copier.copy(Path::new(&file_path), &self.cancel, &mut tracker, None)
+            })
+            .and_then(|result| result.map_err(|e| phase_error("Error writing PostgreSQL file", e))),
+        )?;
+
+        copy_result(&stats, None)
+    }
+
+    // Compare with earlier sorted output (a `SortedFile` or sorted `Sorter`), see
+    // `diff::diff_with`
+    pub fn diff(&self, args: &[Value]) -> Result<RHash, Error> {
+        diff_with(self.sorted_source()?, args, &self.cancel)
+    }
+
+    // Stop a running write_binary_postgres_file or diff; callable from any thread
+    pub fn cancel(&self) {
+        info!(target: "csv_utils::sorted_file", "Cancellation requested");
+        self.cancel.cancel();
+    }
+
+    // Register a block to receive progress reports, as for `Sorter#on_progress`
+    pub fn on_progress(&self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (options,) = args.optional;
+        let ruby = Ruby::get().unwrap();
+        let block = ruby.block_proc()?;
+
+        self.progress.set(Some(Progress::new(block, options)?));
+        info!(target: "csv_utils::sorted_file", "Progress reporting enabled");
+        Ok(())
+    }
+}
+
+/// Check that two sorted outputs give rows with the same key values the same targeting
+/// key and are both in targeting key order, so they can be merged or compared
+pub fn check_compatible(a: &SortedFileHeader, b: &SortedFileHeader) -> Result<(), Error> {
+    let mismatch = if a.source_id != b.source_id {
+        Some("source id")
+    } else if a.key_columns != b.key_columns {
+        Some("key columns")
+    } else if a.hash_algorithm != b.hash_algorithm {
+        Some("hash")
+    } else if a.key_encoding != b.key_encoding {
+        Some("key encoding")
+    } else if a.sorted_by_value || b.sorted_by_value {
+        Some("sort order (sort_by)")
+    } else {
+        None
+    };
+
+    match mismatch {
+        Some(field) => Err(Error::new(
+            sorted_file_error(),
+            format!("Sorted file has a different {}", field),
+        )),
+        None => Ok(()),
+    }
+}
+
+pub fn register(ruby: &Ruby, module: &RModule) -> Result<(), Error> {
+    let class = module.define_class("SortedFile", ruby.class_object())?;
+    class.define_singleton_method("open", function!(SortedFile::open, 1))?;
+    class.define_method("header", method!(SortedFile::header, 0))?;
+    class.define_method("row_count", method!(SortedFile::row_count, 0))?;
+    class.define_method("each_batch", method!(SortedFile::each_batch, 1))?;
+    class.define_method("each_group", method!(SortedFile::each_group, -1))?;
+    class.define_method("lookup", method!(SortedFile::lookup, 1))?;
+    class.define_method("lookup_hex", method!(SortedFile::lookup_hex, 1))?;
+    class.define_method(
+        "write_binary_postgres_file",
+        method!(SortedFile::write_binary_postgres_file, -1),
+    )?;
+    class.define_method("diff", method!(SortedFile::diff, -1))?;
+    class.define_method("cancel!", method!(SortedFile::cancel, 0))?;
+    class.define_method("on_progress", method!(SortedFile::on_progress, -1))?;
+
+    Ok(())
+}
@@ -1,26 +1,72 @@
-use crate::postgres_copier::{GeoIndexes, PostgresCopier};
+use crate::copy_schema::CopySchema;
+use crate::csv_options::CsvOptions;
+use crate::decompress::decompressing_reader;
+use crate::dedup::{DedupStrategy, Deduplicator};
+use crate::diff::{diff_with, SortedSource};
+use crate::errors::{missing_key_column_error, phase_error, sorter_busy_error};
+use crate::file_section::FileSection;
+use crate::gvl::{without_gvl, CancelToken};
+use crate::key_index::KeyIndex;
+use crate::postgres_copier::{CopyStats, GeoIndexes, PostgresCopier};
+use crate::postgres_loader::{copy_to_postgres, load_into_postgres, LoadOptions, StagingOptions};
+use crate::progress::{CountingReader, Progress, ProgressReporter, ProgressTracker};
+use crate::row_limit::RowLimit;
+use crate::ruby_io::RubyReader;
+use crate::ruby_options::option_string;
+use crate::run_file::{RunCompression, RunReader, RunWriter};
+use crate::sort_spec::SortSpec;
+use crate::sorted_file::{self, SortedFile, SortedFileHeader, SortedFileWriter};
+use crate::sorter_options::SorterOptions;
+use crate::spill::{SpillCharge, SpillDirs, SpillUsage, SpillWriter};
+use crate::targeting_key::{
+    key_for_values, HashAlgorithm, KeyEncoding, MissingKeyColumns, TargetKey,
+};
+use crate::upsert::{upsert, ReplaceBy, UpsertOutput};
 use crate::validator::{ruby_rules_array_to_rules, Validator};
+use crate::work_dir::{RunEntry, WorkDir, WorkSettings};
 use bincode::{Decode, Encode};
-use faster_hex::hex_string;
 use log::{debug, error, info, trace, warn};
-use magnus::{function, method, prelude::*, Error, RArray, RHash, RModule, Ruby, Symbol, Value};
+use magnus::{
+    function, gc, method, prelude::*, scan_args::scan_args, DataTypeFunctions, Error, Proc, RArray,
+    RHash, RModule, Ruby, Symbol, Value,
+};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
 use std::{
-    cell::RefCell,
+    cell::{Cell, Ref, RefCell, RefMut},
+    cmp::Reverse,
     collections::BinaryHeap,
     fs::File,
-    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    io::{self, BufReader, Read, Seek},
     path::Path,
+    rc::Rc,
+    sync::Arc,
+    thread::{self, JoinHandle},
 };
-use tempfile::tempfile;
 
 const BUFFER_CAPACITY: usize = 4 * 1024 * 1024;
-const DEFAULT_MAX_TARGETING_KEY_ROWS: usize = 200;
+// Smallest read buffer given to each run file during a merge
+const MIN_MERGE_BUFFER_CAPACITY: usize = 64 * 1024;
+// Records processed between checks for cancellation
+const CANCEL_CHECK_INTERVAL: usize = 10_000;
+const IO_BUFFER_CAPACITY: usize = 64 * 1024;
 
-#[magnus::wrap(class = "CsvUtils::Sorter")]
+#[magnus::wrap(class = "CsvUtils::Sorter", mark)]
 pub struct Sorter {
     inner: RefCell<SorterInner>,
+    // Lives outside the RefCell so another Ruby thread can cancel a running sort
+    cancel: CancelToken,
+    // Block registered with on_progress. Also outside the RefCell, so it can still be
+    // marked while a sort holds the inner state
+    progress: Cell<Option<Progress>>,
+}
+
+impl DataTypeFunctions for Sorter {
+    fn mark(&self, marker: &gc::Marker) {
+        if let Some(progress) = self.progress.get() {
+            progress.mark(marker);
+        }
+    }
 }
 
 // Inner state that can be mutated through RefCell
@@ -28,21 +74,48 @@ struct SorterInner {
     source_id: String,
     source_key: String,
     key_columns: Vec<usize>,
+    sort_spec: Option<SortSpec>,
+    hash_algorithm: HashAlgorithm,
+    key_encoding: KeyEncoding,
+    missing_key_columns: MissingKeyColumns,
+    // Reused to assemble the key columns before hashing
+    key_buf: Vec<u8>,
     geo_columns: Option<GeoIndexes>,
-    current_batch: Vec<SortRecord>,
+    current_batch: Vec<BufferedRecord>,
     buffer_size_bytes: usize,
-    temp_files: Vec<File>,
+    temp_files: Vec<Run>,
     current_buffer_size: usize,
+    // Compression and sorting settings for run files and the output file
+    run_config: RunConfig,
+    // Write runs on a background thread while ingestion continues
+    background_spill: bool,
+    pending_run: Option<JoinHandle<io::Result<Run>>>,
+    // Named directory for run files and the manifest used to resume, if configured
+    work_dir: Option<WorkDir>,
+    // Where other run files and the output file are created
+    spill_dirs: SpillDirs,
+    // Maximum number of run files merged at once
+    merge_fan_in: usize,
     // Store the actual output file directly
     output_file: File,
+    // Sparse index over the output file, for lookups by targeting key
+    key_index: KeyIndex,
     total_rows: usize,
     observed_max_row_size: usize,
+    // Rows and record bytes written to run files so far, and record bytes in the output
+    spilled_rows: usize,
+    spilled_bytes: usize,
+    output_bytes: usize,
 
-    // Maximum number of allowed rows for a given targeting key
+    // Maximum number of allowed rows for a given targeting key, applied when the sorted
+    // output is written
     max_targeting_key_rows: usize,
+    // How rows sharing a targeting key are collapsed, applied before the row cap
+    dedup: DedupStrategy,
 
     validator: Option<Validator>,
     buf: Vec<u8>,
+    cancel: CancelToken,
 }
 
 // Serializable record for run files
@@ -67,13 +140,13 @@ impl PartialOrd for SortRecord {
 
 #[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct KeyData {
-    pub value: [u8; 20],
+    pub value: TargetKey,
     pub position: usize,
 }
 
 impl Ord for KeyData {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // Compare: hash, value, reverse position
+        // Compare: hash, reverse position
         (&self.value, std::cmp::Reverse(self.position))
             .cmp(&(&other.value, std::cmp::Reverse(other.position)))
     }
@@ -85,107 +158,309 @@ impl PartialOrd for KeyData {
     }
 }
 
+// What run file entries are ordered by. The sort prefix is written next to the key
+// in run files (see RunWriter) rather than in the record, so the sorted output
+// doesn't store it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RunKey {
+    // Encoded sort columns when sorting by value (see SortSpec), otherwise None
+    pub sort_prefix: Option<Vec<u8>>,
+    pub key: KeyData,
+}
+
+// A row in the sort buffer, ordered by its sort prefix and then its key
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct BufferedRecord {
+    sort_prefix: Option<Vec<u8>>,
+    record: SortRecord,
+}
+
+// Counts reported back to Ruby from `sort!`
+struct SortSummary {
+    total_rows: usize,
+    file_count: usize,
+    // Rows dropped for exceeding max_targeting_key_rows
+    limit: RowLimit,
+    // Duplicate rows folded into another row by the dedup strategy
+    collapsed_rows: usize,
+}
+
 impl SorterInner {
-    // Generate a composite key from source_id + row values using SHA1, joined by commas
-    fn generate_targeting_key(&self, row: &[String]) -> [u8; 20] {
-        let mut hasher = Sha1::new();
-        hasher.update(self.source_id.as_bytes());
+    // Generate a composite key from source_id + row values, encoded and hashed as
+    // configured. Fails with the index of the first missing key column when the missing
+    // column policy doesn't allow building a key without it.
+    fn generate_targeting_key(&mut self, row: &[String]) -> Result<TargetKey, usize> {
+        self.key_buf.clear();
+        self.key_encoding
+            .push_source_id(&mut self.key_buf, &self.source_id);
 
         for &col in self.key_columns.iter() {
-            if let Some(val) = row.get(col) {
-                hasher.update(b",");
-                hasher.update(val.as_bytes());
-            }
+            let value = match (row.get(col), self.missing_key_columns) {
+                (Some(val), _) => Some(val.as_str()),
+                (None, MissingKeyColumns::Skip) => None,
+                (None, MissingKeyColumns::Empty) => Some(""),
+                (None, MissingKeyColumns::Error | MissingKeyColumns::Reject) => return Err(col),
+            };
+            self.key_encoding.push_column(&mut self.key_buf, value);
         }
 
-        hasher.finalize().into()
+        Ok(self.hash_algorithm.digest(&self.key_buf))
+    }
+
+    // Header describing the sorted output, for saving it as a sorted file
+    fn sorted_file_header(&self) -> SortedFileHeader {
+        SortedFileHeader {
+            source_id: self.source_id.clone(),
+            source_key: self.source_key.clone(),
+            key_columns: self.key_columns.clone(),
+            geo_columns: self.geo_columns,
+            hash_algorithm: self.hash_algorithm.name().to_string(),
+            key_encoding: self.key_encoding.name().to_string(),
+            compression: self.run_config.compression.name().to_string(),
+            sorted_by_value: self.sort_spec.is_some(),
+            row_count: self.total_rows as u64,
+            record_bytes: self.output_bytes as u64,
+            data_length: 0,
+        }
     }
 
-    fn estimate_row_size(row: &[String]) -> usize {
-        let key_size = std::mem::size_of::<KeyData>();
+    fn estimate_row_size(sort_prefix: Option<&[u8]>, row: &[String]) -> usize {
+        let key_size = std::mem::size_of::<RunKey>() + sort_prefix.map_or(0, <[u8]>::len);
         let row_size: usize = row.iter().map(|s| s.len()).sum();
 
         key_size + row_size
     }
 
-    fn make_run(&mut self) -> std::io::Result<Option<File>> {
+    // Take the current batch and write it out as a sorted run file, on a background
+    // thread when background spilling is enabled
+    fn make_run(&mut self, reporter: &ProgressReporter) -> io::Result<()> {
         if self.current_batch.is_empty() {
-            return Ok(None);
+            return Ok(());
         }
 
-        debug!(target: "csv_utils::sorter", "Making run with {} records", self.current_batch.len());
+        let batch = std::mem::take(&mut self.current_batch);
+        self.current_buffer_size = 0;
 
-        // Sort in place before taking ownership
-        self.current_batch.sort_unstable();
+        // Only one run is written in the background at a time, which bounds memory
+        // to two buffers' worth of rows
+        self.finish_pending_run(reporter)?;
 
-        let temp = tempfile()?;
-        {
-            let mut w = BufWriter::with_capacity(BUFFER_CAPACITY, &temp);
-
-            for sort_record in self.current_batch.drain(..) {
-                // First, write the key data
-                bincode::encode_into_std_write(&sort_record.key, &mut w, bincode::config::legacy())
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-                // Write bincode into a buffer so we can record the size of the record
-                self.buf.clear();
-                let length = bincode::encode_into_std_write(
-                    &sort_record,
-                    &mut self.buf,
-                    bincode::config::legacy(),
-                )
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        debug!(target: "csv_utils::sorter", "Making run with {} records", batch.len());
 
-                // [key] [size of record] [record bytes] will make it easy to read the record back in later
-                w.write_all(&(length as u32).to_le_bytes())?;
-                w.write_all(&self.buf)?;
+        let (file, name) = match &mut self.work_dir {
+            Some(work_dir) => {
+                let (file, name) =
+                    work_dir.create_run(self.validator.as_ref().map(Validator::counts))?;
+                (file, Some(name))
             }
-            w.flush()?;
+            None => (self.spill_dirs.tempfile()?, None),
+        };
+
+        if self.background_spill {
+            let config = self.run_config.clone();
+            let handle = thread::Builder::new()
+                .name("csv_utils-run".to_string())
+                .spawn(move || write_run(batch, &config, file, name))?;
+            self.pending_run = Some(handle);
+        } else {
+            let run = write_run(batch, &self.run_config, file, name)?;
+            self.add_run(run, reporter)?;
+            info!(
+                target: "csv_utils::sorter",
+                "Created run file #{}",
+                self.temp_files.len()
+            );
         }
 
-        // current_batch is now empty due to drain, no need to clear
-        self.current_buffer_size = 0;
-        Ok(Some(temp))
+        Ok(())
+    }
+
+    // Wait for the background run (if any) to be written and add it to the run files
+    fn finish_pending_run(&mut self, reporter: &ProgressReporter) -> io::Result<()> {
+        if let Some(handle) = self.pending_run.take() {
+            let run = handle.join().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "Background run writer panicked")
+            })??;
+            self.add_run(run, reporter)?;
+            info!(
+                target: "csv_utils::sorter",
+                "Created run file #{} in the background",
+                self.temp_files.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    // Keep a finished run for merging and report it. Runs written in the background are
+    // reported here, on the thread that owns the progress block.
+    fn add_run(&mut self, run: Run, reporter: &ProgressReporter) -> io::Result<()> {
+        if let (Some(work_dir), Some(name)) = (&mut self.work_dir, &run.name) {
+            work_dir.commit_run(&run.file, run.entry(name))?;
+        }
+        self.spilled_rows += run.rows;
+        self.spilled_bytes += run.bytes;
+        self.temp_files.push(run);
+
+        reporter
+            .phase("run", None, None)
+            .finish(self.spilled_rows as u64, self.spilled_bytes as u64)
     }
 
-    fn merge_runs_to_file(&mut self) -> Result<usize, std::io::Error> {
+    // Merge every run file into the output file. When there are more runs than the
+    // configured fan-in, groups of runs are first merged into larger intermediate runs
+    // so that no more than `merge_fan_in` files are ever open at once.
+    fn merge_runs_to_file(
+        &mut self,
+        reporter: &ProgressReporter,
+        dedup: &mut Deduplicator,
+        limit: &mut RowLimit,
+    ) -> Result<usize, std::io::Error> {
         if self.temp_files.is_empty() {
             return Ok(0);
         }
 
-        // Allocate a buffer for the key data of the size of the KeyData struct
-        let mut key_bytes = bincode::encode_to_vec(
-            &KeyData {
-                value: [0u8; 20],
-                position: 0,
-            },
-            bincode::config::legacy(),
-        )
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let total_rows = Some(self.spilled_rows as u64);
+        let total_bytes = Some(self.spilled_bytes as u64);
+
+        let mut pass = 0;
+        while self.temp_files.len() > self.merge_fan_in {
+            pass += 1;
+            let mut runs = std::mem::take(&mut self.temp_files).into_iter();
+            let mut tracker = reporter.phase("intermediate_merge", total_rows, total_bytes);
+            let (mut rows, mut bytes) = (0, 0);
+            info!(
+                target: "csv_utils::sorter",
+                "Intermediate merge pass {}: merging {} runs with fan-in {}",
+                pass,
+                runs.len(),
+                self.merge_fan_in
+            );
+
+            loop {
+                let group: Vec<Run> = runs.by_ref().take(self.merge_fan_in).collect();
+                if group.len() <= 1 {
+                    self.temp_files.extend(group);
+                    break;
+                }
+
+                let (merged, name) = match &mut self.work_dir {
+                    Some(work_dir) => {
+                        let (file, name) = work_dir.create_merged_run()?;
+                        (file, Some(name))
+                    }
+                    None => (self.spill_dirs.tempfile()?, None),
+                };
+                let mut writer = RunWriter::new(
+                    SpillWriter::new(&merged, &self.run_config.spill_usage),
+                    self.run_config.compression,
+                    BUFFER_CAPACITY,
+                );
+                let mut group_bytes = 0;
+                let count = self.merge_runs(&group, |key, record_bytes| {
+                    writer.write_entry(Some(key), record_bytes)?;
+                    rows += 1;
+                    bytes += record_bytes.len() as u64;
+                    group_bytes += record_bytes.len();
+                    tracker.update(rows, bytes)
+                })?;
+                let charge = writer.finish()?.into_charge();
+
+                debug!(
+                    target: "csv_utils::sorter",
+                    "Merged {} runs into intermediate run with {} records",
+                    group.len(),
+                    count
+                );
+                let run = Run {
+                    file: merged,
+                    rows: count,
+                    bytes: group_bytes,
+                    name,
+                    _charge: charge,
+                };
+                // The merged runs are only deleted once the manifest lists their
+                // replacement, so a kill here loses no rows
+                if let (Some(work_dir), Some(name)) = (&mut self.work_dir, &run.name) {
+                    let merged_names: Vec<String> =
+                        group.iter().filter_map(|run| run.name.clone()).collect();
+                    work_dir.replace_runs(&merged_names, &run.file, run.entry(name))?;
+                }
+                self.temp_files.push(run);
+            }
+            tracker.finish(rows, bytes)?;
+        }
+
+        // Prepare output file
+        self.output_file.rewind()?;
+        self.output_file.set_len(0)?;
+        let mut writer = RunWriter::new(
+            &self.output_file,
+            self.run_config.compression,
+            BUFFER_CAPACITY,
+        );
+        let mut tracker = reporter.phase("merge", total_rows, total_bytes);
+        let (mut rows, mut bytes) = (0, 0);
+        let (mut count, mut bytes_written) = (0, 0);
+        let mut index = KeyIndex::default();
+        let mut write = |key: &KeyData, record_bytes: &[u8]| -> io::Result<()> {
+            if limit.admit(&key.value) {
+                index.record(&key.value, writer.entry_offset());
+                writer.write_entry(None, record_bytes)?;
+                count += 1;
+                bytes_written += record_bytes.len();
+            }
+            Ok(())
+        };
+        self.merge_runs(&self.temp_files, |key, record_bytes| {
+            dedup.push(&key.key, record_bytes, &mut write)?;
+            rows += 1;
+            bytes += record_bytes.len() as u64;
+            tracker.update(rows, bytes)
+        })?;
+        dedup.finish(&mut write)?;
+        writer.finish()?;
+        tracker.finish(rows, bytes)?;
+        self.temp_files.clear();
+        if let Some(work_dir) = &mut self.work_dir {
+            work_dir.finish()?;
+        }
+        self.spilled_rows = 0;
+        self.spilled_bytes = 0;
+        self.output_bytes = bytes_written;
+        self.key_index = index;
+
+        Ok(count)
+    }
+
+    // k-way merge of sorted run files, handing each entry to `emit` in key order
+    fn merge_runs<F>(&self, runs: &[Run], mut emit: F) -> io::Result<usize>
+    where
+        F: FnMut(&RunKey, &[u8]) -> io::Result<()>,
+    {
+        // Split the sort buffer between the readers so memory stays bounded by
+        // buffer_size_mb however many runs are merged
+        let reader_capacity = (self.buffer_size_bytes / runs.len().max(1))
+            .clamp(MIN_MERGE_BUFFER_CAPACITY, BUFFER_CAPACITY);
+        let sorted_by_value = self.sort_spec.is_some();
 
         // Prepare readers with their first records
-        let mut readers = Vec::with_capacity(self.temp_files.len());
-        for file in &self.temp_files {
-            let mut file = file
+        let mut readers = Vec::with_capacity(runs.len());
+        for run in runs {
+            let mut file = run
+                .file
                 .try_clone()
                 .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
             file.rewind()?;
-            let mut reader = BufReader::with_capacity(BUFFER_CAPACITY, file);
-
-            if reader.read_exact(&mut key_bytes).is_err() {
-                continue;
-            }
+            let mut reader = RunReader::new(file, self.run_config.compression, reader_capacity);
 
-            let (key, _): (KeyData, usize) =
-                bincode::decode_from_slice(&key_bytes, bincode::config::legacy())
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let key = match reader.read_key(sorted_by_value)? {
+                Some(key) => key,
+                None => continue,
+            };
 
-            let mut record_length_bytes = [0u8; 4];
-            reader.read_exact(&mut record_length_bytes)?;
-
-            let record_length = u32::from_le_bytes(record_length_bytes) as usize;
-            let mut record_bytes = vec![0u8; record_length];
-            reader.read_exact(&mut record_bytes)?;
+            let mut record_bytes = Vec::new();
+            reader.read_record(&mut record_bytes)?;
 
             readers.push((key, record_bytes, reader));
         }
@@ -193,58 +468,162 @@ impl SorterInner {
         // Create min-heap for merge sorting
         let mut heap = BinaryHeap::with_capacity(readers.len());
         for (i, (key, _, _)) in readers.iter().enumerate() {
-            heap.push((key.clone(), i));
+            heap.push(Reverse((key.clone(), i)));
         }
 
-        // Prepare output file
-        self.output_file.rewind()?;
-        let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, &self.output_file);
         let mut count = 0;
 
         // Process records in sorted order
-        while let Some((_, src_idx)) = heap.pop() {
+        while let Some(Reverse((_, src_idx))) = heap.pop() {
             if let Some((key, record_bytes, reader)) = readers.get_mut(src_idx) {
                 // Write current record
-                writer.write_all(&(record_bytes.len() as u32).to_le_bytes())?;
-                writer.write_all(record_bytes)?;
+                emit(key, record_bytes)?;
                 count += 1;
 
-                if reader.read_exact(&mut key_bytes).is_err() {
-                    // No more records in this reader
-                    continue;
+                if count % CANCEL_CHECK_INTERVAL == 0 {
+                    self.cancel.check()?;
                 }
 
-                let (next_key, _): (KeyData, usize) =
-                    bincode::decode_from_slice(&key_bytes, bincode::config::legacy())
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-                let mut record_length_bytes = [0u8; 4];
-                reader.read_exact(&mut record_length_bytes)?;
+                let next_key = match reader.read_key(sorted_by_value)? {
+                    Some(next_key) => next_key,
+                    // No more records in this reader
+                    None => continue,
+                };
 
-                let record_length = u32::from_le_bytes(record_length_bytes) as usize;
-                let mut rec_bytes = vec![0u8; record_length];
-                reader.read_exact(&mut rec_bytes)?;
-                heap.push((next_key.clone(), src_idx));
+                reader.read_record(record_bytes)?;
+                heap.push(Reverse((next_key.clone(), src_idx)));
                 *key = next_key;
-                *record_bytes = rec_bytes;
             }
         }
 
-        writer.flush()?;
-        self.temp_files.clear();
-
         Ok(count)
     }
 
-    fn sort_in_memory_to_file(&mut self) -> Result<usize, std::io::Error> {
+    // Sort everything added so far into the output file
+    fn sort_to_output_file(&mut self, reporter: &ProgressReporter) -> io::Result<SortSummary> {
+        self.cancel.check()?;
+
+        // A run may still be being written in the background
+        self.finish_pending_run(reporter).map_err(|e| {
+            error!(target: "csv_utils::sorter", "Error creating run file: {}", e);
+            e
+        })?;
+
+        let file_count = self.temp_files.len();
+
+        info!(
+            target: "csv_utils::sorter",
+            "Starting sort with {} temp files and {} records in buffer",
+            file_count,
+            self.current_batch.len()
+        );
+
+        let actual_row_data_size = self.current_batch_size();
+        self.observed_max_row_size = self.observed_max_row_size.max(actual_row_data_size);
+
+        // Rows are only grouped by targeting key in hash order, so the cap can't be
+        // applied when sorting by value
+        let mut limit = RowLimit::new(match self.sort_spec {
+            Some(_) => None,
+            None => Some(self.max_targeting_key_rows),
+        });
+        let mut dedup = Deduplicator::new(self.dedup);
+
+        // If there are no temp files and only data in current batch, sort in memory
+        let total_rows = if self.temp_files.is_empty() && !self.current_batch.is_empty() {
+            let count = self
+                .sort_in_memory_to_file(reporter, &mut dedup, &mut limit)
+                .map_err(|e| {
+                    error!(target: "csv_utils::sorter", "Error sorting data: {}", e);
+                    e
+                })?;
+            info!(
+                target: "csv_utils::sorter",
+                "Sorted {} records in memory",
+                count
+            );
+            count
+        } else {
+            // Otherwise we need to create a run from any remaining records
+            // and merge all runs
+            if !self.current_batch.is_empty() {
+                debug!(
+                    target: "csv_utils::sorter",
+                    "Creating final run from remaining {} records",
+                    self.current_batch.len()
+                );
+                self.make_run(reporter)
+                    .and_then(|_| self.finish_pending_run(reporter))
+                    .map_err(|e| {
+                        error!(target: "csv_utils::sorter", "Error creating run file: {}", e);
+                        e
+                    })?;
+            }
+
+            // Merge all runs to a final file
+            info!(
+                target: "csv_utils::sorter",
+                "Merging {} run files",
+                self.temp_files.len()
+            );
+            let count = self
+                .merge_runs_to_file(reporter, &mut dedup, &mut limit)
+                .map_err(|e| {
+                    error!(target: "csv_utils::sorter", "Error merging data: {}", e);
+                    e
+                })?;
+            info!(
+                target: "csv_utils::sorter",
+                "Merged {} records from {} run files",
+                count,
+                file_count
+            );
+            count
+        };
+
+        self.total_rows = total_rows;
+
+        if dedup.collapsed_rows > 0 {
+            info!(
+                target: "csv_utils::sorter",
+                "Collapsed {} duplicate rows ({:?})",
+                dedup.collapsed_rows,
+                self.dedup
+            );
+        }
+
+        if limit.dropped_rows > 0 {
+            warn!(
+                target: "csv_utils::sorter",
+                "Dropped {} rows from {} keys over max_targeting_key_rows ({})",
+                limit.dropped_rows,
+                limit.overflowed_key_count,
+                self.max_targeting_key_rows
+            );
+        }
+
+        Ok(SortSummary {
+            total_rows,
+            file_count,
+            limit,
+            collapsed_rows: dedup.collapsed_rows,
+        })
+    }
+
+    fn sort_in_memory_to_file(
+        &mut self,
+        reporter: &ProgressReporter,
+        dedup: &mut Deduplicator,
+        limit: &mut RowLimit,
+    ) -> Result<usize, std::io::Error> {
         if self.current_batch.is_empty() {
             return Ok(0);
         }
 
-        self.current_batch.sort_unstable();
+        self.run_config.sort_threads.sort(&mut self.current_batch);
 
         // Write sorted records directly to CSV using write_records
-        let total_rows = self.write_records()?;
+        let total_rows = self.write_records(reporter, dedup, limit)?;
 
         // Clear the batch
         self.current_batch.clear();
@@ -253,24 +632,54 @@ impl SorterInner {
         Ok(total_rows)
     }
 
-    fn write_records(&mut self) -> io::Result<usize> {
+    fn write_records(
+        &mut self,
+        reporter: &ProgressReporter,
+        dedup: &mut Deduplicator,
+        limit: &mut RowLimit,
+    ) -> io::Result<usize> {
         self.output_file.rewind()?;
-        let mut w = BufWriter::with_capacity(BUFFER_CAPACITY, &self.output_file);
-        let mut count = 0;
+        self.output_file.set_len(0)?;
+        let mut w = RunWriter::new(
+            &self.output_file,
+            self.run_config.compression,
+            BUFFER_CAPACITY,
+        );
+        // Reported as the merge phase too: it is what produces the sorted output
+        let mut tracker = reporter.phase("merge", Some(self.current_batch.len() as u64), None);
+        let (mut processed, mut processed_bytes) = (0, 0);
+        let (mut count, mut bytes) = (0, 0);
+        let mut index = KeyIndex::default();
+        let mut write = |key: &KeyData, record_bytes: &[u8]| -> io::Result<()> {
+            if limit.admit(&key.value) {
+                index.record(&key.value, w.entry_offset());
+                // Write the length-prefixed record bytes
+                w.write_entry(None, record_bytes)?;
+                count += 1;
+                bytes += record_bytes.len();
+            }
+            Ok(())
+        };
         for rec in self.current_batch.iter() {
+            processed += 1;
+            if processed % CANCEL_CHECK_INTERVAL == 0 {
+                self.cancel.check()?;
+            }
+
             // Serialize record to bytes
             self.buf.clear();
-            let length =
-                bincode::encode_into_std_write(rec, &mut self.buf, bincode::config::legacy())
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            bincode::encode_into_std_write(&rec.record, &mut self.buf, bincode::config::legacy())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-            // Write length as u32 (4 bytes)
-            w.write_all(&(length as u32).to_le_bytes())?;
-            // Write the record bytes
-            w.write_all(&self.buf)?;
-            count += 1;
+            dedup.push(&rec.record.key, &self.buf, &mut write)?;
+            processed_bytes += self.buf.len() as u64;
+            tracker.update(processed as u64, processed_bytes)?;
         }
-        w.flush()?;
+        dedup.finish(&mut write)?;
+        w.finish()?;
+        tracker.finish(processed as u64, processed_bytes)?;
+        self.output_bytes = bytes;
+        self.key_index = index;
         Ok(count)
     }
 
@@ -283,10 +692,12 @@ impl SorterInner {
         let elements_size: usize = self
             .current_batch
             .iter()
-            .map(|sort_record| {
+            .map(|buffered| {
+                let sort_record = &buffered.record;
                 // Size of KeyData struct
-                let key_size =
-                    std::mem::size_of_val(&sort_record.key) + std::mem::size_of::<[u8; 20]>();
+                let key_size = std::mem::size_of_val(&sort_record.key)
+                    + std::mem::size_of::<TargetKey>()
+                    + buffered.sort_prefix.as_ref().map_or(0, Vec::capacity);
 
                 // Size of Vec<String> and its contents
                 let row_size = std::mem::size_of_val(&sort_record.record)
@@ -302,21 +713,173 @@ impl SorterInner {
 
         vec_size + elements_size
     }
+
+    // Copier reading the sorted output from the start
+    fn postgres_copier(&self, schema: CopySchema) -> Result<PostgresCopier<File>, Error> {
+        let mut input_file = self.output_file.try_clone().map_err(|e| {
+            Error::new(
+                magnus::exception::runtime_error(),
+                format!("Failed to clone output file: {}", e),
+            )
+        })?;
+        input_file.rewind().map_err(|e| {
+            Error::new(
+                magnus::exception::runtime_error(),
+                format!("Failed to rewind input file: {}", e),
+            )
+        })?;
+
+        let copier = PostgresCopier::new(
+            input_file,
+            self.run_config.compression,
+            self.geo_columns,
+            self.source_key.clone(),
+            schema,
+        )
+        .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+
+        debug!(
+            target: "csv_utils::sorter",
+            "Created PostgreSQL copier for source key: {}",
+            self.source_key
+        );
+
+        Ok(copier)
+    }
+}
+
+// How rows inside a run are sorted. rayon's global pool is never used, since it would
+// take every core of a host shared with other workers.
+#[derive(Clone)]
+enum SortThreads {
+    Sequential,
+    Pool(Arc<ThreadPool>),
+}
+
+impl SortThreads {
+    fn sort(&self, records: &mut [BufferedRecord]) {
+        match self {
+            SortThreads::Sequential => records.sort_unstable(),
+            SortThreads::Pool(pool) => pool.install(|| records.par_sort_unstable()),
+        }
+    }
+}
+
+// Everything needed to write a run file, cloned onto background threads
+#[derive(Clone)]
+struct RunConfig {
+    compression: RunCompression,
+    sort_threads: SortThreads,
+    // Disk taken by run files, checked against max_spill_bytes
+    spill_usage: SpillUsage,
+}
+
+// A sorted run file with the number of records and record bytes written to it
+struct Run {
+    file: File,
+    rows: usize,
+    bytes: usize,
+    // File name in the working directory; unnamed temp files have none
+    name: Option<String>,
+    // Counts the file against max_spill_bytes until the run is dropped
+    _charge: SpillCharge,
+}
+
+impl Run {
+    fn entry(&self, name: &str) -> RunEntry {
+        RunEntry {
+            name: name.to_string(),
+            rows: self.rows,
+            bytes: self.bytes,
+        }
+    }
+}
+
+// Sort a batch of records and write it to `file` as a new run
+fn write_run(
+    mut batch: Vec<BufferedRecord>,
+    config: &RunConfig,
+    file: File,
+    name: Option<String>,
+) -> io::Result<Run> {
+    config.sort_threads.sort(&mut batch);
+
+    let mut w = RunWriter::new(
+        SpillWriter::new(&file, &config.spill_usage),
+        config.compression,
+        BUFFER_CAPACITY,
+    );
+    let mut buf = Vec::new();
+    let mut bytes = 0;
+
+    let rows = batch.len();
+
+    for buffered in batch {
+        // Write bincode into a buffer so we can record the size of the record
+        buf.clear();
+        bincode::encode_into_std_write(&buffered.record, &mut buf, bincode::config::legacy())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        // [key] [size of record] [record bytes] will make it easy to read the record back in later
+        let key = RunKey {
+            sort_prefix: buffered.sort_prefix,
+            key: buffered.record.key,
+        };
+        w.write_entry(Some(&key), &buf)?;
+        bytes += buf.len();
+    }
+    let charge = w.finish()?.into_charge();
+
+    Ok(Run {
+        file,
+        rows,
+        bytes,
+        name,
+        _charge: charge,
+    })
 }
 
 impl Sorter {
-    pub fn new(
-        source_id: String,
-        source_key: String,
-        key_columns: Vec<usize>,
-        geo_columns_vec: Option<Vec<usize>>,
-        buffer_size_mb: usize,
-    ) -> Result<Self, Error> {
+    pub fn new(args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<
+            (String, String, Vec<usize>, Option<Vec<usize>>, usize),
+            (Option<RHash>,),
+            (),
+            (),
+            (),
+            (),
+        >(args)?;
+        let (source_id, source_key, key_columns, geo_columns_vec, buffer_size_mb) = args.required;
+        let (options,) = args.optional;
+        let options = match options {
+            Some(options) => SorterOptions::from_ruby(options)?,
+            None => SorterOptions::default(),
+        };
+
         let buffer_size_bytes = buffer_size_mb * 1024 * 1024;
 
+        let sort_threads = match options.sort_threads {
+            1 => SortThreads::Sequential,
+            threads => {
+                let pool = ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .thread_name(|i| format!("csv_utils-sort-{}", i))
+                    .build()
+                    .map_err(|e| {
+                        Error::new(
+                            magnus::exception::runtime_error(),
+                            format!("Failed to create sort thread pool: {}", e),
+                        )
+                    })?;
+                SortThreads::Pool(Arc::new(pool))
+            }
+        };
+
         let geo_columns = geo_columns_vec.map(|indexes| (indexes[0], indexes[1]));
 
-        let output_file = tempfile().map_err(|e| {
+        let mut spill_dirs = SpillDirs::new(options.spill_dirs.clone());
+        let spill_usage = SpillUsage::new(options.max_spill_bytes);
+        let output_file = spill_dirs.tempfile().map_err(|e| {
             Error::new(
                 magnus::exception::runtime_error(),
                 format!("Failed to create output file: {}", e),
@@ -325,51 +888,161 @@ impl Sorter {
 
         info!(target: "csv_utils::sorter", "Creating new sorter for source: {}", source_id);
 
+        // Pick up run files left in the working directory by an earlier sorter
+        let (work_dir, temp_files) = match &options.work_dir {
+            Some(path) => {
+                let work_dir = WorkDir::open(
+                    path,
+                    WorkSettings {
+                        source_id: source_id.clone(),
+                        key_columns: key_columns.clone(),
+                        hash_algorithm: options.hash_algorithm.name().to_string(),
+                        key_encoding: options.key_encoding.name().to_string(),
+                        missing_key_columns: options.missing_key_columns.name().to_string(),
+                        compression: options.compression.name().to_string(),
+                        sort_by: options.sort_by.clone(),
+                        dedup: options.dedup.name().to_string(),
+                        max_targeting_key_rows: options.max_targeting_key_rows,
+                    },
+                )?;
+                let runs = work_dir
+                    .open_runs()?
+                    .into_iter()
+                    .map(|(file, entry)| {
+                        let disk_bytes = file.metadata().map_or(0, |metadata| metadata.len());
+                        Run {
+                            file,
+                            rows: entry.rows,
+                            bytes: entry.bytes,
+                            name: Some(entry.name),
+                            _charge: spill_usage.existing(disk_bytes),
+                        }
+                    })
+                    .collect();
+                (Some(work_dir), runs)
+            }
+            None => (None, Vec::new()),
+        };
+        let spilled_rows = temp_files.iter().map(|run: &Run| run.rows).sum();
+        let spilled_bytes = temp_files.iter().map(|run: &Run| run.bytes).sum();
+
+        let cancel = CancelToken::default();
+
         Ok(Self {
             inner: RefCell::new(SorterInner {
                 source_id,
                 source_key,
                 key_columns,
+                sort_spec: options.sort_by,
+                hash_algorithm: options.hash_algorithm,
+                key_encoding: options.key_encoding,
+                missing_key_columns: options.missing_key_columns,
+                key_buf: Vec::new(),
                 geo_columns,
                 current_batch: Vec::new(),
                 buffer_size_bytes,
-                temp_files: Vec::new(),
+                temp_files,
                 current_buffer_size: 0,
+                run_config: RunConfig {
+                    compression: options.compression,
+                    sort_threads,
+                    spill_usage,
+                },
+                background_spill: options.background_spill,
+                pending_run: None,
+                work_dir,
+                spill_dirs,
+                merge_fan_in: options.merge_fan_in,
                 output_file,
+                key_index: KeyIndex::default(),
                 total_rows: 0,
                 observed_max_row_size: 0,
-                max_targeting_key_rows: DEFAULT_MAX_TARGETING_KEY_ROWS,
+                spilled_rows,
+                spilled_bytes,
+                output_bytes: 0,
+                max_targeting_key_rows: options.max_targeting_key_rows,
+                dedup: options.dedup,
                 validator: None,
                 buf: Vec::with_capacity(BUFFER_CAPACITY),
+                cancel: cancel.clone(),
             }),
+            cancel,
+            progress: Cell::new(None),
         })
     }
 
+    // The sorter's state. Methods that release the GVL (sort!, save, ...) and ones that
+    // yield to a block keep it borrowed, so a call from another Ruby thread or from the
+    // block raises `CsvUtils::SorterBusyError` instead of waiting.
+    fn inner(&self) -> Result<Ref<'_, SorterInner>, Error> {
+        self.inner.try_borrow().map_err(|_| sorter_busy())
+    }
+
+    fn inner_mut(&self) -> Result<RefMut<'_, SorterInner>, Error> {
+        self.inner.try_borrow_mut().map_err(|_| sorter_busy())
+    }
+
     pub fn enable_validation(&self, schema: RArray, error_log_path: String) -> Result<(), Error> {
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = self.inner_mut()?;
         let rules = ruby_rules_array_to_rules(schema)
             .map_err(|e| Error::new(magnus::exception::arg_error(), e.to_string()))?;
 
         info!(target: "csv_utils::sorter", "Validation enabled with error log: {}", error_log_path);
 
-        inner.validator = Some(
-            Validator::new(rules, error_log_path)
-                .map_err(|e| Error::new(magnus::exception::arg_error(), e.to_string()))?,
-        );
+        // Count the rows an earlier sorter already wrote to the working directory's runs
+        let validator = match inner.work_dir.as_ref().and_then(WorkDir::validation) {
+            Some(counts) => Validator::resume(rules, error_log_path, counts),
+            None => Validator::new(rules, error_log_path),
+        }
+        .map_err(|e| Error::new(magnus::exception::arg_error(), e.to_string()))?;
+        inner.validator = Some(validator);
 
         Ok(())
     }
 
-    pub fn add_row(&self, row: Vec<String>, position: usize) -> bool {
-        let mut inner = self.inner.borrow_mut();
+    pub fn add_row(&self, row: Vec<String>, position: usize) -> Result<bool, Error> {
+        let mut inner = self.inner_mut()?;
 
-        let key_bytes = inner.generate_targeting_key(&row);
+        let key_bytes = match inner.generate_targeting_key(&row) {
+            Ok(key_bytes) => key_bytes,
+            Err(col) if inner.missing_key_columns == MissingKeyColumns::Reject => {
+                debug!(
+                    target: "csv_utils::sorter",
+                    "Rejecting row {}: missing key column {}",
+                    position,
+                    col
+                );
+                if let Some(validator) = &mut inner.validator {
+                    let _ = validator.add_error_to_file(
+                        "missing_key",
+                        position,
+                        col,
+                        &format!("Key column {}", col + 1),
+                    );
+                    validator.missing_key_error_count += 1;
+                }
+                return Ok(false);
+            }
+            Err(col) => {
+                error!(
+                    target: "csv_utils::sorter",
+                    "Row {} is missing key column {}",
+                    position,
+                    col
+                );
+                return Err(Error::new(
+                    missing_key_column_error(),
+                    format!("Row {} is missing key column {}", position, col),
+                ));
+            }
+        };
+        let sort_prefix = inner.sort_spec.as_ref().map(|spec| spec.encode(&row));
         let key = KeyData {
             value: key_bytes,
             position,
         };
 
-        let row_size = SorterInner::estimate_row_size(&row);
+        let row_size = SorterInner::estimate_row_size(sort_prefix.as_deref(), &row);
 
         // Check if adding this row would exceed buffer size
         if inner.current_buffer_size + row_size > inner.buffer_size_bytes
@@ -387,32 +1060,23 @@ impl Sorter {
             );
 
             // Create a new run file from current batch
-            match inner.make_run() {
-                Ok(Some(run_file)) => {
-                    inner.temp_files.push(run_file);
-                    info!(
-                        target: "csv_utils::sorter",
-                        "Created run file #{}",
-                        inner.temp_files.len()
-                    );
-                }
-                Ok(None) => {
-                    // No records to process, this is fine
-                }
-                Err(e) => {
-                    error!(target: "csv_utils::sorter", "Error creating run file: {}", e);
-                    eprintln!("Error creating run file: {}", e);
-                }
+            let reporter = ProgressReporter::new(self.progress.get());
+            if let Err(e) = inner.make_run(&reporter) {
+                error!(target: "csv_utils::sorter", "Error creating run file: {}", e);
+                return reporter.result(Err(phase_error("Error creating run file", e)));
             }
         }
 
         if let Some(validator) = &mut inner.validator {
             if !validator.validate_row(&row) {
-                return false;
+                return Ok(false);
             }
         }
 
-        inner.current_batch.push(SortRecord { key, record: row });
+        inner.current_batch.push(BufferedRecord {
+            sort_prefix,
+            record: SortRecord { key, record: row },
+        });
         inner.current_buffer_size += row_size;
 
         trace!(
@@ -423,32 +1087,164 @@ impl Sorter {
             inner.buffer_size_bytes
         );
 
-        true
+        Ok(true)
     }
 
-    pub fn add_file(&self, file_path: String) -> Result<(), Error> {
+    pub fn add_file(&self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(String,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (file_path,) = args.required;
+        let (options,) = args.optional;
+        let csv_options = parse_csv_options(options)?;
+
         info!(target: "csv_utils::sorter", "Adding file: {}", file_path);
 
-        // parse csv file, skipping headers
+        // With a working directory, pick up after the rows an earlier sorter already
+        // wrote to run files
+        let checkpoint = {
+            let mut inner = self.inner_mut()?;
+            match &mut inner.work_dir {
+                Some(work_dir) => {
+                    let (byte_offset, position) = match work_dir.input(&file_path) {
+                        Some(input) if input.complete => {
+                            info!(
+                                target: "csv_utils::sorter",
+                                "Skipping {}: all {} rows are in the working directory",
+                                file_path,
+                                input.position
+                            );
+                            return Ok(());
+                        }
+                        Some(input) => (input.byte_offset, input.position),
+                        None => (0, 0),
+                    };
+                    work_dir.begin_input(&file_path, byte_offset, position);
+                    Some((byte_offset, position))
+                }
+                None => None,
+            }
+        };
+
         let file = File::open(&file_path)
             .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
-        let mut reader = csv::Reader::from_reader(file);
+        let total_bytes = file.metadata().ok().map(|metadata| metadata.len());
+        // Progress is measured on the file as stored, before any decompression
+        let file = CountingReader::new(file);
+        let bytes_read = file.counter();
+        // Compressed feeds (.csv.gz, .csv.zst, .csv.bz2) are decoded as a stream
+        let mut input = decompressing_reader(
+            BufReader::with_capacity(IO_BUFFER_CAPACITY, file),
+            Some(Path::new(&file_path)),
+        )
+        .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+
+        // Offsets are into the decompressed stream, so skip ahead by reading
+        if let Some((byte_offset, position)) = checkpoint.filter(|(offset, _)| *offset > 0) {
+            info!(
+                target: "csv_utils::sorter",
+                "Resuming {} at byte {} (row {})",
+                file_path,
+                byte_offset,
+                position
+            );
+            io::copy(&mut input.by_ref().take(byte_offset), &mut io::sink())
+                .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+        }
+
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase("add_file", None, total_bytes);
+        let position = reporter.result(self.add_reader(
+            input,
+            &csv_options,
+            &mut tracker,
+            &bytes_read,
+            checkpoint,
+        ))?;
+
+        info!(target: "csv_utils::sorter", "Finished processing file: {}, read {} rows", file_path, position);
+        Ok(())
+    }
+
+    // Read CSV from any Ruby object that responds to `read`, e.g. IO, StringIO or Tempfile
+    pub fn add_io(&self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(Value,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (io,) = args.required;
+        let (options,) = args.optional;
+        let csv_options = parse_csv_options(options)?;
+
+        info!(target: "csv_utils::sorter", "Adding IO stream");
+
+        let io = CountingReader::new(RubyReader::new(io)?);
+        let bytes_read = io.counter();
+        let reader = BufReader::with_capacity(IO_BUFFER_CAPACITY, io);
+        let input = decompressing_reader(reader, None)
+            .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase("add_io", None, None);
+        let position = reporter.result(self.add_reader(
+            input,
+            &csv_options,
+            &mut tracker,
+            &bytes_read,
+            None,
+        ))?;
+
+        info!(target: "csv_utils::sorter", "Finished processing IO stream, read {} rows", position);
+        Ok(())
+    }
+
+    // Parse CSV rows from `input` and add them, returning the position after the last row.
+    // `bytes_read` counts the raw input consumed so far, for progress reports.
+    // `checkpoint` is the byte offset and position `input` starts at when the working
+    // directory tracks it, and each row's offset is recorded there as it is added.
+    fn add_reader<R: Read>(
+        &self,
+        input: R,
+        csv_options: &CsvOptions,
+        tracker: &mut ProgressTracker,
+        bytes_read: &Rc<Cell<u64>>,
+        checkpoint: Option<(u64, usize)>,
+    ) -> Result<usize, Error> {
+        let (start_offset, mut position) = checkpoint.unwrap_or((0, 0));
+        // parse csv, skipping headers unless told otherwise. A resumed file starts
+        // past its header.
+        let mut builder = csv_options.reader_builder();
+        if start_offset > 0 {
+            builder.has_headers(false);
+        }
+        let mut reader = builder.from_reader(input);
         // Allocate a buffer for the record
         let mut record = csv::StringRecord::new();
-        let mut position = 0;
 
         loop {
             match reader.read_record(&mut record) {
                 Ok(true) => {
+                    if checkpoint.is_some() {
+                        let offset = start_offset + record.position().map_or(0, |p| p.byte());
+                        if let Some(work_dir) = &mut self.inner_mut()?.work_dir {
+                            work_dir.advance_input(offset, position, false);
+                        }
+                    }
+
                     // Convert ByteRecord to Vec<String>
                     let row: Vec<String> = record.iter().map(|field| field.to_string()).collect();
 
-                    self.add_row(row, position);
+                    self.add_row(row, position)?;
                     position += 1;
+                    tracker
+                        .update(position as u64, bytes_read.get())
+                        .map_err(progress_error)?;
                 }
                 Ok(false) => break, // End of file
+                Err(e) if e.is_io_error() => {
+                    // The underlying stream failed; retrying would just fail again
+                    error!(target: "csv_utils::sorter", "Error reading row {}: {}", position, e);
+                    return Err(Error::new(
+                        magnus::exception::runtime_error(),
+                        format!("Error reading input: {}", e),
+                    ));
+                }
                 Err(e) => {
-                    if let Some(validator) = &mut self.inner.borrow_mut().validator {
+                    if let Some(validator) = &mut self.inner_mut()?.validator {
                         let _ = validator.add_error_to_file("parse", position, 0, &e.to_string());
                         validator.parse_error_count += 1;
                     }
@@ -459,98 +1255,50 @@ impl Sorter {
             }
         }
 
-        info!(target: "csv_utils::sorter", "Finished processing file: {}, read {} rows", file_path, position);
-        Ok(())
+        if checkpoint.is_some() {
+            let offset = start_offset + reader.position().byte();
+            if let Some(work_dir) = &mut self.inner_mut()?.work_dir {
+                work_dir.advance_input(offset, position, true);
+            }
+        }
+
+        tracker
+            .finish(position as u64, bytes_read.get())
+            .map_err(progress_error)?;
+
+        Ok(position)
     }
 
-    // Sort all rows and write to a final temp file, return total rows information
+    // Sort all rows and write to a final temp file, return total rows information.
+    // The sort and merge run without the GVL so other Ruby threads keep running.
     pub fn sort(&self) -> Result<RHash, Error> {
-        let mut inner = self.inner.borrow_mut();
-        let temp_file_count = inner.temp_files.len();
+        let mut inner = self.inner_mut()?;
 
-        info!(
-            target: "csv_utils::sorter",
-            "Starting sort with {} temp files and {} records in buffer",
-            temp_file_count,
-            inner.current_batch.len()
-        );
-
-        let actual_row_data_size = inner.current_batch_size();
-        inner.observed_max_row_size = inner.observed_max_row_size.max(actual_row_data_size);
-
-        // If there are no temp files and only data in current batch, sort in memory
-        let total_rows = if inner.temp_files.is_empty() && !inner.current_batch.is_empty() {
-            match inner.sort_in_memory_to_file() {
-                Ok(count) => {
-                    info!(
-                        target: "csv_utils::sorter",
-                        "Sorted {} records in memory",
-                        count
-                    );
-                    inner.total_rows = count;
-                    count
-                }
-                Err(e) => {
-                    error!(target: "csv_utils::sorter", "Error sorting data: {}", e);
-                    return Err(Error::new(
-                        magnus::exception::runtime_error(),
-                        format!("Error sorting data: {}", e),
-                    ));
-                }
-            }
-        } else {
-            // Otherwise we need to create a run from any remaining records
-            // and merge all runs
-            if !inner.current_batch.is_empty() {
-                debug!(
-                    target: "csv_utils::sorter",
-                    "Creating final run from remaining {} records",
-                    inner.current_batch.len()
-                );
-                match inner.make_run() {
-                    Ok(Some(run_file)) => {
-                        inner.temp_files.push(run_file);
-                    }
-                    Ok(None) => {
-                        // No records to process, this is fine
-                    }
-                    Err(e) => {
-                        error!(target: "csv_utils::sorter", "Error creating run file: {}", e);
-                        eprintln!("Error creating run file: {}", e);
-                    }
-                }
-            }
-
-            // Merge all runs to a final file
-            info!(
-                target: "csv_utils::sorter",
-                "Merging {} run files",
-                inner.temp_files.len()
-            );
-            match inner.merge_runs_to_file() {
-                Ok(count) => {
-                    info!(
-                        target: "csv_utils::sorter",
-                        "Merged {} records from {} run files",
-                        count,
-                        temp_file_count
-                    );
-                    inner.total_rows = count;
-                    count
-                }
-                Err(e) => {
-                    error!(target: "csv_utils::sorter", "Error merging data: {}", e);
-                    return Err(Error::new(
-                        magnus::exception::runtime_error(),
-                        format!("Error merging data: {}", e),
-                    ));
-                }
-            }
-        };
+        let reporter = ProgressReporter::new(self.progress.get());
+        let summary = reporter.result(
+            without_gvl(&self.cancel, || inner.sort_to_output_file(&reporter))
+                .and_then(|result| result.map_err(|e| phase_error("Error sorting data", e))),
+        )?;
+        let total_rows = summary.total_rows;
+        let temp_file_count = summary.file_count;
 
         let result = RHash::new();
         result.aset(Symbol::new("total_rows"), total_rows)?;
         result.aset(Symbol::new("file_count"), temp_file_count)?;
+        result.aset(Symbol::new("collapsed_rows"), summary.collapsed_rows)?;
+        result.aset(Symbol::new("dropped_rows"), summary.limit.dropped_rows)?;
+        result.aset(
+            Symbol::new("overflowed_key_count"),
+            summary.limit.overflowed_key_count,
+        )?;
+        let overflowed_keys = RArray::new();
+        for overflow in summary.limit.overflowed_keys.iter() {
+            let entry = RHash::new();
+            entry.aset(Symbol::new("key"), overflow.key.to_hex())?;
+            entry.aset(Symbol::new("dropped_rows"), overflow.dropped_rows)?;
+            overflowed_keys.push(entry)?;
+        }
+        result.aset(Symbol::new("overflowed_keys"), overflowed_keys)?;
         result.aset(
             Symbol::new("max_row_memory_usage"),
             inner.observed_max_row_size,
@@ -572,7 +1320,7 @@ impl Sorter {
     pub fn each_batch(&self, batch_size: usize) -> Result<(), Error> {
         let ruby = Ruby::get().unwrap();
         let block = ruby.block_proc()?;
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = self.inner_mut()?;
 
         info!(
             target: "csv_utils::sorter",
@@ -587,159 +1335,721 @@ impl Sorter {
             )
         })?;
 
-        let mut reader = BufReader::with_capacity(BUFFER_CAPACITY, &inner.output_file);
-        let mut current_batch: RArray = RArray::new();
-        let mut last_key = [0u8; 20];
-        let mut run_length = 0;
-        let mut total_processed = 0;
-        let mut batch_count = 0;
+        let mut reader = RunReader::new(
+            &inner.output_file,
+            inner.run_config.compression,
+            BUFFER_CAPACITY,
+        );
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "each_batch",
+            Some(inner.total_rows as u64),
+            Some(inner.output_bytes as u64),
+        );
+        yield_batches(&mut reader, batch_size, block, &reporter, &mut tracker)
+    }
 
-        loop {
-            let mut len_bytes = [0u8; 4];
-            if reader.read_exact(&mut len_bytes).is_err() {
-                break; // EOF
-            }
-            let len = u32::from_le_bytes(len_bytes) as usize;
+    // Iterate over the sorted output one targeting key at a time, yielding the key's hex
+    // digest and its rows, most recently added first. `max_rows:` caps each group.
+    pub fn each_group(&self, args: &[Value]) -> Result<(), Error> {
+        let max_rows = group_max_rows(args)?;
 
-            let mut bytes = vec![0u8; len];
-            reader
-                .read_exact(&mut bytes)
-                .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+        let ruby = Ruby::get().unwrap();
+        let block = ruby.block_proc()?;
+        let mut inner = self.inner_mut()?;
 
-            let record: SortRecord = bincode::decode_from_slice(&bytes, bincode::config::legacy())
-                .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?
-                .0;
+        if inner.sort_spec.is_some() {
+            return Err(Error::new(
+                magnus::exception::runtime_error(),
+                "each_group needs rows sorted by targeting key, not sort_by",
+            ));
+        }
 
-            let target_key = record.key.value;
+        info!(
+            target: "csv_utils::sorter",
+            "Starting group iteration with max group size {:?}",
+            max_rows
+        );
 
-            if !current_batch.is_empty() && target_key == last_key {
-                run_length += 1;
-            } else {
-                run_length = 1;
-            }
+        inner.output_file.rewind().map_err(|e| {
+            Error::new(
+                magnus::exception::runtime_error(),
+                format!("Error seeking in sorted file: {}", e),
+            )
+        })?;
 
-            if run_length > inner.max_targeting_key_rows {
-                // We will never serve more than MAX_RUN_LENGTH rows for a given key, so
-                // may as well not emit them
-                debug!(
-                    target: "csv_utils::sorter",
-                    "Skipping record with key {} (hit max run length {})",
-                    std::str::from_utf8(&target_key).unwrap_or("<invalid utf8>"),
-                    inner.max_targeting_key_rows
-                );
-                continue;
-            }
+        let mut reader = RunReader::new(
+            &inner.output_file,
+            inner.run_config.compression,
+            BUFFER_CAPACITY,
+        );
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "each_group",
+            Some(inner.total_rows as u64),
+            Some(inner.output_bytes as u64),
+        );
+        yield_groups(&mut reader, max_rows, block, &reporter, &mut tracker)
+    }
 
-            // If the batch is full, complete the target_key run and then start a new batch
-            if current_batch.len() >= batch_size && target_key != last_key {
-                debug!(
-                    target: "csv_utils::sorter",
-                    "Yielding batch {} with {} records",
-                    batch_count,
-                    current_batch.len()
+    // Rows of the sorted output whose key columns hold `key_values`, most recent first
+    pub fn lookup(&self, key_values: Vec<String>) -> Result<RArray, Error> {
+        let key = {
+            let inner = self.inner()?;
+            check_key_values(&key_values, inner.key_columns.len())?;
+            key_for_values(
+                inner.hash_algorithm,
+                inner.key_encoding,
+                &inner.source_id,
+                &key_values,
+            )
+        };
+
+        self.lookup_key(key)
+    }
+
+    // Rows of the sorted output with the given hex targeting key, most recent first
+    pub fn lookup_hex(&self, hex: String) -> Result<RArray, Error> {
+        let key = parse_hex_key(&hex, self.inner()?.hash_algorithm)?;
+        self.lookup_key(key)
+    }
+
+    fn lookup_key(&self, key: TargetKey) -> Result<RArray, Error> {
+        let inner = self.inner()?;
+        if inner.sort_spec.is_some() {
+            return Err(Error::new(
+                magnus::exception::runtime_error(),
+                "lookup needs rows sorted by targeting key, not sort_by",
+            ));
+        }
+
+        debug!(target: "csv_utils::sorter", "Looking up key {}", key.to_hex());
+
+        let rows = inner
+            .key_index
+            .lookup(
+                &inner.output_file,
+                0..u64::MAX,
+                inner.run_config.compression,
+                &key,
+            )
+            .map_err(|e| {
+                Error::new(
+                    magnus::exception::runtime_error(),
+                    format!("Error reading sorted file: {}", e),
+                )
+            })?;
+
+        Ok(RArray::from_vec(rows))
+    }
+
+    // Write the sorted rows in PostgreSQL's binary COPY format, with the columns given by
+    // an optional `columns:` option, see `CopySchema::from_options`
+    pub fn write_binary_postgres_file(&self, args: &[Value]) -> Result<RHash, Error> {
+        let args = scan_args::<(String,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (file_path,) = args.required;
+        let (options,) = args.optional;
+        let schema = CopySchema::from_options(options)?;
+        let mut inner = self.inner_mut()?;
+        let output_file_path = Path::new(&file_path);
+
+        info!(
+            target: "csv_utils::sorter",
+            "Writing binary PostgreSQL file to {}",
+            file_path
+        );
+
+        let mut copier = inner.postgres_copier(schema)?;
+
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "write_binary_postgres_file",
+            Some(inner.total_rows as u64),
+            Some(inner.output_bytes as u64),
+        );
+
+        // Writing the file is pure Rust I/O, so let other Ruby threads run meanwhile
+        let stats = reporter.result(
+            without_gvl(&self.cancel, || {
+                copier.copy(
+                    output_file_path,
+                    &self.cancel,
+                    &mut tracker,
+                    inner.validator.as_mut(),
+                )
+            })
+            .and_then(|result| result.map_err(|e| phase_error("Error writing PostgreSQL file", e))),
+        )?;
+
+        info!(
+            target: "csv_utils::sorter",
+            "Successfully wrote PostgreSQL binary format file to {}",
+            file_path
+        );
+
+        copy_result(&stats, inner.validator.as_ref())
+    }
+
+    // Stream the sorted rows into a PostgreSQL table with COPY, see
+    // `postgres_loader::copy_to_postgres`. Takes the same `columns:` and `null:` options
+    // as `write_binary_postgres_file`.
+    pub fn copy_to_postgres(&self, args: &[Value]) -> Result<RHash, Error> {
+        let args = scan_args::<(String, String), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (conninfo, table) = args.required;
+        let (options,) = args.optional;
+        let schema = CopySchema::from_options(options)?;
+        let load_options = LoadOptions::from_ruby(table, options)?;
+        let mut inner = self.inner_mut()?;
+
+        info!(
+            target: "csv_utils::sorter",
+            "Copying sorted rows into {}",
+            load_options.table
+        );
+
+        let mut copier = inner.postgres_copier(schema)?;
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "copy_to_postgres",
+            Some(inner.total_rows as u64),
+            Some(inner.output_bytes as u64),
+        );
+
+        // Waiting on the server doesn't need the GVL either
+        let stats = reporter.result(
+            without_gvl(&self.cancel, || {
+                copy_to_postgres(
+                    &mut copier,
+                    &conninfo,
+                    &load_options,
+                    &self.cancel,
+                    &mut tracker,
+                    inner.validator.as_mut(),
+                )
+            })
+            .and_then(|result| result.map_err(|e| phase_error("Error copying to PostgreSQL", e))),
+        )?;
+
+        copy_result(&stats, inner.validator.as_ref())
+    }
+
+    // Load the sorted rows into a PostgreSQL table through a staging table, replacing
+    // this source key's rows or the whole table, see `postgres_loader::load_into_postgres`
+    pub fn load_into_postgres(&self, args: &[Value]) -> Result<RHash, Error> {
+        let args = scan_args::<(String, String), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (conninfo, table) = args.required;
+        let (options,) = args.optional;
+        let schema = CopySchema::from_options(options)?;
+        let load_options = LoadOptions::from_ruby(table, options)?;
+        let staging_options = StagingOptions::from_ruby(options)?;
+        let mut inner = self.inner_mut()?;
+
+        info!(
+            target: "csv_utils::sorter",
+            "Loading sorted rows into {} through a staging table",
+            load_options.table
+        );
+
+        let mut copier = inner.postgres_copier(schema)?;
+        let source_key = inner.source_key.clone();
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "load_into_postgres",
+            Some(inner.total_rows as u64),
+            Some(inner.output_bytes as u64),
+        );
+
+        let stats = reporter.result(
+            without_gvl(&self.cancel, || {
+                load_into_postgres(
+                    &mut copier,
+                    &conninfo,
+                    &source_key,
+                    &load_options,
+                    &staging_options,
+                    &self.cancel,
+                    &mut tracker,
+                    inner.validator.as_mut(),
+                )
+            })
+            .and_then(|result| result.map_err(|e| phase_error("Error loading into PostgreSQL", e))),
+        )?;
+
+        let result = copy_result(&stats.copy, inner.validator.as_ref())?;
+        result.aset(Symbol::new("removed_rows"), stats.removed_rows)?;
+        Ok(result)
+    }
+
+    // This sorter's side of a diff, read from the start of the sorted output without
+    // moving the output file's offset, which the sorter's other methods use
+    pub fn sorted_source(&self) -> Result<SortedSource, Error> {
+        let inner = self.inner()?;
+        let data = inner
+            .output_file
+            .try_clone()
+            .and_then(|file| {
+                let length = file.metadata()?.len();
+                Ok(FileSection::new(file, 0..length))
+            })
+            .map_err(|e| {
+                Error::new(
+                    magnus::exception::runtime_error(),
+                    format!("Failed to clone output file: {}", e),
+                )
+            })?;
+
+        Ok(SortedSource {
+            header: inner.sorted_file_header(),
+            data: Box::new(data),
+            compression: inner.run_config.compression,
+        })
+    }
+
+    // Compare the sorted output with earlier sorted output, e.g. the previous day's
+    // feed, yielding each added, removed or changed targeting key to the block or
+    // writing them to a CSV report. Neither side is loaded into memory.
+    pub fn diff(&self, args: &[Value]) -> Result<RHash, Error> {
+        diff_with(self.sorted_source()?, args, &self.cancel)
+    }
+
+    // Save the sorted output, with its key index and a header describing it, so that
+    // `SortedFile.open` can read it back later
+    pub fn save(&self, file_path: String) -> Result<(), Error> {
+        let inner = self.inner()?;
+
+        info!(target: "csv_utils::sorter", "Saving sorted file to {}", file_path);
+
+        let header = inner.sorted_file_header();
+
+        without_gvl(&self.cancel, || {
+            sorted_file::write(
+                Path::new(&file_path),
+                &header,
+                &inner.output_file,
+                &inner.key_index,
+            )
+        })
+        .and_then(|result| result.map_err(|e| phase_error("Error saving sorted file", e)))
+    }
+
+    // Merge this sorter's output into sorted output saved earlier by `save`, e.g. the
+    // previous day's full feed, writing the result as a new sorted file. Rows sorted
+    // here win over previous rows with the same key (`replace_by: :key`, the default) or
+    // the same key and position (`replace_by: :position`). Sorters with `dedup` can't
+    // upsert.
+    pub fn upsert(&self, args: &[Value]) -> Result<RHash, Error> {
+        let args = scan_args::<(String, String), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (previous_path, output_path) = args.required;
+        let (options,) = args.optional;
+
+        let replace_by = match options {
+            Some(options) => option_string(options, "replace_by")?,
+            None => None,
+        };
+        let replace_by = match replace_by {
+            Some(name) => ReplaceBy::from_name(&name).ok_or_else(|| {
+                error!(target: "csv_utils::sorter", "Invalid replace_by: {}", name);
+                Error::new(
+                    magnus::exception::arg_error(),
+                    format!("Invalid replace_by: {}", name),
+                )
+            })?,
+            None => ReplaceBy::Key,
+        };
+
+        // Creating the output would truncate the previous file while it is being read
+        let same_file = match (
+            std::fs::canonicalize(&previous_path),
+            std::fs::canonicalize(&output_path),
+        ) {
+            (Ok(previous), Ok(output)) => previous == output,
+            _ => false,
+        };
+        if same_file {
+            return Err(Error::new(
+                magnus::exception::arg_error(),
+                "upsert can't write over the file it reads from",
+            ));
+        }
+
+        let inner = self.inner()?;
+        // Rows are only paired up by key or position, so a strategy collapsing them
+        // would be silently ignored
+        if inner.dedup != DedupStrategy::All {
+            error!(target: "csv_utils::sorter", "upsert can't be combined with dedup");
+            return Err(Error::new(
+                magnus::exception::arg_error(),
+                "upsert doesn't support dedup; sort the previous and newer rows together instead",
+            ));
+        }
+
+        let mut header = inner.sorted_file_header();
+        let previous = SortedFile::open(previous_path.clone())?;
+        previous.check_compatible(&header)?;
+        let previous_data = previous.data_reader()?;
+
+        info!(
+            target: "csv_utils::sorter",
+            "Upserting into {} ({} rows) by {:?}, writing {}",
+            previous_path,
+            previous.row_count(),
+            replace_by,
+            output_path
+        );
+
+        let reporter = ProgressReporter::new(self.progress.get());
+        let mut tracker = reporter.phase(
+            "upsert",
+            Some(previous.row_count() + inner.total_rows as u64),
+            None,
+        );
+
+        let stats = reporter.result(
+            without_gvl(&self.cancel, || -> io::Result<_> {
+                let writer = SortedFileWriter::create(Path::new(&output_path), &header)?;
+                let mut output = UpsertOutput::new(
+                    writer.data(),
+                    inner.run_config.compression,
+                    inner.max_targeting_key_rows,
                 );
 
-                let args = RArray::new();
-                let _ = args.push(current_batch);
-                block.call::<_, Value>(args)?;
-                current_batch = RArray::new();
-                batch_count += 1;
-            }
+                let mut newer_data = &inner.output_file;
+                newer_data.rewind()?;
+                upsert(
+                    previous_data,
+                    previous.compression(),
+                    newer_data,
+                    inner.run_config.compression,
+                    replace_by,
+                    &mut output,
+                    &self.cancel,
+                    &mut tracker,
+                )?;
+
+                let (stats, index) = output.finish()?;
+                header.row_count = stats.total_rows;
+                header.record_bytes = stats.record_bytes;
+                writer.finish(header, &index)?;
+                Ok(stats)
+            })
+            .and_then(|result| result.map_err(|e| phase_error("Error upserting sorted file", e))),
+        )?;
 
-            last_key = target_key;
+        info!(
+            target: "csv_utils::sorter",
+            "Upsert wrote {} rows: {:?}",
+            stats.total_rows,
+            stats
+        );
+
+        let result = RHash::new();
+        result.aset(Symbol::new("total_rows"), stats.total_rows)?;
+        result.aset(Symbol::new("added_rows"), stats.added_rows)?;
+        result.aset(Symbol::new("replaced_rows"), stats.replaced_rows)?;
+        result.aset(Symbol::new("removed_rows"), stats.removed_rows)?;
+        result.aset(Symbol::new("kept_rows"), stats.kept_rows)?;
+        result.aset(Symbol::new("dropped_rows"), stats.dropped_rows)?;
+        Ok(result)
+    }
+
+    // Ask a running (or future) sort!, write_binary_postgres_file, copy_to_postgres or
+    // load_into_postgres to stop; safe to call from another Ruby thread
+    pub fn cancel(&self) {
+        info!(target: "csv_utils::sorter", "Cancellation requested");
+        self.cancel.cancel();
+    }
 
-            let item = RArray::new();
-            let key_hex = hex_string(&target_key);
-            let _ = item.push(key_hex);
-            let _ = item.push(record.record);
-            let _ = current_batch.push(item);
-            total_processed += 1;
+    // Register a block to receive progress reports, replacing any previous one
+    pub fn on_progress(&self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (options,) = args.optional;
+        let ruby = Ruby::get().unwrap();
+        let block = ruby.block_proc()?;
+
+        self.progress.set(Some(Progress::new(block, options)?));
+        info!(target: "csv_utils::sorter", "Progress reporting enabled");
+        Ok(())
+    }
+}
+
+// Yield sorted output to `block` as batches of `[hex_key, row]` pairs. A batch may run
+// past `batch_size` so that a key's rows are never split across batches.
+pub fn yield_batches<R: Read>(
+    reader: &mut RunReader<R>,
+    batch_size: usize,
+    block: Proc,
+    reporter: &ProgressReporter,
+    tracker: &mut ProgressTracker,
+) -> Result<(), Error> {
+    let mut bytes_read = 0;
+    let mut bytes = Vec::new();
+    let mut current_batch: RArray = RArray::new();
+    let mut last_key = TargetKey::default();
+    let mut total_processed = 0;
+    let mut rows_read = 0;
+    let mut batch_count = 0;
+
+    loop {
+        let has_record = reader
+            .read_record(&mut bytes)
+            .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+        if !has_record {
+            break; // EOF
         }
 
-        // Yield any remaining records
-        if !current_batch.is_empty() {
+        let record: SortRecord = bincode::decode_from_slice(&bytes, bincode::config::legacy())
+            .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?
+            .0;
+
+        rows_read += 1;
+        bytes_read += bytes.len() as u64;
+        reporter.result(
+            tracker
+                .update(rows_read, bytes_read)
+                .map_err(progress_error),
+        )?;
+
+        let target_key = record.key.value;
+
+        // If the batch is full, complete the target_key run and then start a new batch
+        if current_batch.len() >= batch_size && target_key != last_key {
             debug!(
                 target: "csv_utils::sorter",
-                "Yielding final batch with {} records",
+                "Yielding batch {} with {} records",
+                batch_count,
                 current_batch.len()
             );
 
             let args = RArray::new();
             let _ = args.push(current_batch);
             block.call::<_, Value>(args)?;
+            current_batch = RArray::new();
             batch_count += 1;
         }
 
-        info!(
+        last_key = target_key;
+
+        let item = RArray::new();
+        let key_hex = target_key.to_hex();
+        let _ = item.push(key_hex);
+        let _ = item.push(record.record);
+        let _ = current_batch.push(item);
+        total_processed += 1;
+    }
+
+    // Yield any remaining records
+    if !current_batch.is_empty() {
+        debug!(
             target: "csv_utils::sorter",
-            "Finished batch iteration: yielded {} batches with {} total records",
-            batch_count,
-            total_processed
+            "Yielding final batch with {} records",
+            current_batch.len()
         );
 
-        Ok(())
+        let args = RArray::new();
+        let _ = args.push(current_batch);
+        block.call::<_, Value>(args)?;
+        batch_count += 1;
     }
 
-    pub fn write_binary_postgres_file(&self, file_path: String) -> Result<(), Error> {
-        let inner = self.inner.borrow_mut();
-        let output_file_path = Path::new(&file_path);
+    reporter.result(
+        tracker
+            .finish(rows_read, bytes_read)
+            .map_err(progress_error),
+    )?;
 
-        info!(
-            target: "csv_utils::sorter",
-            "Writing binary PostgreSQL file to {}",
-            file_path
-        );
+    info!(
+        target: "csv_utils::sorter",
+        "Finished batch iteration: yielded {} batches with {} total records",
+        batch_count,
+        total_processed
+    );
 
-        // Input comes from previous step's output file
-        let mut input_file = inner.output_file.try_clone().map_err(|e| {
-            Error::new(
-                magnus::exception::runtime_error(),
-                format!("Failed to clone output file: {}", e),
-            )
-        })?;
-        input_file.rewind().map_err(|e| {
+    Ok(())
+}
+
+// Check that `lookup` was given a value for each key column
+pub fn check_key_values(key_values: &[String], key_column_count: usize) -> Result<(), Error> {
+    if key_values.len() != key_column_count {
+        return Err(Error::new(
+            magnus::exception::arg_error(),
+            format!(
+                "Expected {} key values, got {}",
+                key_column_count,
+                key_values.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Parse a hex targeting key given to `lookup_hex`
+pub fn parse_hex_key(hex: &str, algorithm: HashAlgorithm) -> Result<TargetKey, Error> {
+    TargetKey::from_hex(hex)
+        .filter(|key| key.as_slice().len() == algorithm.digest_len())
+        .ok_or_else(|| {
             Error::new(
-                magnus::exception::runtime_error(),
-                format!("Failed to rewind input file: {}", e),
+                magnus::exception::arg_error(),
+                format!("Invalid targeting key: {}", hex),
             )
-        })?;
+        })
+}
 
-        let mut copier =
-            PostgresCopier::new(input_file, inner.geo_columns, inner.source_key.clone())
-                .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+// `max_rows:` from the options hash given to `each_group`
+pub fn group_max_rows(args: &[Value]) -> Result<Option<usize>, Error> {
+    let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+    let (options,) = args.optional;
+    let max_rows = match options {
+        Some(options) => options.lookup::<_, Option<usize>>(Symbol::new("max_rows"))?,
+        None => None,
+    };
+    if max_rows == Some(0) {
+        error!(target: "csv_utils::sorter", "Invalid each_group max_rows: 0");
+        return Err(Error::new(
+            magnus::exception::arg_error(),
+            "max_rows must be at least 1",
+        ));
+    }
 
-        debug!(
-            target: "csv_utils::sorter",
-            "Created PostgreSQL copier for source key: {}",
-            inner.source_key
-        );
+    Ok(max_rows)
+}
 
-        copier
-            .copy(output_file_path)
+// Yield sorted output to `block` one targeting key at a time, as the hex key and up to
+// `max_rows` of its rows
+pub fn yield_groups<R: Read>(
+    reader: &mut RunReader<R>,
+    max_rows: Option<usize>,
+    block: Proc,
+    reporter: &ProgressReporter,
+    tracker: &mut ProgressTracker,
+) -> Result<(), Error> {
+    let mut bytes_read = 0;
+    let mut bytes = Vec::new();
+    let mut current_key: Option<TargetKey> = None;
+    let mut current_group = RArray::new();
+    let mut rows_read = 0;
+    let mut group_count = 0;
+    let mut skipped_rows = 0;
+
+    loop {
+        let has_record = reader
+            .read_record(&mut bytes)
             .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?;
+        if !has_record {
+            break; // EOF
+        }
 
-        info!(
-            target: "csv_utils::sorter",
-            "Successfully wrote PostgreSQL binary format file to {}",
-            file_path
-        );
+        let record: SortRecord = bincode::decode_from_slice(&bytes, bincode::config::legacy())
+            .map_err(|e| Error::new(magnus::exception::runtime_error(), e.to_string()))?
+            .0;
 
-        Ok(())
+        rows_read += 1;
+        bytes_read += bytes.len() as u64;
+        reporter.result(
+            tracker
+                .update(rows_read, bytes_read)
+                .map_err(progress_error),
+        )?;
+
+        let target_key = record.key.value;
+        if current_key != Some(target_key) {
+            if let Some(key) = current_key {
+                block.call::<_, Value>((key.to_hex(), current_group))?;
+                current_group = RArray::new();
+                group_count += 1;
+            }
+            current_key = Some(target_key);
+        }
+
+        if max_rows.is_some_and(|max_rows| current_group.len() >= max_rows) {
+            skipped_rows += 1;
+            continue;
+        }
+        current_group.push(record.record)?;
+    }
+
+    // Yield the last group
+    if let Some(key) = current_key {
+        block.call::<_, Value>((key.to_hex(), current_group))?;
+        group_count += 1;
+    }
+
+    reporter.result(
+        tracker
+            .finish(rows_read, bytes_read)
+            .map_err(progress_error),
+    )?;
+
+    info!(
+        target: "csv_utils::sorter",
+        "Finished group iteration: yielded {} groups, skipped {} rows over max_rows",
+        group_count,
+        skipped_rows
+    );
+
+    Ok(())
+}
+
+/// What `write_binary_postgres_file` and `copy_to_postgres` return
+pub fn copy_result(stats: &CopyStats, validator: Option<&Validator>) -> Result<RHash, Error> {
+    let result = RHash::new();
+    result.aset(Symbol::new("total_rows"), stats.rows)?;
+    result.aset(
+        Symbol::new("conversion_error_count"),
+        stats.conversion_errors,
+    )?;
+    if let Some(validator) = validator {
+        result.aset(Symbol::new("validation"), validator.status()?)?;
+    }
+    Ok(result)
+}
+
+fn sorter_busy() -> Error {
+    warn!(target: "csv_utils::sorter", "Sorter called while another call is using it");
+    Error::new(
+        sorter_busy_error(),
+        "Sorter is busy with another call, e.g. sort! on another thread",
+    )
+}
+
+// Phases abort with this when the progress block raised; ProgressReporter::result
+// replaces it with the block's own exception
+fn progress_error(e: io::Error) -> Error {
+    Error::new(magnus::exception::runtime_error(), e.to_string())
+}
+
+fn parse_csv_options(options: Option<RHash>) -> Result<CsvOptions, Error> {
+    match options {
+        Some(options) => CsvOptions::from_ruby(options),
+        None => Ok(CsvOptions::default()),
     }
 }
 
 pub fn register(ruby: &Ruby, module: &RModule) -> Result<(), Error> {
     let class = module.define_class("Sorter", ruby.class_object())?;
-    class.define_singleton_method("new", function!(Sorter::new, 5))?;
+    class.define_singleton_method("new", function!(Sorter::new, -1))?;
     class.define_method("enable_validation", method!(Sorter::enable_validation, 2))?;
     class.define_method("add_row", method!(Sorter::add_row, 2))?;
-    class.define_method("add_file", method!(Sorter::add_file, 1))?;
+    class.define_method("add_file", method!(Sorter::add_file, -1))?;
+    class.define_method("add_io", method!(Sorter::add_io, -1))?;
     class.define_method("sort!", method!(Sorter::sort, 0))?;
     class.define_method("each_batch", method!(Sorter::each_batch, 1))?;
+    class.define_method("each_group", method!(Sorter::each_group, -1))?;
+    class.define_method("lookup", method!(Sorter::lookup, 1))?;
+    class.define_method("lookup_hex", method!(Sorter::lookup_hex, 1))?;
     class.define_method(
         "write_binary_postgres_file",
-        method!(Sorter::write_binary_postgres_file, 1),
+        method!(Sorter::write_binary_postgres_file, -1),
+    )?;
+    class.define_method("copy_to_postgres", method!(Sorter::copy_to_postgres, -1))?;
+    class.define_method(
+        "load_into_postgres",
+        method!(Sorter::load_into_postgres, -1),
     )?;
+    class.define_method("save", method!(Sorter::save, 1))?;
+    class.define_method("upsert", method!(Sorter::upsert, -1))?;
+    class.define_method("diff", method!(Sorter::diff, -1))?;
+    class.define_method("cancel!", method!(Sorter::cancel, 0))?;
+    class.define_method("on_progress", method!(Sorter::on_progress, -1))?;
 
     Ok(())
 }
@@ -0,0 +1,215 @@
+use crate::dedup::DedupStrategy;
+use crate::ruby_options::option_string;
+use crate::run_file::RunCompression;
+use crate::sort_spec::SortSpec;
+use crate::targeting_key::{HashAlgorithm, KeyEncoding, MissingKeyColumns};
+use log::{debug, error};
+use magnus::{exception::arg_error, prelude::*, Error, RArray, RHash, Symbol, Value};
+use std::path::{Path, PathBuf};
+
+// Run files merged at once unless configured otherwise
+pub const DEFAULT_MERGE_FAN_IN: usize = 64;
+// Rows kept per targeting key unless configured otherwise
+pub const DEFAULT_MAX_TARGETING_KEY_ROWS: usize = 200;
+
+/// Optional settings accepted by `Sorter.new`
+#[derive(Debug, Clone)]
+pub struct SorterOptions {
+    pub compression: RunCompression,
+    pub merge_fan_in: usize,
+    // Threads used to sort each run, from a pool of the sorter's own when more than one
+    pub sort_threads: usize,
+    // Write run files on a background thread while rows keep being added
+    pub background_spill: bool,
+    // Hash applied to the key columns to build targeting keys
+    pub hash_algorithm: HashAlgorithm,
+    pub key_encoding: KeyEncoding,
+    pub missing_key_columns: MissingKeyColumns,
+    // Order rows by column values instead of by targeting key
+    pub sort_by: Option<SortSpec>,
+    // Rows kept per targeting key; the rest are dropped from the sorted output
+    pub max_targeting_key_rows: usize,
+    // How rows sharing a targeting key are collapsed in the sorted output
+    pub dedup: DedupStrategy,
+    // Directory for named run files and a manifest, so an interrupted sort can resume
+    pub work_dir: Option<String>,
+    // Directories for run files and the sorted output, used in turn; empty uses the
+    // system temp directory
+    pub spill_dirs: Vec<PathBuf>,
+    // Most bytes run files may take up at once
+    pub max_spill_bytes: Option<u64>,
+}
+
+impl Default for SorterOptions {
+    fn default() -> Self {
+        Self {
+            compression: RunCompression::None,
+            merge_fan_in: DEFAULT_MERGE_FAN_IN,
+            sort_threads: 1,
+            background_spill: false,
+            hash_algorithm: HashAlgorithm::Sha1,
+            key_encoding: KeyEncoding::Legacy,
+            missing_key_columns: MissingKeyColumns::Skip,
+            sort_by: None,
+            max_targeting_key_rows: DEFAULT_MAX_TARGETING_KEY_ROWS,
+            dedup: DedupStrategy::All,
+            work_dir: None,
+            spill_dirs: Vec::new(),
+            max_spill_bytes: None,
+        }
+    }
+}
+
+impl SorterOptions {
+    pub fn from_ruby(options: RHash) -> Result<Self, Error> {
+        let mut result = Self::default();
+
+        if let Some(compression) = option_string(options, "compression")? {
+            result.compression = RunCompression::from_name(&compression).ok_or_else(|| {
+                error!(target: "csv_utils::sorter", "Invalid compression: {}", compression);
+                Error::new(arg_error(), format!("Invalid compression: {}", compression))
+            })?;
+        }
+
+        if let Some(merge_fan_in) =
+            options.lookup::<_, Option<usize>>(Symbol::new("merge_fan_in"))?
+        {
+            if merge_fan_in < 2 {
+                error!(target: "csv_utils::sorter", "Invalid merge_fan_in: {}", merge_fan_in);
+                return Err(Error::new(
+                    arg_error(),
+                    format!("merge_fan_in must be at least 2, got {}", merge_fan_in),
+                ));
+            }
+            result.merge_fan_in = merge_fan_in;
+        }
+
+        if let Some(sort_threads) =
+            options.lookup::<_, Option<usize>>(Symbol::new("sort_threads"))?
+        {
+            if sort_threads == 0 {
+                error!(target: "csv_utils::sorter", "Invalid sort_threads: 0");
+                return Err(Error::new(arg_error(), "sort_threads must be at least 1"));
+            }
+            result.sort_threads = sort_threads;
+        }
+        if let Some(background_spill) =
+            options.lookup::<_, Option<bool>>(Symbol::new("background_spill"))?
+        {
+            result.background_spill = background_spill;
+        }
+
+        if let Some(hash) = option_string(options, "hash")? {
+            result.hash_algorithm = HashAlgorithm::from_name(&hash).ok_or_else(|| {
+                error!(target: "csv_utils::sorter", "Invalid hash algorithm: {}", hash);
+                Error::new(arg_error(), format!("Invalid hash algorithm: {}", hash))
+            })?;
+        }
+
+        if let Some(encoding) = option_string(options, "key_encoding")? {
+            result.key_encoding = KeyEncoding::from_name(&encoding).ok_or_else(|| {
+                error!(target: "csv_utils::sorter", "Invalid key encoding: {}", encoding);
+                Error::new(arg_error(), format!("Invalid key encoding: {}", encoding))
+            })?;
+        }
+
+        if let Some(policy) = option_string(options, "missing_key_columns")? {
+            result.missing_key_columns =
+                MissingKeyColumns::from_name(&policy).ok_or_else(|| {
+                    error!(target: "csv_utils::sorter", "Invalid missing_key_columns: {}", policy);
+                    Error::new(
+                        arg_error(),
+                        format!("Invalid missing_key_columns: {}", policy),
+                    )
+                })?;
+        }
+
+        let max_rows = options.lookup::<_, Option<usize>>(Symbol::new("max_targeting_key_rows"))?;
+        if let Some(max_rows) = max_rows {
+            if max_rows == 0 {
+                error!(target: "csv_utils::sorter", "Invalid max_targeting_key_rows: 0");
+                return Err(Error::new(
+                    arg_error(),
+                    "max_targeting_key_rows must be at least 1",
+                ));
+            }
+            result.max_targeting_key_rows = max_rows;
+        }
+
+        if let Some(sort_by) = options.lookup::<_, Option<RArray>>(Symbol::new("sort_by"))? {
+            result.sort_by = Some(SortSpec::from_ruby(sort_by)?);
+        }
+
+        if let Some(dedup) = option_string(options, "dedup")? {
+            result.dedup = DedupStrategy::from_name(&dedup).ok_or_else(|| {
+                error!(target: "csv_utils::sorter", "Invalid dedup strategy: {}", dedup);
+                Error::new(arg_error(), format!("Invalid dedup strategy: {}", dedup))
+            })?;
+        }
+
+        result.work_dir = options.lookup::<_, Option<String>>(Symbol::new("work_dir"))?;
+
+        // One directory or an array of them
+        if let Some(spill_dir) = options.lookup::<_, Option<Value>>(Symbol::new("spill_dir"))? {
+            let dirs = match RArray::from_value(spill_dir) {
+                Some(dirs) => dirs.to_vec::<String>()?,
+                None => vec![String::try_convert(spill_dir)?],
+            };
+            if dirs.is_empty() {
+                return Err(Error::new(arg_error(), "spill_dir must not be empty"));
+            }
+            for dir in dirs.iter() {
+                if !Path::new(dir).is_dir() {
+                    error!(target: "csv_utils::sorter", "Invalid spill_dir: {}", dir);
+                    return Err(Error::new(
+                        arg_error(),
+                        format!("spill_dir {} is not a directory", dir),
+                    ));
+                }
+            }
+            // work_dir keeps the run files itself, so they could never go here
+            if result.work_dir.is_some() {
+                return Err(Error::new(
+                    arg_error(),
+                    "Give either work_dir: or spill_dir:, not both",
+                ));
+            }
+            result.spill_dirs = dirs.into_iter().map(PathBuf::from).collect();
+        }
+
+        if let Some(max_spill_bytes) =
+            options.lookup::<_, Option<u64>>(Symbol::new("max_spill_bytes"))?
+        {
+            if max_spill_bytes == 0 {
+                error!(target: "csv_utils::sorter", "Invalid max_spill_bytes: 0");
+                return Err(Error::new(
+                    arg_error(),
+                    "max_spill_bytes must be at least 1",
+                ));
+            }
+            result.max_spill_bytes = Some(max_spill_bytes);
+        }
+
+        // Duplicates are only adjacent when rows are grouped by targeting key
+        if result.sort_by.is_some() && result.dedup != DedupStrategy::All {
+            error!(target: "csv_utils::sorter", "dedup can't be combined with sort_by");
+            return Err(Error::new(
+                arg_error(),
+                "dedup is only supported when sorting by targeting key",
+            ));
+        }
+
+        // The cap is per targeting key, which sort_by doesn't group rows by
+        if result.sort_by.is_some() && max_rows.is_some() {
+            error!(target: "csv_utils::sorter", "max_targeting_key_rows can't be combined with sort_by");
+            return Err(Error::new(
+                arg_error(),
+                "max_targeting_key_rows is only supported when sorting by targeting key",
+            ));
+        }
+
+        debug!(target: "csv_utils::sorter", "Using sorter options: {:?}", result);
+
+        Ok(result)
+    }
+}
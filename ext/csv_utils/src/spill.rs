@@ -0,0 +1,159 @@
+use log::{debug, error};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tempfile::{tempfile, tempfile_in};
+
+/// Kept inside the I/O error a run file write fails with once `max_spill_bytes` is
+/// reached, so `phase_error` can raise `CsvUtils::DiskLimitError` for it
+#[derive(Debug)]
+pub struct SpillLimitExceeded {
+    pub limit: u64,
+}
+
+impl fmt::Display for SpillLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "run files would take more than max_spill_bytes ({} bytes)",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for SpillLimitExceeded {}
+
+/// Bytes held by run files, shared with the threads writing them
+#[derive(Clone)]
+pub struct SpillUsage {
+    limit: Option<u64>,
+    used: Arc<AtomicU64>,
+}
+
+impl SpillUsage {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            used: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Charge for run files that are already on disk, e.g. those left in a working
+    /// directory. They count towards the limit but aren't refused.
+    pub fn existing(&self, bytes: u64) -> SpillCharge {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+        SpillCharge {
+            usage: self.clone(),
+            bytes,
+        }
+    }
+
+    fn charge(&self, bytes: u64) -> io::Result<()> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        match self.limit {
+            Some(limit) if used > limit => {
+                self.release(bytes);
+                error!(
+                    target: "csv_utils::spill",
+                    "Run files reached max_spill_bytes ({} bytes)",
+                    limit
+                );
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    SpillLimitExceeded { limit },
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Bytes a run file takes up, given back when the run is dropped and its file deleted
+pub struct SpillCharge {
+    usage: SpillUsage,
+    bytes: u64,
+}
+
+impl Drop for SpillCharge {
+    fn drop(&mut self) {
+        self.usage.release(self.bytes);
+    }
+}
+
+/// Writer for a run file that counts what it writes against `max_spill_bytes`
+pub struct SpillWriter<W: Write> {
+    inner: W,
+    charge: SpillCharge,
+}
+
+impl<W: Write> SpillWriter<W> {
+    pub fn new(inner: W, usage: &SpillUsage) -> Self {
+        Self {
+            inner,
+            charge: SpillCharge {
+                usage: usage.clone(),
+                bytes: 0,
+            },
+        }
+    }
+
+    pub fn into_charge(self) -> SpillCharge {
+        self.charge
+    }
+}
+
+impl<W: Write> Write for SpillWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Charge up front so the limit is hit before the disk fills, then refund
+        // whatever wasn't written
+        let length = buf.len() as u64;
+        self.charge.usage.charge(length)?;
+        match self.inner.write(buf) {
+            Ok(written) => {
+                self.charge.usage.release(length - written as u64);
+                self.charge.bytes += written as u64;
+                Ok(written)
+            }
+            Err(e) => {
+                self.charge.usage.release(length);
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Directories for run files and the sorted output, used in turn. With none configured
+/// the system temp directory is used.
+pub struct SpillDirs {
+    dirs: Vec<PathBuf>,
+    next: usize,
+}
+
+impl SpillDirs {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self { dirs, next: 0 }
+    }
+
+    /// Create an anonymous temp file in the next directory
+    pub fn tempfile(&mut self) -> io::Result<File> {
+        if self.dirs.is_empty() {
+            return tempfile();
+        }
+
+        let dir = &self.dirs[self.next % self.dirs.len()];
+        self.next += 1;
+        debug!(target: "csv_utils::spill", "Creating spill file in {}", dir.display());
+        tempfile_in(dir)
+    }
+}
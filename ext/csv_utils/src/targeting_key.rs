@@ -0,0 +1,249 @@
+use bincode::{
+    de::{read::Reader, Decoder},
+    enc::{write::Writer, Encoder},
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+use faster_hex::{hex_decode, hex_string};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use twox_hash::XxHash3_128;
+
+// Longest digest produced by any supported algorithm (SHA-256)
+const MAX_KEY_LENGTH: usize = 32;
+
+/// Hash used to turn a row's key columns into its targeting key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Xxh3_128,
+}
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "xxh3_128" => Some(HashAlgorithm::Xxh3_128),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxh3_128 => "xxh3_128",
+        }
+    }
+
+    /// Length in bytes of the algorithm's digests
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Xxh3_128 => 16,
+        }
+    }
+
+    /// Hash the encoded key columns of one row
+    pub fn digest(self, input: &[u8]) -> TargetKey {
+        match self {
+            HashAlgorithm::Sha1 => TargetKey::from_slice(&Sha1::digest(input)),
+            HashAlgorithm::Sha256 => TargetKey::from_slice(&Sha256::digest(input)),
+            // Big-endian, the canonical byte order for XXH128 digests
+            HashAlgorithm::Xxh3_128 => {
+                TargetKey::from_slice(&XxHash3_128::oneshot(input).to_be_bytes())
+            }
+        }
+    }
+}
+
+/// How the source id and key columns are laid out before hashing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEncoding {
+    // `source_id,value,value`: kept for existing keys, but `["a,b", "c"]` and
+    // `["a", "b,c"]` collide
+    Legacy,
+    // Every value is preceded by its length, so no two distinct rows share an encoding
+    LengthPrefixed,
+}
+
+impl KeyEncoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "legacy" => Some(KeyEncoding::Legacy),
+            "length_prefixed" => Some(KeyEncoding::LengthPrefixed),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyEncoding::Legacy => "legacy",
+            KeyEncoding::LengthPrefixed => "length_prefixed",
+        }
+    }
+
+    pub fn push_source_id(self, buf: &mut Vec<u8>, source_id: &str) {
+        if self == KeyEncoding::LengthPrefixed {
+            buf.extend_from_slice(&(source_id.len() as u32).to_le_bytes());
+        }
+        buf.extend_from_slice(source_id.as_bytes());
+    }
+
+    // Append a key column's value, or `None` for a column the row doesn't have
+    pub fn push_column(self, buf: &mut Vec<u8>, value: Option<&str>) {
+        match (self, value) {
+            (KeyEncoding::Legacy, Some(value)) => {
+                buf.push(b',');
+                buf.extend_from_slice(value.as_bytes());
+            }
+            (KeyEncoding::Legacy, None) => {}
+            (KeyEncoding::LengthPrefixed, Some(value)) => {
+                buf.push(1);
+                buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                buf.extend_from_slice(value.as_bytes());
+            }
+            (KeyEncoding::LengthPrefixed, None) => buf.push(0),
+        }
+    }
+}
+
+/// The targeting key of a row whose key columns hold `key_values`, in order
+pub fn key_for_values(
+    algorithm: HashAlgorithm,
+    encoding: KeyEncoding,
+    source_id: &str,
+    key_values: &[String],
+) -> TargetKey {
+    let mut buf = Vec::new();
+    encoding.push_source_id(&mut buf, source_id);
+    for value in key_values {
+        encoding.push_column(&mut buf, Some(value.as_str()));
+    }
+
+    algorithm.digest(&buf)
+}
+
+/// What to do with a row that is too short to have every key column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingKeyColumns {
+    // Leave the column out of the key (the original behavior)
+    Skip,
+    // Raise CsvUtils::MissingKeyColumnError
+    Error,
+    // Use an empty value for the column
+    Empty,
+    // Drop the row, logging it to the validator if validation is enabled
+    Reject,
+}
+
+impl MissingKeyColumns {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "skip" => Some(MissingKeyColumns::Skip),
+            "error" => Some(MissingKeyColumns::Error),
+            "empty" => Some(MissingKeyColumns::Empty),
+            "reject" => Some(MissingKeyColumns::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MissingKeyColumns::Skip => "skip",
+            MissingKeyColumns::Error => "error",
+            MissingKeyColumns::Empty => "empty",
+            MissingKeyColumns::Reject => "reject",
+        }
+    }
+}
+
+/// A targeting key digest, stored inline whatever the algorithm's output length.
+///
+/// Keys order by their bytes, so with any single algorithm the output is grouped by key
+/// exactly as it was with fixed-size SHA-1 keys. Run files store the length followed
+/// by the digest bytes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TargetKey {
+    bytes: [u8; MAX_KEY_LENGTH],
+    len: u8,
+}
+
+impl TargetKey {
+    pub fn from_slice(digest: &[u8]) -> Self {
+        let mut bytes = [0u8; MAX_KEY_LENGTH];
+        bytes[..digest.len()].copy_from_slice(digest);
+        Self {
+            bytes,
+            len: digest.len() as u8,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn to_hex(self) -> String {
+        hex_string(self.as_slice())
+    }
+
+    // Parse a hex digest as produced by `to_hex`, `None` if it isn't valid hex
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() % 2 != 0 || hex.len() > MAX_KEY_LENGTH * 2 {
+            return None;
+        }
+
+        let mut bytes = [0u8; MAX_KEY_LENGTH];
+        let len = hex.len() / 2;
+        hex_decode(hex.as_bytes(), &mut bytes[..len]).ok()?;
+        Some(Self {
+            bytes,
+            len: len as u8,
+        })
+    }
+}
+
+impl Ord for TargetKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+impl PartialOrd for TargetKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Encode for TargetKey {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.len.encode(encoder)?;
+        encoder.writer().write(self.as_slice())
+    }
+}
+
+impl<Context> Decode<Context> for TargetKey {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let len = u8::decode(decoder)? as usize;
+        if len > MAX_KEY_LENGTH {
+            return Err(DecodeError::OtherString(format!(
+                "Targeting key too long: {} bytes",
+                len
+            )));
+        }
+
+        let mut bytes = [0u8; MAX_KEY_LENGTH];
+        decoder.claim_bytes_read(len)?;
+        decoder.reader().read(&mut bytes[..len])?;
+        Ok(Self {
+            bytes,
+            len: len as u8,
+        })
+    }
+}
+
+bincode::impl_borrow_decode!(TargetKey);
@@ -0,0 +1,209 @@
+use crate::group_reader::{Group, GroupReader};
+use crate::gvl::CancelToken;
+use crate::key_index::KeyIndex;
+use crate::progress::ProgressTracker;
+use crate::row_limit::RowLimit;
+use crate::run_file::{RunCompression, RunWriter};
+use crate::sorter::KeyData;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Read};
+
+const BUFFER_CAPACITY: usize = 4 * 1024 * 1024;
+// Rows written between checks for cancellation
+const CANCEL_CHECK_INTERVAL: u64 = 10_000;
+
+/// Which previous rows a newer feed's rows replace when upserting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceBy {
+    // Every previous row of a targeting key the newer feed has
+    Key,
+    // Only previous rows with the same targeting key and position
+    Position,
+}
+
+impl ReplaceBy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "key" => Some(ReplaceBy::Key),
+            "position" => Some(ReplaceBy::Position),
+            _ => None,
+        }
+    }
+}
+
+/// Row counts reported by `Sorter#upsert`. Rows dropped by the row cap only count as
+/// dropped, so added, replaced and kept rows add up to the rows written.
+#[derive(Debug, Default)]
+pub struct UpsertStats {
+    // Newer rows with no previous row in their place
+    pub added_rows: u64,
+    // Newer rows that took the place of a previous row
+    pub replaced_rows: u64,
+    // Previous rows dropped without a newer row in their place
+    pub removed_rows: u64,
+    // Previous rows carried over unchanged
+    pub kept_rows: u64,
+    pub total_rows: u64,
+    pub record_bytes: u64,
+    // Rows dropped for exceeding max_targeting_key_rows
+    pub dropped_rows: u64,
+}
+
+/// Writes merged rows, applying the row cap and indexing the output
+pub struct UpsertOutput<'a> {
+    writer: RunWriter<&'a File>,
+    index: KeyIndex,
+    limit: RowLimit,
+    stats: UpsertStats,
+}
+
+impl<'a> UpsertOutput<'a> {
+    pub fn new(file: &'a File, compression: RunCompression, max_rows: usize) -> Self {
+        Self {
+            writer: RunWriter::new(file, compression, BUFFER_CAPACITY),
+            index: KeyIndex::default(),
+            limit: RowLimit::new(Some(max_rows)),
+            stats: UpsertStats::default(),
+        }
+    }
+
+    // Write a row unless the row cap drops it, returning whether it was written
+    fn write(&mut self, key: &KeyData, record_bytes: &[u8]) -> io::Result<bool> {
+        if !self.limit.admit(&key.value) {
+            self.stats.dropped_rows += 1;
+            return Ok(false);
+        }
+
+        self.index.record(&key.value, self.writer.entry_offset());
+        self.writer.write_entry(None, record_bytes)?;
+        self.stats.total_rows += 1;
+        self.stats.record_bytes += record_bytes.len() as u64;
+        Ok(true)
+    }
+
+    pub fn finish(self) -> io::Result<(UpsertStats, KeyIndex)> {
+        self.writer.finish()?;
+        Ok((self.stats, self.index))
+    }
+}
+
+/// Stream previous sorted output and a newer feed's sorted output, both in targeting key
+/// order, into `output`, with the newer rows winning as `replace_by` says
+#[allow(clippy::too_many_arguments)]
+pub fn upsert<P: Read, N: Read>(
+    previous: P,
+    previous_compression: RunCompression,
+    newer: N,
+    newer_compression: RunCompression,
+    replace_by: ReplaceBy,
+    output: &mut UpsertOutput,
+    cancel: &CancelToken,
+    progress: &mut ProgressTracker,
+) -> io::Result<()> {
+    let mut previous = GroupReader::new(previous, previous_compression, BUFFER_CAPACITY)?;
+    let mut newer = GroupReader::new(newer, newer_compression, BUFFER_CAPACITY)?;
+    let (mut rows, mut bytes) = (0, 0);
+
+    loop {
+        let order = match (previous.peek_key(), newer.peek_key()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(previous_key), Some(newer_key)) => previous_key.cmp(&newer_key),
+        };
+
+        let (previous_group, newer_group) = match order {
+            Ordering::Less => (previous.next_group()?, Vec::new()),
+            Ordering::Greater => (Vec::new(), newer.next_group()?),
+            Ordering::Equal => (previous.next_group()?, newer.next_group()?),
+        };
+
+        let group_rows = (previous_group.len() + newer_group.len()) as u64;
+        bytes += previous_group
+            .iter()
+            .chain(newer_group.iter())
+            .map(|(_, record_bytes)| record_bytes.len() as u64)
+            .sum::<u64>();
+
+        match replace_by {
+            ReplaceBy::Key => merge_by_key(previous_group, newer_group, output)?,
+            ReplaceBy::Position => merge_by_position(previous_group, newer_group, output)?,
+        }
+
+        if (rows + group_rows) / CANCEL_CHECK_INTERVAL > rows / CANCEL_CHECK_INTERVAL {
+            cancel.check()?;
+        }
+        rows += group_rows;
+        progress.update(rows, bytes)?;
+    }
+
+    progress.finish(rows, bytes)
+}
+
+// The newer rows replace every previous row of the key, pairing up in order, and the
+// previous rows left without a written newer row are removed
+fn merge_by_key(previous: Group, newer: Group, output: &mut UpsertOutput) -> io::Result<()> {
+    if newer.is_empty() {
+        for (key, record_bytes) in previous.iter() {
+            if output.write(key, record_bytes)? {
+                output.stats.kept_rows += 1;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut replaced = 0;
+    for (i, (key, record_bytes)) in newer.iter().enumerate() {
+        if !output.write(key, record_bytes)? {
+            continue;
+        }
+        if i < previous.len() {
+            replaced += 1;
+        } else {
+            output.stats.added_rows += 1;
+        }
+    }
+    output.stats.replaced_rows += replaced;
+    output.stats.removed_rows += previous.len() as u64 - replaced;
+    Ok(())
+}
+
+// Rows of a key are ordered by descending position, so the two groups are merged like
+// sorted lists, with a newer row replacing a previous one at the same position
+fn merge_by_position(previous: Group, newer: Group, output: &mut UpsertOutput) -> io::Result<()> {
+    let mut previous = previous.into_iter().peekable();
+    let mut newer = newer.into_iter().peekable();
+
+    loop {
+        let order = match (previous.peek(), newer.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((previous_key, _)), Some((newer_key, _))) => {
+                newer_key.position.cmp(&previous_key.position)
+            }
+        };
+
+        let (key, record_bytes) = match order {
+            Ordering::Less => previous.next().unwrap(),
+            Ordering::Greater => newer.next().unwrap(),
+            Ordering::Equal => {
+                previous.next();
+                newer.next().unwrap()
+            }
+        };
+
+        let written = output.write(&key, &record_bytes)?;
+        let stat = match (order, written) {
+            (Ordering::Less, true) => &mut output.stats.kept_rows,
+            (Ordering::Greater, true) => &mut output.stats.added_rows,
+            (Ordering::Equal, true) => &mut output.stats.replaced_rows,
+            // The previous row is gone with nothing written in its place
+            (Ordering::Equal, false) => &mut output.stats.removed_rows,
+            (_, false) => continue,
+        };
+        *stat += 1;
+    }
+    Ok(())
+}
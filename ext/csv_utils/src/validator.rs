@@ -2,10 +2,11 @@ use magnus::{
     exception::arg_error, function, method, prelude::*, Error, RArray, RHash, RModule, Ruby,
     Symbol, Value,
 };
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::error::Error as StdError;
 use std::fmt;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
 use url::Url;
 use log::{debug, error, info};
@@ -29,12 +30,14 @@ pub struct ValidationRule {
     validation_type: ValidationType,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 enum ValidationType {
-    Ignore,   // Ignore this column
-    Url,      // Validate as URL
-    Protocol, // Check if it contains ://
-    Invalid,  // Invalid validation type
+    Ignore,     // Ignore this column
+    Url,        // Validate as URL
+    Protocol,   // Check if it contains ://
+    Invalid,    // Invalid validation type
+    MissingKey, // Row lacks a key column; only reported by the sorter
+    Conversion, // Field doesn't parse as its PostgreSQL column type; only reported by the sorter
 }
 
 impl ValidationType {
@@ -56,10 +59,29 @@ pub struct Validator {
     pub failed_url_error_count: usize,
     pub failed_protocol_error_count: usize,
     pub parse_error_count: usize,
+    // Rows dropped by the sorter for lacking a key column
+    pub missing_key_error_count: usize,
+    // Fields written as NULL by `write_binary_postgres_file` because they didn't convert
+    pub conversion_error_count: usize,
     pub first_error_row: Option<usize>,
     first_error_type: Option<ValidationType>,
 }
 
+/// The counts for rows already written to run files, checkpointed in a sorter's working
+/// directory so a resumed sort still reports the rows it doesn't read again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidationCounts {
+    total_rows: usize,
+    failed_url_error_count: usize,
+    failed_protocol_error_count: usize,
+    parse_error_count: usize,
+    missing_key_error_count: usize,
+    first_error_row: Option<usize>,
+    first_error_type: Option<ValidationType>,
+    // Length of the error log, which holds the errors of exactly these rows
+    error_log_bytes: Option<u64>,
+}
+
 impl Validator {
     pub fn new(
         rules: Vec<ValidationRule>,
@@ -119,16 +141,55 @@ impl Validator {
             }
         };
 
-        Ok(Self {
+        Ok(Self::with_error_log(rules, error_log_file))
+    }
+
+    /// Carry on from the counts an earlier sorter checkpointed, appending to its error
+    /// log after the errors of the rows it checkpointed. The log is started afresh if
+    /// it's gone or shorter than it was.
+    pub fn resume(
+        rules: Vec<ValidationRule>,
+        error_log_path: String,
+        counts: &ValidationCounts,
+    ) -> Result<Self, ValidationError> {
+        let error_log_file = counts
+            .error_log_bytes
+            .and_then(|length| reopen_error_log(&error_log_path, length));
+
+        let mut validator = match error_log_file {
+            Some(file) => {
+                info!(
+                    target: "csv_utils::validator",
+                    "Resuming validation after {} rows, error log: {}",
+                    counts.total_rows, error_log_path
+                );
+                Self::with_error_log(rules, Some(file))
+            }
+            None => Self::new(rules, error_log_path)?,
+        };
+        validator.total_rows = counts.total_rows;
+        validator.failed_url_error_count = counts.failed_url_error_count;
+        validator.failed_protocol_error_count = counts.failed_protocol_error_count;
+        validator.parse_error_count = counts.parse_error_count;
+        validator.missing_key_error_count = counts.missing_key_error_count;
+        validator.first_error_row = counts.first_error_row;
+        validator.first_error_type = counts.first_error_type;
+        Ok(validator)
+    }
+
+    fn with_error_log(rules: Vec<ValidationRule>, error_log_file: Option<File>) -> Self {
+        Self {
             rules,
             error_log_file,
             total_rows: 0,
             failed_url_error_count: 0,
             failed_protocol_error_count: 0,
             parse_error_count: 0,
+            missing_key_error_count: 0,
+            conversion_error_count: 0,
             first_error_row: None,
             first_error_type: None,
-        })
+        }
     }
 
     pub fn add_error_to_file(
@@ -141,11 +202,15 @@ impl Validator {
         if self.failed_url_error_count > 5000
             || self.failed_protocol_error_count > 5000
             || self.parse_error_count > 5000
+            || self.missing_key_error_count > 5000
+            || self.conversion_error_count > 5000
         {
             // Stop logging errors if we have too many
             if self.failed_url_error_count == 5001 || 
                self.failed_protocol_error_count == 5001 || 
-               self.parse_error_count == 5001 {
+               self.parse_error_count == 5001 ||
+               self.missing_key_error_count == 5001 ||
+               self.conversion_error_count == 5001 {
                 info!(
                     target: "csv_utils::validator",
                     "Error count threshold reached, stopping detailed error logging"
@@ -156,7 +221,11 @@ impl Validator {
 
         if self.first_error_row.is_none() {
             self.first_error_row = Some(row_number);
-            self.first_error_type = Some(ValidationType::from_string(error_type));
+            self.first_error_type = Some(match error_type {
+                "missing_key" => ValidationType::MissingKey,
+                "conversion" => ValidationType::Conversion,
+                _ => ValidationType::from_string(error_type),
+            });
             info!(
                 target: "csv_utils::validator",
                 "First error detected: type={}, row={}", error_type, row_number
@@ -168,6 +237,8 @@ impl Validator {
                 "protocol" => format!("{} does not include a valid link protocol", column_name),
                 "url" => format!("{} does not include a valid domain", column_name),
                 "parse" => format!("{} could not be parsed", column_name),
+                "missing_key" => format!("{} is missing", column_name),
+                "conversion" => format!("{} could not be converted", column_name),
                 _ => {
                     error!(
                         target: "csv_utils::validator",
@@ -206,7 +277,9 @@ impl Validator {
             let field = &row[col_idx];
 
             match rule.validation_type {
-                ValidationType::Invalid => continue,
+                ValidationType::Invalid
+                | ValidationType::MissingKey
+                | ValidationType::Conversion => continue,
                 ValidationType::Ignore => continue,
                 ValidationType::Url => {
                     if !field.is_empty() && Url::parse(field).is_err() {
@@ -268,6 +341,23 @@ impl Validator {
         !failed_url && !failed_protocol
     }
 
+    pub fn counts(&self) -> ValidationCounts {
+        ValidationCounts {
+            total_rows: self.total_rows,
+            failed_url_error_count: self.failed_url_error_count,
+            failed_protocol_error_count: self.failed_protocol_error_count,
+            parse_error_count: self.parse_error_count,
+            missing_key_error_count: self.missing_key_error_count,
+            first_error_row: self.first_error_row,
+            first_error_type: self.first_error_type,
+            error_log_bytes: self
+                .error_log_file
+                .as_ref()
+                .and_then(|file| file.metadata().ok())
+                .map(|metadata| metadata.len()),
+        }
+    }
+
     pub fn first_error_message(&self) -> Option<String> {
         match self.first_error_type {
             Some(ValidationType::Url) => Some(format!(
@@ -281,6 +371,14 @@ impl Validator {
                 "Error parsing row: {}",
                 self.first_error_row.unwrap() + 1
             )),
+            Some(ValidationType::MissingKey) => Some(format!(
+                "Missing key column: {}",
+                self.first_error_row.unwrap() + 1
+            )),
+            Some(ValidationType::Conversion) => Some(format!(
+                "Invalid value: {}",
+                self.first_error_row.unwrap() + 1
+            )),
             _ => None,
         }
     }
@@ -290,7 +388,11 @@ impl Validator {
             target: "csv_utils::validator",
             "Validation completed: {} rows processed, {} errors found",
             self.total_rows,
-            self.failed_url_error_count + self.failed_protocol_error_count + self.parse_error_count
+            self.failed_url_error_count
+                + self.failed_protocol_error_count
+                + self.parse_error_count
+                + self.missing_key_error_count
+                + self.conversion_error_count
         );
         
         let status = RHash::new();
@@ -304,9 +406,21 @@ impl Validator {
             self.failed_protocol_error_count,
         )?;
         status.aset(Symbol::new("parse_error_count"), self.parse_error_count)?;
+        status.aset(
+            Symbol::new("missing_key_error_count"),
+            self.missing_key_error_count,
+        )?;
+        status.aset(
+            Symbol::new("conversion_error_count"),
+            self.conversion_error_count,
+        )?;
         status.aset(
             Symbol::new("error_count"),
-            self.failed_url_error_count + self.failed_protocol_error_count + self.parse_error_count,
+            self.failed_url_error_count
+                + self.failed_protocol_error_count
+                + self.parse_error_count
+                + self.missing_key_error_count
+                + self.conversion_error_count,
         )?;
         if let Some(first_error_row) = self.first_error_row {
             status.aset(Symbol::new("first_error_row"), first_error_row)?;
@@ -319,6 +433,17 @@ impl Validator {
     }
 }
 
+// Open the error log to append after its first `length` bytes, dropping errors logged
+// for rows that weren't checkpointed; `None` if it no longer holds that much
+fn reopen_error_log(path: &str, length: u64) -> Option<File> {
+    let file = OpenOptions::new().append(true).open(path).ok()?;
+    if file.metadata().ok()?.len() < length {
+        return None;
+    }
+    file.set_len(length).ok()?;
+    Some(file)
+}
+
 pub fn ruby_rules_array_to_rules(rules: RArray) -> Result<Vec<ValidationRule>, Error> {
     info!(
         target: "csv_utils::validator",
@@ -0,0 +1,381 @@
+use crate::errors::work_dir_error;
+use crate::sort_spec::SortSpec;
+use crate::validator::ValidationCounts;
+use log::{debug, info, warn};
+use magnus::Error;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_NAME: &str = "manifest.json";
+const LOCK_NAME: &str = "lock";
+// Bumped whenever the manifest or run files change incompatibly
+const MANIFEST_VERSION: u32 = 2;
+const RUN_PREFIX: &str = "run-";
+const RUN_SUFFIX: &str = ".run";
+
+/// Sorter settings that decide what a run file holds and how runs are merged. Runs from
+/// a working directory are only reused by a sorter with the same settings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WorkSettings {
+    pub source_id: String,
+    pub key_columns: Vec<usize>,
+    pub hash_algorithm: String,
+    pub key_encoding: String,
+    pub missing_key_columns: String,
+    pub compression: String,
+    pub sort_by: Option<SortSpec>,
+    pub dedup: String,
+    pub max_targeting_key_rows: usize,
+}
+
+/// A run file in the working directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunEntry {
+    pub name: String,
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+/// How far `add_file` got through an input file: the byte offset (in the decompressed
+/// stream) and position of the first row not yet in a run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputProgress {
+    pub path: String,
+    pub byte_offset: u64,
+    pub position: usize,
+    // Every row of the file is in a run
+    pub complete: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u32,
+    settings: WorkSettings,
+    runs: Vec<RunEntry>,
+    inputs: Vec<InputProgress>,
+    // Validation counts for the rows in the runs, when validation is enabled
+    #[serde(default)]
+    validation: Option<ValidationCounts>,
+}
+
+/// Named directory holding a sorter's run files and a manifest describing them, so a
+/// new sorter can pick up where a killed one left off.
+///
+/// The manifest is rewritten each time a run file is finished. It lists the runs and,
+/// for each input file, how far ingestion had got when the newest run was cut, so rows
+/// that were still in memory are read again on resume and nothing is read twice.
+///
+/// The directory is locked for as long as the sorter using it is alive.
+pub struct WorkDir {
+    path: PathBuf,
+    _lock: File,
+    manifest: Manifest,
+    // Input progress including rows still in memory
+    live_inputs: Vec<InputProgress>,
+    current_input: Option<usize>,
+    // `live_inputs` as of the run being written, committed along with it
+    pending_inputs: Option<Vec<InputProgress>>,
+    pending_validation: Option<ValidationCounts>,
+    next_run: usize,
+}
+
+impl WorkDir {
+    pub fn open(path: &str, settings: WorkSettings) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        let io_error = |e: io::Error| {
+            Error::new(
+                work_dir_error(),
+                format!("Working directory {}: {}", path.display(), e),
+            )
+        };
+
+        fs::create_dir_all(&path).map_err(io_error)?;
+        let lock = lock(&path).map_err(io_error)?.ok_or_else(|| {
+            Error::new(
+                work_dir_error(),
+                format!(
+                    "Working directory {} is in use by another sorter",
+                    path.display()
+                ),
+            )
+        })?;
+        let manifest = match fs::read(path.join(MANIFEST_NAME)) {
+            Ok(json) => {
+                let manifest: Manifest = serde_json::from_slice(&json)
+                    .map_err(|e| io_error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+                check_manifest(&path, &manifest, &settings)?;
+                info!(
+                    target: "csv_utils::work_dir",
+                    "Resuming from {} with {} run files",
+                    path.display(),
+                    manifest.runs.len()
+                );
+                manifest
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest {
+                version: MANIFEST_VERSION,
+                settings,
+                runs: Vec::new(),
+                inputs: Vec::new(),
+                validation: None,
+            },
+            Err(e) => return Err(io_error(e)),
+        };
+
+        let mut work_dir = Self {
+            path: path.clone(),
+            _lock: lock,
+            live_inputs: manifest.inputs.clone(),
+            manifest,
+            current_input: None,
+            pending_inputs: None,
+            pending_validation: None,
+            next_run: 1,
+        };
+        work_dir.remove_orphans().map_err(io_error)?;
+        Ok(work_dir)
+    }
+
+    // Delete run files the manifest doesn't list, left by a sorter killed while writing
+    // them, and continue numbering after the highest run kept
+    fn remove_orphans(&mut self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.path)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let number = match name
+                .strip_prefix(RUN_PREFIX)
+                .and_then(|rest| rest.strip_suffix(RUN_SUFFIX))
+                .and_then(|number| number.parse::<usize>().ok())
+            {
+                Some(number) => number,
+                None => continue,
+            };
+
+            if self.manifest.runs.iter().any(|run| run.name == name) {
+                self.next_run = self.next_run.max(number + 1);
+            } else {
+                debug!(target: "csv_utils::work_dir", "Removing unfinished run file {}", name);
+                fs::remove_file(self.path.join(&name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the run files left by an earlier sorter
+    pub fn open_runs(&self) -> Result<Vec<(File, RunEntry)>, Error> {
+        self.manifest
+            .runs
+            .iter()
+            .map(|run| {
+                let file = File::open(self.path.join(&run.name)).map_err(|e| {
+                    Error::new(
+                        work_dir_error(),
+                        format!("Run file {} listed in the manifest: {}", run.name, e),
+                    )
+                })?;
+                Ok((file, run.clone()))
+            })
+            .collect()
+    }
+
+    /// How far an earlier sorter got through `path`, as of its last run
+    pub fn input(&self, path: &str) -> Option<&InputProgress> {
+        let path = input_path(path);
+        self.manifest.inputs.iter().find(|input| input.path == path)
+    }
+
+    /// Validation counts for the rows in the runs, as of the last run
+    pub fn validation(&self) -> Option<&ValidationCounts> {
+        self.manifest.validation.as_ref()
+    }
+
+    /// Start tracking rows read from `path`, from `byte_offset` and `position`
+    pub fn begin_input(&mut self, path: &str, byte_offset: u64, position: usize) {
+        let path = input_path(path);
+        let progress = InputProgress {
+            path: path.clone(),
+            byte_offset,
+            position,
+            complete: false,
+        };
+        let index = match self.live_inputs.iter().position(|input| input.path == path) {
+            Some(index) => {
+                self.live_inputs[index] = progress;
+                index
+            }
+            None => {
+                self.live_inputs.push(progress);
+                self.live_inputs.len() - 1
+            }
+        };
+        self.current_input = Some(index);
+    }
+
+    /// Record that the next row of the current input starts at `byte_offset`
+    pub fn advance_input(&mut self, byte_offset: u64, position: usize, complete: bool) {
+        if let Some(input) = self.current_input.map(|i| &mut self.live_inputs[i]) {
+            input.byte_offset = byte_offset;
+            input.position = position;
+            input.complete = complete;
+        }
+        if complete {
+            self.current_input = None;
+        }
+    }
+
+    /// Create the next run file, remembering the input progress and validation counts
+    /// it will cover
+    pub fn create_run(
+        &mut self,
+        validation: Option<ValidationCounts>,
+    ) -> io::Result<(File, String)> {
+        self.pending_inputs = Some(self.live_inputs.clone());
+        self.pending_validation = validation;
+        self.create_run_file()
+    }
+
+    /// Create a run file for an intermediate merge, which doesn't change input progress
+    pub fn create_merged_run(&mut self) -> io::Result<(File, String)> {
+        self.create_run_file()
+    }
+
+    fn create_run_file(&mut self) -> io::Result<(File, String)> {
+        let name = format!("{}{:06}{}", RUN_PREFIX, self.next_run, RUN_SUFFIX);
+        self.next_run += 1;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(self.path.join(&name))?;
+        Ok((file, name))
+    }
+
+    /// Add a finished run file from `create_run` to the manifest
+    pub fn commit_run(&mut self, file: &File, run: RunEntry) -> io::Result<()> {
+        file.sync_all()?;
+        self.manifest.runs.push(run);
+        if let Some(inputs) = self.pending_inputs.take() {
+            self.manifest.inputs = inputs;
+            self.manifest.validation = self.pending_validation.take();
+        }
+        self.save()
+    }
+
+    /// Swap runs merged in an intermediate pass for the run they were merged into
+    pub fn replace_runs(
+        &mut self,
+        merged: &[String],
+        file: &File,
+        run: RunEntry,
+    ) -> io::Result<()> {
+        file.sync_all()?;
+        self.manifest
+            .runs
+            .retain(|entry| !merged.contains(&entry.name));
+        self.manifest.runs.push(run);
+        self.save()?;
+
+        for name in merged {
+            fs::remove_file(self.path.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the run files and manifest once they have been merged into the output
+    pub fn finish(&mut self) -> io::Result<()> {
+        match fs::remove_file(self.path.join(MANIFEST_NAME)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for run in self.manifest.runs.drain(..) {
+            if let Err(e) = fs::remove_file(self.path.join(&run.name)) {
+                warn!(target: "csv_utils::work_dir", "Failed to remove {}: {}", run.name, e);
+            }
+        }
+        self.manifest.inputs.clear();
+        self.manifest.validation = None;
+        self.live_inputs.clear();
+        Ok(())
+    }
+
+    // Replace the manifest atomically, so a kill leaves either the old or the new one
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.manifest)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let temp_path = self.path.join(format!("{}.tmp", MANIFEST_NAME));
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, self.path.join(MANIFEST_NAME))?;
+
+        debug!(
+            target: "csv_utils::work_dir",
+            "Saved manifest with {} run files",
+            self.manifest.runs.len()
+        );
+        Ok(())
+    }
+}
+
+// Take the directory's lock file, or `None` when another sorter holds it. The file is
+// left in place, since removing it would let two sorters lock different files.
+fn lock(path: &Path) -> io::Result<Option<File>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(LOCK_NAME))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+// Inputs are matched by canonical path, so `./x` and `x` are the same file. A path that
+// can't be resolved is kept as given.
+fn input_path(path: &str) -> String {
+    fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+fn check_manifest(path: &Path, manifest: &Manifest, settings: &WorkSettings) -> Result<(), Error> {
+    let theirs = &manifest.settings;
+    let mismatch = if manifest.version != MANIFEST_VERSION {
+        Some("manifest version")
+    } else if theirs.source_id != settings.source_id {
+        Some("source id")
+    } else if theirs.key_columns != settings.key_columns {
+        Some("key columns")
+    } else if theirs.hash_algorithm != settings.hash_algorithm {
+        Some("hash")
+    } else if theirs.key_encoding != settings.key_encoding {
+        Some("key encoding")
+    } else if theirs.missing_key_columns != settings.missing_key_columns {
+        Some("missing key column policy")
+    } else if theirs.compression != settings.compression {
+        Some("compression")
+    } else if theirs.sort_by != settings.sort_by {
+        Some("sort order (sort_by)")
+    } else if theirs.dedup != settings.dedup {
+        Some("dedup strategy")
+    } else if theirs.max_targeting_key_rows != settings.max_targeting_key_rows {
+        Some("max_targeting_key_rows")
+    } else {
+        None
+    };
+
+    match mismatch {
+        Some(field) => Err(Error::new(
+            work_dir_error(),
+            format!(
+                "Working directory {} was used by a sorter with a different {}",
+                path.display(),
+                field
+            ),
+        )),
+        None => Ok(()),
+    }
+}
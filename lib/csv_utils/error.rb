@@ -1,3 +1,36 @@
 module CsvUtils
   class Error < StandardError; end
+
+  # Raised when a sort or write is cancelled, either with Sorter#cancel! or by a Ruby
+  # thread interrupt such as Thread#kill or Timeout
+  class CancelledError < Error; end
+
+  # Raised by Sorter#add_row (and add_file/add_io) for a row missing one of the key
+  # columns, when the sorter was created with missing_key_columns: :error
+  class MissingKeyColumnError < Error; end
+
+  # Raised when a Sorter is called while another call is still using it, e.g. add_row
+  # from another thread during sort!, or a sorter method from an each_batch block
+  class SorterBusyError < Error; end
+
+  # Raised by SortedFile.open for a file that wasn't written by Sorter#save, or was
+  # written with an unsupported format version
+  class SortedFileError < Error; end
+
+  # Raised by BinaryCopyFile for a file that isn't in PostgreSQL's binary COPY format, is
+  # truncated, or holds fields that don't match the column types it was opened with
+  class BinaryCopyFileError < Error; end
+
+  # Raised by Sorter.new when the work_dir can't be used: its manifest is unreadable, a
+  # run file it lists is missing, or it was written by a sorter with different settings
+  class WorkDirError < Error; end
+
+  # Raised while sorting when run files would take more than the sorter's
+  # max_spill_bytes, or the spill directory's disk is full
+  class DiskLimitError < Error; end
+
+  # Raised by Sorter#copy_to_postgres and #load_into_postgres when they can't connect,
+  # or the server rejects a statement (including COPY rows that don't fit the table) or
+  # cancels it after the statement_timeout
+  class PostgresError < Error; end
 end
@@ -0,0 +1,131 @@
+# frozen_string_literal: true
+
+require "csv_utils"
+require "csv"
+require "tempfile"
+
+RSpec.describe CsvUtils::BinaryCopyFile do
+  let(:source_id) { "1" }
+  let(:source_key) { "12345abcdef" }
+  let(:path) { Tempfile.new("copy").path }
+  let(:csv_path) { Tempfile.new("csv").path }
+
+  def sorted(*rows, geo_columns: nil)
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], geo_columns, 100)
+    rows.each_with_index { |row, index| sorter.add_row(row, index) }
+    sorter.sort!
+    sorter
+  end
+
+  it "reads back the default layout" do
+    sorted(["1", "hello", "-74.006", "40.7128"], geo_columns: [2, 3]).write_binary_postgres_file(path)
+
+    rows = described_class.open(path).to_a
+    expect(rows).to match([[
+                            source_key,
+                            "a6322dae6eb1e2bf764a57fa0b8671cf570b546d",
+                            "SRID=4326;POINT(40.7128 -74.006)",
+                            ["1", "hello", "-74.006", "40.7128"],
+                            an_instance_of(Time),
+                            an_instance_of(Time)
+                          ]])
+  end
+
+  it "reads typed columns given the columns they were written with" do
+    columns = [
+      { value: :position },
+      { column: 1, type: :int4 },
+      { column: 2, type: :numeric },
+      { column: 2, type: :float8 },
+      { column: 3, type: :bool },
+      { column: 4, type: :date, format: "%m/%d/%Y" },
+      { column: 5, type: :jsonb },
+      { column: 6, type: :uuid },
+      { column: 7, type: :timestamptz },
+      { value: :target_key, type: :bytea }
+    ]
+    sorted(["a", "42", "-12.50", "yes", "03/02/2000", "{\"n\": 1}",
+            "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11", "2000-01-01T00:00:01Z"],
+           ["b", "", "x"]).write_binary_postgres_file(path, columns: columns)
+
+    rows = described_class.open(path, columns: columns).to_a
+    expect(rows).to eq([
+                         [0, 42, "-12.50", -12.5, true, "2000-03-02", "{\"n\": 1}",
+                          "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11", Time.utc(2000, 1, 1, 0, 0, 1),
+                          ["1b203a064753792c0af618c5dd5b6ba19a02bbc7"].pack("H*")],
+                         [1, nil, nil, nil, nil, nil, nil, nil, nil,
+                          ["73fba28284279b75d6e3b0cea5805fd69e93180f"].pack("H*")]
+                       ])
+  end
+
+  it "takes the column types by name" do
+    sorted(%w[a 1], %w[b 2]).write_binary_postgres_file(path, columns: [{ column: 0 }, { column: 1, type: :int8 }])
+
+    file = described_class.open(path, types: %i[varchar int8])
+    expect(file.map { |key, value| [key, value] }).to eq([["a", 1], ["b", 2]])
+    expect(file.first).to eq(["a", 1])
+  end
+
+  it "converts the file to CSV" do
+    columns = [{ column: 0 }, { column: 1, type: :numeric }, { value: :record }]
+    sorted(["a", "1.5", "x,y"], ["b", "", "\"q\""]).write_binary_postgres_file(path, columns: columns)
+
+    file = described_class.open(path, columns: columns)
+    result = file.to_csv(csv_path, headers: %w[key amount record], null: "NULL")
+    expect(result).to eq(total_rows: 2)
+    expect(CSV.read(csv_path)).to eq([
+                                       %w[key amount record],
+                                       ["a", "1.5", '{a,1.5,"x,y"}'],
+                                       ["b", "NULL", '{b,"","\"q\""}']
+                                     ])
+  end
+
+  it "writes floats and arrays the way PostgreSQL does" do
+    columns = [{ column: 0, type: :float8 }, { value: :record }]
+    sorted(["1e15", "a b"], %w[0.00001 NULL], %w[0.1 plain]).write_binary_postgres_file(path, columns: columns)
+
+    described_class.open(path, columns: columns).to_csv(csv_path)
+    expect(CSV.read(csv_path)).to contain_exactly(
+      ["1e+15", '{1e15,"a b"}'],
+      ["1e-05", '{0.00001,"NULL"}'],
+      ["0.1", "{0.1,plain}"]
+    )
+  end
+
+  it "rejects files that aren't binary COPY data" do
+    File.write(path, "key,value\n")
+    expect { described_class.open(path) }.to raise_error(CsvUtils::BinaryCopyFileError, /not a binary COPY file/)
+  end
+
+  it "rejects rows that don't match the column types" do
+    sorted(%w[a 1]).write_binary_postgres_file(path, columns: [{ column: 0 }, { column: 1 }])
+    expect { described_class.open(path, types: %i[text]).to_a }
+      .to raise_error(CsvUtils::BinaryCopyFileError, /row has 2 fields, expected 1/)
+    expect { described_class.open(path, types: %i[text int8]).to_a }
+      .to raise_error(CsvUtils::BinaryCopyFileError, /invalid int8/)
+  end
+
+  it "rejects truncated files" do
+    sorted(%w[a 1]).write_binary_postgres_file(path)
+    File.truncate(path, File.size(path) - 10)
+    expect { described_class.open(path).to_csv(csv_path) }.to raise_error(CsvUtils::BinaryCopyFileError)
+  end
+
+  it "rejects field lengths past the end of the file" do
+    File.binwrite(path, "PGCOPY\n\xFF\r\n\0".b + [0, 0].pack("N2") + [1].pack("n") + [0x7fffffff].pack("N") + "abc")
+    expect { described_class.open(path, types: %i[text]).to_a }.to raise_error(CsvUtils::BinaryCopyFileError)
+  end
+
+  it "rejects numerics with an unknown sign" do
+    numeric = [1, 0, 0x1234, 0, 5].pack("s>s>S>S>s>")
+    File.binwrite(path, "PGCOPY\n\xFF\r\n\0".b + [0, 0].pack("N2") + [1, numeric.bytesize].pack("nN") + numeric +
+                        [-1].pack("s>"))
+    expect { described_class.open(path, types: %i[numeric]).to_a }
+      .to raise_error(CsvUtils::BinaryCopyFileError, /invalid numeric sign 0x1234/)
+  end
+
+  it "rejects unknown types" do
+    sorted(%w[a 1]).write_binary_postgres_file(path)
+    expect { described_class.open(path, types: %i[money]) }.to raise_error(ArgumentError, /Invalid type: money/)
+  end
+end
@@ -0,0 +1,274 @@
+# frozen_string_literal: true
+
+require "csv_utils"
+require "csv"
+require "tempfile"
+require "activerecord-copy"
+
+RSpec.describe CsvUtils::SortedFile do
+  let(:source_id) { "1" }
+  let(:source_key) { "12345abcdef" }
+  let(:path) { Tempfile.new("sorted").path }
+
+  def saved_sorter(buffer_size_mb: 100, rows: 10, **options)
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, buffer_size_mb, **options)
+    rows.times { |i| sorter.add_row(["id-#{i % 7}", "t", i.to_s, "x" * 50], i) }
+    sorter.sort!
+    sorter.save(path)
+    sorter
+  end
+
+  def feed(rows, **options)
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100, **options)
+    rows.each_with_index { |row, i| sorter.add_row(row, i) }
+    sorter.sort!
+    sorter
+  end
+
+  def batches(source)
+    rows = []
+    source.each_batch(100) { |batch| rows.concat(batch) }
+    rows
+  end
+
+  it "records how the file was sorted" do
+    saved_sorter(hash: :sha256, compression: :lz4)
+    header = described_class.open(path).header
+    expect(header).to include(
+      format_version: 1,
+      source_id: source_id,
+      source_key: source_key,
+      key_columns: [0, 1],
+      geo_columns: nil,
+      hash: :sha256,
+      key_encoding: :legacy,
+      compression: :lz4,
+      sorted_by_value: false,
+      row_count: 10
+    )
+  end
+
+  it "iterates the same rows as the sorter" do
+    sorter = saved_sorter
+    sorted_file = described_class.open(path)
+    expect(sorted_file.row_count).to eq(10)
+    expect(batches(sorted_file)).to eq(batches(sorter))
+
+    groups = []
+    sorted_file.each_group { |key, rows| groups << [key, rows.size] }
+    expect(groups.size).to eq(7)
+  end
+
+  [nil, :zstd].each do |compression|
+    it "looks up keys after reopening#{" with #{compression}" if compression}" do
+      sorter = saved_sorter(buffer_size_mb: 1, rows: 30_000, compression: compression)
+      sorted_file = described_class.open(path)
+
+      expect(sorted_file.lookup(%w[id-3 t])).to eq(sorter.lookup(%w[id-3 t]))
+      expect(sorted_file.lookup(%w[id-3 t]).size).to eq(4286)
+      hex = batches(sorter).last.first
+      expect(sorted_file.lookup_hex(hex)).to eq(sorter.lookup_hex(hex))
+      expect(sorted_file.lookup(%w[id-9 t])).to eq([])
+    end
+  end
+
+  it "writes a binary postgres file" do
+    saved_sorter
+    outfile_path = Tempfile.new.path
+    described_class.open(path).write_binary_postgres_file(outfile_path)
+
+    decoder = ActiveRecordCopy::Decoder.new(file: outfile_path,
+                                            column_types: %i[text text bytea character[] timestamp timestamp])
+    results = []
+    decoder.each { |result| results << result }
+    expect(results.size).to eq(10)
+    expect(results.map(&:first).uniq).to eq([source_key])
+  end
+
+  it "writes a binary postgres file with the columns given" do
+    saved_sorter
+    outfile_path = Tempfile.new.path
+    described_class.open(path).write_binary_postgres_file(outfile_path,
+                                                          columns: [{ column: 2 }, { value: :position, type: :int4 }])
+
+    decoder = ActiveRecordCopy::Decoder.new(file: outfile_path, column_types: %i[text integer])
+    results = []
+    decoder.each { |result| results << result }
+    expect(results.size).to eq(10)
+    results.each { |value, position| expect(value).to eq(position.to_s) }
+  end
+
+  it "reports progress and can be cancelled" do
+    saved_sorter(rows: 30_000)
+    sorted_file = described_class.open(path)
+    phases = []
+    sorted_file.on_progress(every_rows: 10_000) do |progress|
+      phases << progress[:phase]
+      sorted_file.cancel! if progress[:phase] == :write_binary_postgres_file
+    end
+
+    sorted_file.each_batch(1000) { |_batch| nil }
+    expect { sorted_file.write_binary_postgres_file(Tempfile.new.path) }.to raise_error(CsvUtils::CancelledError)
+    expect(phases.uniq).to eq(%i[each_batch write_binary_postgres_file])
+  end
+
+  it "keeps the progress block alive through garbage collection" do
+    saved_sorter(rows: 30_000)
+    sorted_file = described_class.open(path)
+    phases = []
+    # Only the sorted file holds on to the block
+    sorted_file.on_progress(every_rows: 10_000) { |progress| phases << progress[:phase] }
+    GC.start
+    GC.compact if GC.respond_to?(:compact)
+
+    expect(batches(sorted_file).size).to eq(30_000)
+    expect(phases).to include(:each_batch)
+  end
+
+  describe "upsert" do
+    let(:output_path) { Tempfile.new("upserted").path }
+
+    def rows_by_key(sorted_file)
+      rows = Hash.new { |hash, key| hash[key] = [] }
+      sorted_file.each_batch(100) { |batch| batch.each { |_key, row| rows[row[0]] << row[1] } }
+      rows
+    end
+
+    before do
+      feed([%w[a 1], %w[a 2], %w[b 1], %w[c 1]]).save(path)
+    end
+
+    it "replaces every previous row of a key by default" do
+      result = feed([%w[a 3], %w[d 1]]).upsert(path, output_path)
+      expect(result).to include(total_rows: 4, added_rows: 1, replaced_rows: 1, removed_rows: 1, kept_rows: 2)
+
+      upserted = described_class.open(output_path)
+      expect(upserted.row_count).to eq(4)
+      expect(rows_by_key(upserted)).to eq("a" => %w[3], "b" => %w[1], "c" => %w[1], "d" => %w[1])
+      expect(upserted.lookup(%w[a])).to eq([%w[a 3]])
+    end
+
+    it "replaces rows by key and position" do
+      result = feed([%w[a 3], %w[d 1], %w[a 4]]).upsert(path, output_path, replace_by: :position)
+      expect(result).to include(total_rows: 6, added_rows: 2, replaced_rows: 1, removed_rows: 0, kept_rows: 3)
+      # a3 takes a1's position, a4 is new and a2 is kept
+      expect(rows_by_key(described_class.open(output_path))["a"]).to eq(%w[4 2 3])
+    end
+
+    it "counts rows dropped by max_targeting_key_rows as dropped only" do
+      newer = feed([%w[a 3], %w[d 1]], max_targeting_key_rows: 1)
+      result = newer.upsert(path, output_path, replace_by: :position)
+      # a2 is kept, a3 is dropped by the cap, so a1 is removed with nothing in its place
+      expect(result).to eq(total_rows: 4, added_rows: 1, replaced_rows: 0, removed_rows: 1, kept_rows: 3,
+                            dropped_rows: 1)
+      expect(rows_by_key(described_class.open(output_path))["a"]).to eq(%w[2])
+    end
+
+    it "refuses sorters with dedup" do
+      expect { feed([%w[a 3]], dedup: :last).upsert(path, output_path) }.to raise_error(ArgumentError, /dedup/)
+    end
+
+    it "merges feeds sorted in multiple runs" do
+      previous = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1)
+      20_000.times { |i| previous.add_row(["id-#{i}", "old", "x" * 50], i) }
+      previous.sort!
+      previous.save(path)
+
+      newer = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1)
+      20_000.times { |i| newer.add_row(["id-#{i + 10_000}", "new", "x" * 50], i) }
+      newer.sort!
+      result = newer.upsert(path, output_path)
+      expect(result).to include(total_rows: 30_000, added_rows: 10_000, replaced_rows: 10_000, kept_rows: 10_000)
+      expect(described_class.open(output_path).lookup(%w[id-15000])).to eq([["id-15000", "new", "x" * 50]])
+    end
+
+    it "rejects files sorted differently" do
+      expect { feed([%w[a 3]], hash: :sha256).upsert(path, output_path) }.to raise_error(CsvUtils::SortedFileError)
+      expect { feed([%w[a 3]]).upsert(path, path) }.to raise_error(ArgumentError)
+    end
+  end
+
+  describe "diff" do
+    before do
+      feed([%w[a 1], %w[a 2], %w[b 1], %w[c 1]]).save(path)
+    end
+
+    it "yields added, removed and changed keys" do
+      current = feed([%w[a 1], %w[a 3], %w[c 1], %w[d 1]])
+      changes = {}
+      result = current.diff(described_class.open(path)) do |change, key, previous_rows, rows|
+        changes[change] = [key, previous_rows, rows]
+      end
+
+      expect(result).to eq(added_keys: 1, removed_keys: 1, changed_keys: 1, unchanged_keys: 1)
+      expect(changes[:added][1..]).to eq([[], [%w[d 1]]])
+      expect(changes[:removed][1..]).to eq([[%w[b 1]], []])
+      expect(changes[:changed][1..]).to eq([[%w[a 2], %w[a 1]], [%w[a 3], %w[a 1]]])
+      expect(current.lookup_hex(changes[:changed][0])).to eq([%w[a 3], %w[a 1]])
+    end
+
+    it "compares a saved file with a sorter" do
+      result = described_class.open(path).diff(feed([%w[a 1], %w[a 2], %w[b 1]])) { |*| nil }
+      expect(result).to eq(added_keys: 1, removed_keys: 0, changed_keys: 0, unchanged_keys: 2)
+    end
+
+    it "compares a file or a sorter with itself" do
+      sorted_file = described_class.open(path)
+      expect(sorted_file.diff(sorted_file) { |*| nil })
+        .to eq(added_keys: 0, removed_keys: 0, changed_keys: 0, unchanged_keys: 3)
+
+      sorter = feed([%w[a 1], %w[a 2], %w[b 1], %w[c 1]])
+      expect(sorter.diff(sorter) { |*| nil })
+        .to eq(added_keys: 0, removed_keys: 0, changed_keys: 0, unchanged_keys: 3)
+    end
+
+    it "lets the block read both sides" do
+      previous = described_class.open(path)
+      current = feed([%w[a 1], %w[a 3], %w[c 1], %w[d 1]])
+      looked_up = []
+      result = current.diff(previous) do |_change, key, previous_rows, rows|
+        looked_up << [previous.lookup_hex(key), current.lookup_hex(key)]
+        expect(batches(previous).size).to eq(4)
+        expect(looked_up.last).to eq([previous_rows, rows])
+      end
+
+      expect(result).to eq(added_keys: 1, removed_keys: 1, changed_keys: 1, unchanged_keys: 1)
+      expect(looked_up.size).to eq(3)
+    end
+
+    it "stops when the sorter is cancelled" do
+      current = feed((0...20_000).map { |i| ["id-#{i}", "1"] })
+      expect { current.diff(described_class.open(path)) { current.cancel! } }
+        .to raise_error(CsvUtils::CancelledError)
+    end
+
+    it "writes a CSV report" do
+      report_path = Tempfile.new(["diff", ".csv"]).path
+      feed([%w[b 2], %w[c 1]]).diff(described_class.open(path), report: report_path)
+
+      report = CSV.read(report_path, headers: true)
+      expect(report.map { |row| [row["change"], row["previous_rows"], row["rows"]] })
+        .to contain_exactly(%w[removed 2 0], %w[changed 1 1])
+    end
+
+    it "re-raises errors from the block" do
+      expect { feed([%w[d 1]]).diff(described_class.open(path)) { raise "boom" } }.to raise_error("boom")
+    end
+
+    it "rejects outputs sorted differently" do
+      other = CsvUtils::Sorter.new(source_id, source_key, [1], nil, 100)
+      other.sort!
+      expect { other.diff(described_class.open(path)) { |*| nil } }.to raise_error(CsvUtils::SortedFileError)
+    end
+  end
+
+  it "rejects files it did not write" do
+    File.write(path, "id,name\n1,a\n")
+    expect { described_class.open(path) }.to raise_error(CsvUtils::SortedFileError)
+  end
+
+  it "rejects a header length too large to be real" do
+    File.binwrite(path, "CSVUSORT#{[1, 0xffffffff].pack("V2")}{}")
+    expect { described_class.open(path) }.to raise_error(CsvUtils::SortedFileError, /header length/)
+  end
+end
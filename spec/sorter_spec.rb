@@ -2,6 +2,10 @@
 
 require "csv_utils"
 require "csv"
+require "json"
+require "stringio"
+require "tmpdir"
+require "zlib"
 require "activerecord-copy"
 
 LITTLE_ENDIAN_BYTE_ORDER = 0x01
@@ -59,6 +63,83 @@ RSpec.describe CsvUtils::Sorter do
     expect(collect_rows(sorter)).to eq([%w[3 Jim 35], %w[2 Jane 30], %w[1 John 25]])
   end
 
+  it "accepts a gzipped file" do
+    file = Tempfile.new(["feed", ".csv.gz"])
+    Zlib::GzipWriter.open(file.path) do |gz|
+      gz.write("id,name,age\n1,John,25\n2,Jane,30\n3,Jim,35\n")
+    end
+
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    sorter.add_file(file.path)
+    result = sorter.sort!
+    expect(result[:total_rows]).to eq(3)
+    expect(collect_rows(sorter)).to eq([%w[3 Jim 35], %w[2 Jane 30], %w[1 John 25]])
+  end
+
+  it "doesn't take a CSV file starting with BZh for bzip2" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    sorter.add_io(StringIO.new("BZh,name\nBZh9,a\n1,b\n"))
+    expect(sorter.sort![:total_rows]).to eq(2)
+  end
+
+  it "accepts an IO stream" do
+    io = StringIO.new(<<~CSV)
+      id,name,age
+      1,John,25
+      2,Jane,30
+      3,Jim,35
+    CSV
+
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    sorter.add_io(io)
+    result = sorter.sort!
+    expect(result[:total_rows]).to eq(3)
+    expect(collect_rows(sorter)).to eq([%w[3 Jim 35], %w[2 Jane 30], %w[1 John 25]])
+  end
+
+  it "keeps the rest of a read that returns more than was asked for" do
+    data = "id,name\n#{(1..50_000).map { |i| "#{i},name-#{i}\n" }.join}"
+    io = Object.new
+    # Hands back everything on the first call, whatever length is asked for
+    io.define_singleton_method(:read) do |_length|
+      chunk = data
+      data = nil
+      chunk
+    end
+
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    sorter.add_io(io)
+    expect(sorter.sort![:total_rows]).to eq(50_000)
+  end
+
+  it "rejects objects that cannot be read" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    expect { sorter.add_io(42) }.to raise_error(ArgumentError)
+  end
+
+  it "accepts a file with a custom dialect" do
+    tsv_data = <<~TSV
+      # exported rows
+      1	'John'	25
+      2	'Jane'	30
+      3	'Jim'	35
+    TSV
+    file = Tempfile.new
+    file.write(tsv_data)
+    file.rewind
+
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    sorter.add_file(file.path, delimiter: "\t", quote: "'", headers: false, comment: "#")
+    result = sorter.sort!
+    expect(result[:total_rows]).to eq(3)
+    expect(collect_rows(sorter)).to eq([%w[3 Jim 35], %w[2 Jane 30], %w[1 John 25]])
+  end
+
+  it "rejects multi-character dialect options" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    expect { sorter.add_file(Tempfile.new.path, delimiter: "||") }.to raise_error(ArgumentError)
+  end
+
   it "sorts a CSV file with compound keys" do
     sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, 100)
     sorter.add_row(%w[1 2 3], 0)
@@ -119,6 +200,643 @@ RSpec.describe CsvUtils::Sorter do
     expect(count).to eq(1)
   end
 
+  describe "each_group" do
+    def grouped_sorter(**options)
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100, **options)
+      %w[a b a c a b].each_with_index { |key, i| sorter.add_row([key, i.to_s], i) }
+      sorter.sort!
+      sorter
+    end
+
+    it "yields each key once with its rows, most recent first" do
+      groups = {}
+      grouped_sorter.each_group { |key, rows| groups[key] = rows.map { |row| row[1] } }
+      expect(groups.size).to eq(3)
+      expect(groups.values).to match_array([%w[4 2 0], %w[5 1], %w[3]])
+      expect(groups.keys).to eq(groups.keys.sort)
+    end
+
+    it "limits group size" do
+      sizes = []
+      grouped_sorter.each_group(max_rows: 2) { |_key, rows| sizes << rows.size }
+      expect(sizes).to match_array([2, 2, 1])
+    end
+
+    it "honors max_targeting_key_rows" do
+      sizes = []
+      grouped_sorter(max_targeting_key_rows: 1).each_group { |_key, rows| sizes << rows.size }
+      expect(sizes).to eq([1, 1, 1])
+    end
+  end
+
+  describe "lookup" do
+    [nil, :zstd].each do |compression|
+      it "finds a key's rows after merging runs#{" with #{compression}" if compression}" do
+        sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, 1, compression: compression)
+        30_000.times { |i| sorter.add_row(["id-#{i % 3000}", "t", i.to_s, "x" * 50], i) }
+        expect(sorter.sort![:file_count]).to be > 1
+
+        rows = sorter.lookup(%w[id-1234 t])
+        expect(rows.map { |row| row[2] }).to eq(%w[28234 25234 22234 19234 16234 13234 10234 7234 4234 1234])
+        expect(sorter.lookup(%w[id-1234 u])).to eq([])
+      end
+    end
+
+    it "finds rows by hex key" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, 100)
+      sorter.add_row(%w[1 2 3], 0)
+      sorter.add_row(%w[1 3 2], 1)
+      sorter.sort!
+      expect(sorter.lookup_hex("3c9db9ba838cbefabdbd7ce6c6ca549d3f0e6743")).to eq([%w[1 3 2]])
+      expect(sorter.lookup_hex("0d1a3778431c4f1daffc613e793225ca2fee71c4")).to eq([])
+    end
+
+    it "rejects invalid keys" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, 100)
+      sorter.sort!
+      expect { sorter.lookup(%w[1]) }.to raise_error(ArgumentError)
+      expect { sorter.lookup_hex("xyz") }.to raise_error(ArgumentError)
+      expect { sorter.lookup_hex("abcd") }.to raise_error(ArgumentError)
+    end
+  end
+
+  %i[lz4 zstd].each do |compression|
+    it "sorts with #{compression} compressed run files" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, compression: compression)
+      30_000.times { |i| sorter.add_row(["id-#{i}", "x" * 50], i) }
+      result = sorter.sort!
+      expect(result[:total_rows]).to eq(30_000)
+      expect(result[:file_count]).to be > 1
+      expect(collect_rows(sorter).size).to eq(30_000)
+    end
+  end
+
+  it "merges many runs in multiple passes with a bounded fan-in" do
+    rows = Array.new(40_000) { |i| ["id-#{i % 5000}", "x" * 50, i.to_s] }
+
+    in_memory = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    rows.each_with_index { |row, i| in_memory.add_row(row, i) }
+    in_memory.sort!
+
+    external = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, merge_fan_in: 2)
+    rows.each_with_index { |row, i| external.add_row(row, i) }
+    result = external.sort!
+    expect(result[:file_count]).to be > 2
+    expect(result[:total_rows]).to eq(40_000)
+
+    expect(collect_rows(external)).to eq(collect_rows(in_memory))
+  end
+
+  it "writes runs in the background with parallel sorting" do
+    rows = Array.new(40_000) { |i| ["id-#{i % 5000}", "x" * 50, i.to_s] }
+
+    in_memory = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    rows.each_with_index { |row, i| in_memory.add_row(row, i) }
+    in_memory.sort!
+
+    background = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1,
+                                      background_spill: true, sort_threads: 2)
+    rows.each_with_index { |row, i| background.add_row(row, i) }
+    result = background.sort!
+    expect(result[:file_count]).to be > 1
+    expect(result[:total_rows]).to eq(40_000)
+
+    expect(collect_rows(background)).to eq(collect_rows(in_memory))
+  end
+
+  { sha1: 40, sha256: 64, xxh3_128: 32 }.each do |hash, hex_length|
+    it "builds #{hash} targeting keys" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100, hash: hash)
+      sorter.add_row(%w[a 1], 0)
+      sorter.add_row(%w[b 2], 1)
+      sorter.add_row(%w[a 3], 2)
+      sorter.sort!
+
+      keys = []
+      sorter.each_batch(10) { |batch| keys.concat(batch.map(&:first)) }
+      expect(keys.map(&:length).uniq).to eq([hex_length])
+      expect(keys.uniq.size).to eq(2)
+      expect(keys).to eq(keys.sort)
+    end
+  end
+
+  it "rejects unknown hash algorithms" do
+    expect do
+      CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, hash: :md5)
+    end.to raise_error(ArgumentError)
+  end
+
+  describe "key encoding" do
+    def keys_for(rows, **options)
+      sorter = CsvUtils::Sorter.new("1", "12345abcdef", [0, 1], nil, 100, **options)
+      rows.each_with_index { |row, i| sorter.add_row(row, i) }
+      sorter.sort!
+      keys = []
+      sorter.each_batch(10) { |batch| keys.concat(batch.map(&:first)) }
+      keys
+    end
+
+    it "keeps the legacy encoding by default" do
+      expect(keys_for([["a,b", "c"], ["a", "b,c"]]).uniq.size).to eq(1)
+    end
+
+    it "does not collide with length-prefixed encoding" do
+      expect(keys_for([["a,b", "c"], ["a", "b,c"]], key_encoding: :length_prefixed).uniq.size).to eq(2)
+    end
+
+    it "treats missing key columns as empty when asked" do
+      expect(keys_for([["a"], ["a", ""]], missing_key_columns: :empty).uniq.size).to eq(1)
+      expect(keys_for([["a"], ["a", ""]], key_encoding: :length_prefixed).uniq.size).to eq(2)
+    end
+
+    it "raises on missing key columns when asked" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, 100, missing_key_columns: :error)
+      expect { sorter.add_row(["a"], 0) }.to raise_error(CsvUtils::MissingKeyColumnError)
+    end
+
+    it "rejects rows with missing key columns when asked" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, 100, missing_key_columns: :reject)
+      sorter.enable_validation([], error_log_path)
+      expect(sorter.add_row(%w[a b], 0)).to be(true)
+      expect(sorter.add_row(["a"], 1)).to be(false)
+
+      result = sorter.sort!
+      expect(result[:total_rows]).to eq(1)
+      expect(result[:validation][:missing_key_error_count]).to eq(1)
+      expect(File.read(error_log_path)).to include("Key column 2 is missing,2,2")
+    end
+  end
+
+  describe "sorting by value" do
+    let(:rows) do
+      [
+        ["b", "10", "2024-03-01"],
+        ["a", "9", "2024-01-15"],
+        ["c", "", "2023-12-31"],
+        ["a", "100", "2024-02-01T12:00:00Z"]
+      ]
+    end
+
+    def sorted_rows(rows, buffer_size_mb, sort_by)
+      sorter = CsvUtils::Sorter.new("1", "12345abcdef", [0], nil, buffer_size_mb, sort_by: sort_by)
+      rows.each_with_index { |row, i| sorter.add_row(row, i) }
+      sorter.sort!
+      collect_rows(sorter)
+    end
+
+    it "sorts by string, then numeric descending" do
+      sort_by = [{ column: 0 }, { column: 1, type: :numeric, order: :desc }]
+      expect(sorted_rows(rows, 100, sort_by).map { |row| row[0..1] })
+        .to eq([%w[a 100], %w[a 9], %w[b 10], ["c", ""]])
+    end
+
+    it "puts unparseable numbers last" do
+      sort_by = [{ column: 1, type: :numeric }]
+      expect(sorted_rows(rows, 100, sort_by).map { |row| row[1] }).to eq(["9", "10", "100", ""])
+    end
+
+    it "sorts dates" do
+      sort_by = [{ column: 2, type: :date }]
+      expect(sorted_rows(rows, 100, sort_by).map(&:first)).to eq(%w[c a a b])
+    end
+
+    it "sorts formatted dates by their offsets" do
+      offset_rows = [
+        ["a", "2024-01-01 10:00:00 +0200"], # 08:00 UTC
+        ["b", "2024-01-01 09:00:00 +0000"], # 09:00 UTC
+        ["c", "2024-01-01 07:30:00 -0100"]  # 08:30 UTC
+      ]
+      sort_by = [{ column: 1, type: :date, format: "%Y-%m-%d %H:%M:%S %z" }]
+      expect(sorted_rows(offset_rows, 100, sort_by).map(&:first)).to eq(%w[a c b])
+    end
+
+    it "sorts by value across multiple runs" do
+      many = Array.new(30_000) { |i| ["id-#{i}", ((i * 7919) % 30_000).to_s, "x" * 50] }
+      sorted = sorted_rows(many, 1, [{ column: 1, type: :numeric }])
+      expect(sorted.map { |row| row[1].to_i }).to eq((0...30_000).to_a)
+    end
+
+    it "rejects invalid sort specs" do
+      expect do
+        CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, sort_by: [{ column: 0, type: :money }])
+      end.to raise_error(ArgumentError)
+    end
+  end
+
+  describe "max_targeting_key_rows" do
+    def capped_sorter(buffer_size_mb)
+      sorter = CsvUtils::Sorter.new("1", "12345abcdef", [0], nil, buffer_size_mb, max_targeting_key_rows: 3)
+      position = 0
+      { "a" => 5, "b" => 2, "c" => 4 }.each do |key, count|
+        count.times do
+          sorter.add_row([key, position.to_s, "x" * 50], position)
+          position += 1
+        end
+      end
+      sorter
+    end
+
+    it "drops rows beyond the cap and reports them" do
+      sorter = capped_sorter(100)
+      result = sorter.sort!
+      expect(result[:total_rows]).to eq(8)
+      expect(result[:dropped_rows]).to eq(3)
+      expect(result[:overflowed_key_count]).to eq(2)
+      expect(result[:overflowed_keys].map { |k| k[:dropped_rows] }).to match_array([2, 1])
+
+      rows = collect_rows(sorter)
+      expect(rows.size).to eq(8)
+      # The most recent rows for each key are kept
+      expect(rows.select { |row| row[0] == "a" }.map { |row| row[1] }).to eq(%w[4 3 2])
+    end
+
+    it "applies the cap to the binary postgres file" do
+      sorter = capped_sorter(100)
+      sorter.sort!
+      outfile_path = Tempfile.new.path
+      sorter.write_binary_postgres_file(outfile_path)
+
+      decoder = ActiveRecordCopy::Decoder.new(file: outfile_path,
+                                              column_types: %i[text text bytea character[] timestamp timestamp])
+      results = []
+      decoder.each { |result| results << result }
+      expect(results.size).to eq(8)
+    end
+
+    it "applies the cap when merging runs" do
+      sorter = CsvUtils::Sorter.new("1", "12345abcdef", [0], nil, 1, max_targeting_key_rows: 3)
+      20_000.times { |i| sorter.add_row(["id-#{i % 1000}", i.to_s, "x" * 50], i) }
+      result = sorter.sort!
+      expect(result[:file_count]).to be > 1
+      expect(result[:total_rows]).to eq(3000)
+      expect(result[:dropped_rows]).to eq(17_000)
+      expect(result[:overflowed_key_count]).to eq(1000)
+      expect(result[:overflowed_keys].size).to eq(100)
+    end
+  end
+
+  describe "dedup" do
+    def deduped(strategy, buffer_size_mb = 100)
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, buffer_size_mb, dedup: strategy)
+      [["a", "", "c1", ""], ["b", "x", "y", "z"], ["a", "b2", "", ""], ["a", "", "c3", ""]].each_with_index do |row, i|
+        sorter.add_row(row, i)
+      end
+      [sorter.sort!, collect_rows(sorter).sort_by(&:first)]
+    end
+
+    it "keeps every row by default" do
+      result, rows = deduped(:all)
+      expect(result[:collapsed_rows]).to eq(0)
+      expect(rows.size).to eq(4)
+    end
+
+    it "keeps the first row added for each key" do
+      result, rows = deduped(:first)
+      expect(result[:total_rows]).to eq(2)
+      expect(result[:collapsed_rows]).to eq(2)
+      expect(rows.map { |row| row[0..3] }).to eq([["a", "", "c1", ""], %w[b x y z]])
+    end
+
+    it "keeps the last row added for each key" do
+      result, rows = deduped(:last)
+      expect(result[:collapsed_rows]).to eq(2)
+      expect(rows.map { |row| row[0..3] }).to eq([["a", "", "c3", ""], %w[b x y z]])
+    end
+
+    it "fills empty fields of the last row from earlier rows" do
+      result, rows = deduped(:merge)
+      expect(result[:collapsed_rows]).to eq(2)
+      expect(rows.map { |row| row[0..3] }).to eq([["a", "b2", "c3", ""], %w[b x y z]])
+    end
+
+    it "collapses duplicates when merging runs" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, dedup: :last)
+      20_000.times { |i| sorter.add_row(["id-#{i % 1000}", i.to_s, "x" * 50], i) }
+      result = sorter.sort!
+      expect(result[:file_count]).to be > 1
+      expect(result[:total_rows]).to eq(1000)
+      expect(result[:collapsed_rows]).to eq(19_000)
+      expect(collect_rows(sorter).map { |row| row[1].to_i }).to all(be >= 19_000)
+    end
+
+    it "rejects unknown strategies and sort_by" do
+      expect do
+        CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100, dedup: :newest)
+      end.to raise_error(ArgumentError)
+      expect do
+        CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100, dedup: :last, sort_by: [{ column: 0 }])
+      end.to raise_error(ArgumentError)
+    end
+
+    it "rejects max_targeting_key_rows with sort_by" do
+      expect do
+        CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100, max_targeting_key_rows: 5, sort_by: [{ column: 0 }])
+      end.to raise_error(ArgumentError, /max_targeting_key_rows/)
+      expect do
+        CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100, sort_by: [{ column: 0 }])
+      end.not_to raise_error
+    end
+  end
+
+  describe "work_dir" do
+    let(:work_dir) { File.join(Dir.mktmpdir, "work") }
+
+    def feed_file(rows)
+      file = Tempfile.new(["feed", ".csv"])
+      file.write("id,value\n")
+      rows.each { |i| file.write("id-#{i},#{"x" * 50}\n") }
+      file.close
+      file
+    end
+
+    def work_sorter(**options)
+      CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, work_dir: work_dir, **options)
+    end
+
+    def manifest
+      JSON.parse(File.read(File.join(work_dir, "manifest.json")))
+    end
+
+    # Stands in for a worker killed before sort!: rows after the last run are lost, and the
+    # directory's lock goes with the process
+    def in_killed_worker(&block)
+      pid = fork do
+        block.call
+        exit!(0)
+      end
+      _, status = Process.wait2(pid)
+      expect(status).to be_success
+    end
+
+    it "resumes a file from the last run written" do
+      csv = feed_file(0...30_000)
+      in_killed_worker { work_sorter.add_file(csv.path) }
+      input = manifest["inputs"].first
+      expect(input["complete"]).to be(false)
+      expect(input["position"]).to be_between(1, 29_999)
+      expect(manifest["runs"]).not_to be_empty
+
+      resumed = work_sorter(merge_fan_in: 2)
+      resumed.add_file(csv.path)
+      expect(resumed.sort![:total_rows]).to eq(30_000)
+
+      uninterrupted = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1)
+      uninterrupted.add_file(csv.path)
+      uninterrupted.sort!
+      expect(collect_rows(resumed)).to eq(collect_rows(uninterrupted))
+      expect(Dir.children(work_dir)).to eq(["lock"])
+    end
+
+    it "skips files whose rows are all in runs" do
+      first = feed_file(0...20_000)
+      second = feed_file(20_000...40_000)
+      in_killed_worker do
+        sorter = work_sorter
+        sorter.add_file(first.path)
+        sorter.add_file(second.path)
+      end
+      expect(manifest["inputs"].first).to include("path" => File.realpath(first.path), "complete" => true)
+
+      resumed = work_sorter
+      resumed.add_file(first.path)
+      resumed.add_file(second.path)
+      expect(resumed.sort![:total_rows]).to eq(40_000)
+    end
+
+    it "matches inputs by their resolved path" do
+      csv = feed_file(0...30_000)
+      in_killed_worker { work_sorter.add_file(csv.path) }
+
+      resumed = work_sorter
+      resumed.add_file(File.join(File.dirname(csv.path), ".", File.basename(csv.path)))
+      expect(resumed.sort![:total_rows]).to eq(30_000)
+    end
+
+    it "keeps the validation counts of rows already in runs" do
+      csv = Tempfile.new(["feed", ".csv"])
+      csv.write("id,link\n")
+      30_000.times { |i| csv.write("id-#{i},#{i % 10 == 0 ? "bad" : "https://example.com/#{"x" * 50}"}\n") }
+      csv.close
+      rules = [{ column_name: "id", validation_type: :ignore }, { column_name: "link", validation_type: :protocol }]
+
+      in_killed_worker do
+        sorter = work_sorter
+        sorter.enable_validation(rules, error_log_path)
+        sorter.add_file(csv.path)
+      end
+      resumed = work_sorter
+      resumed.enable_validation(rules, error_log_path)
+      resumed.add_file(csv.path)
+      result = resumed.sort!
+
+      expect(result[:total_rows]).to eq(27_000)
+      expect(result[:validation]).to include(total_rows_processed: 30_000, failed_protocol_error_count: 3_000,
+                                             first_error_row: 0)
+      # The log keeps the errors from before the resume, without repeating any
+      errors = File.readlines(error_log_path).drop(1)
+      expect(errors.size).to eq(3_000)
+      expect(errors.uniq.size).to eq(3_000)
+    end
+
+    it "refuses runs from a sorter with different settings" do
+      sort_by = [{ column: 1, type: :numeric }]
+      in_killed_worker { work_sorter(sort_by: sort_by).add_file(feed_file(0...20_000).path) }
+      expect { work_sorter(sort_by: sort_by, hash: :sha256) }.to raise_error(CsvUtils::WorkDirError, /different hash/)
+      expect { work_sorter(sort_by: [{ column: 1, type: :numeric, order: :desc }]) }
+        .to raise_error(CsvUtils::WorkDirError, /sort_by/)
+      expect { work_sorter(sort_by: sort_by, missing_key_columns: :empty) }
+        .to raise_error(CsvUtils::WorkDirError, /missing key column policy/)
+    end
+
+    it "refuses runs from a sorter that would merge them differently" do
+      in_killed_worker { work_sorter.add_file(feed_file(0...20_000).path) }
+      expect { work_sorter(dedup: :last) }.to raise_error(CsvUtils::WorkDirError, /dedup/)
+      expect { work_sorter(max_targeting_key_rows: 5) }.to raise_error(CsvUtils::WorkDirError, /max_targeting_key_rows/)
+    end
+
+    it "can't be shared by two sorters" do
+      sorter = work_sorter
+      expect { work_sorter }.to raise_error(CsvUtils::WorkDirError, /in use by another sorter/)
+      sorter.add_row(["id-1", "x"], 0)
+      expect(sorter.sort![:total_rows]).to eq(1)
+    end
+  end
+
+  describe "spill_dir" do
+    def spilled_sorter(**options)
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, **options)
+      20_000.times { |i| sorter.add_row(["id-#{i}", "x" * 50], i) }
+      sorter
+    end
+
+    it "writes run files to the given directories" do
+      skip "needs /proc to see the sorter's open files" unless File.directory?("/proc/self/fd")
+
+      dirs = [Dir.mktmpdir, Dir.mktmpdir]
+      sorter = spilled_sorter(spill_dir: dirs)
+      # Run files are anonymous, so look for the ones the merge holds open
+      open_files = nil
+      sorter.on_progress(every_rows: 10_000) do |progress|
+        next unless progress[:phase] == :merge && open_files.nil?
+
+        open_files = Dir["/proc/self/fd/*"].filter_map do |fd|
+          File.readlink(fd)
+        rescue SystemCallError
+          nil
+        end
+      end
+      result = sorter.sort!
+
+      expect(result[:file_count]).to be > 1
+      expect(result[:total_rows]).to eq(20_000)
+      # The sorted output and at least two runs, spread over both directories
+      in_dirs = open_files.select { |file| dirs.any? { |dir| file.start_with?("#{dir}/") } }
+      expect(in_dirs.size).to be >= 3
+      dirs.each { |dir| expect(in_dirs).to include(start_with("#{dir}/")) }
+    end
+
+    it "rejects a missing directory" do
+      expect do
+        CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, spill_dir: "/does/not/exist")
+      end.to raise_error(ArgumentError)
+    end
+
+    it "can't be combined with work_dir" do
+      expect do
+        CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, spill_dir: Dir.mktmpdir, work_dir: Dir.mktmpdir)
+      end.to raise_error(ArgumentError, /work_dir/)
+    end
+
+    it "raises DiskLimitError when run files exceed max_spill_bytes" do
+      expect { spilled_sorter(max_spill_bytes: 100_000).sort! }
+        .to raise_error(CsvUtils::DiskLimitError, /max_spill_bytes/)
+      expect { spilled_sorter(max_spill_bytes: 100_000, background_spill: true).sort! }
+        .to raise_error(CsvUtils::DiskLimitError)
+      expect(spilled_sorter(max_spill_bytes: 100_000_000).sort![:total_rows]).to eq(20_000)
+    end
+  end
+
+  it "rejects unknown compression" do
+    expect do
+      CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1, compression: :rar)
+    end.to raise_error(ArgumentError)
+  end
+
+  it "raises CancelledError when sorting a cancelled sorter" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    sorter.add_row(%w[1 2 3], 0)
+    sorter.cancel!
+    expect { sorter.sort! }.to raise_error(CsvUtils::CancelledError)
+  end
+
+  it "lets other Ruby threads run while sorting" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1)
+    100_000.times { |i| sorter.add_row(["id-#{i}", "x" * 50], i) }
+
+    ticks = 0
+    ticker = Thread.new do
+      loop do
+        ticks += 1
+        sleep 0.001
+      end
+    end
+    sorter.sort!
+    ticker.kill
+    expect(ticks).to be > 0
+  end
+
+  it "keeps working after an interrupt handled by a trap" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1)
+    100_000.times { |i| sorter.add_row(["id-#{i}", "x" * 50], i) }
+    sorter.sort!
+
+    previous = trap("USR1") { nil }
+    begin
+      signaller = Thread.new do
+        sleep 0.01
+        Process.kill("USR1", Process.pid)
+      end
+      begin
+        sorter.write_binary_postgres_file(Tempfile.new.path)
+      rescue CsvUtils::CancelledError
+        nil
+      end
+      signaller.join
+    ensure
+      trap("USR1", previous)
+    end
+
+    outfile = Tempfile.new
+    sorter.write_binary_postgres_file(outfile.path)
+    expect(File.size(outfile.path)).to be > 0
+    expect(sorter.lookup(["id-1"]).size).to eq(1)
+  end
+
+  it "raises SorterBusyError when another thread calls the sorter during sort!" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1)
+    30_000.times { |i| sorter.add_row(["id-#{i}", "x" * 50], i) }
+
+    # The progress block runs in the middle of the merge; each call is made from a new
+    # thread while this one waits for it
+    calls = [
+      -> { sorter.add_row(%w[late x], 30_000) },
+      -> { sorter.lookup(["id-1"]) },
+      -> { sorter.each_batch(10) { |_batch| nil } },
+      -> { sorter.sort! }
+    ]
+    results = []
+    sorter.on_progress(every_rows: 10_000) do |progress|
+      next unless progress[:phase] == :merge && results.empty?
+
+      calls.each do |call|
+        results << Thread.new do
+          call.call
+        rescue CsvUtils::SorterBusyError => e
+          e
+        end.value
+      end
+    end
+
+    expect(sorter.sort![:total_rows]).to eq(30_000)
+    expect(results).to all(be_a(CsvUtils::SorterBusyError))
+    expect(results.size).to eq(calls.size)
+    expect(sorter.lookup(["id-1"]).size).to eq(1)
+  end
+
+  it "reports progress for each phase" do
+    rows = Array.new(30_000) { |i| "id-#{i},#{"x" * 50}" }
+    csv = Tempfile.new(["progress", ".csv"])
+    csv.write("id,value\n#{rows.join("\n")}\n")
+    csv.close
+
+    reports = []
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 1)
+    sorter.on_progress(every_rows: 10_000) { |progress| reports << progress }
+    sorter.add_file(csv.path)
+    sorter.sort!
+    sorter.each_batch(5_000) { |_batch| nil }
+    sorter.write_binary_postgres_file(Tempfile.new.path)
+
+    expect(reports.map { |r| r[:phase] }.uniq)
+      .to eq(%i[add_file run merge each_batch write_binary_postgres_file])
+
+    add_file = reports.select { |r| r[:phase] == :add_file }
+    expect(add_file.map { |r| r[:rows] }).to eq([10_000, 20_000, 30_000, 30_000])
+    expect(add_file.last[:bytes]).to eq(File.size(csv.path))
+    expect(add_file.last[:total_bytes]).to eq(File.size(csv.path))
+
+    merge = reports.select { |r| r[:phase] == :merge }.last
+    expect(merge[:rows]).to eq(30_000)
+    expect(merge[:bytes]).to eq(merge[:total_bytes])
+  end
+
+  it "aborts when the progress block raises" do
+    sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+    sorter.on_progress(every_rows: 1) { |_progress| raise ArgumentError, "stop" }
+    sorter.add_row(%w[1 2 3], 0)
+    expect { sorter.sort! }.to raise_error(ArgumentError, "stop")
+  end
+
   it "validates on add_row" do
     sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
     sorter.enable_validation([{ column_name: "my_url", validation_type: :url }], error_log_path)
@@ -208,5 +926,320 @@ RSpec.describe CsvUtils::Sorter do
                                        ]
                                      ])
     end
+
+    it "writes the columns given" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0, 1], nil, 100)
+      sorter.add_row(%w[1 2 3], 0)
+      sorter.add_row(%w[4 5], 7)
+      sorter.sort!
+      sorter.write_binary_postgres_file(outfile_path, columns: [
+                                          { value: :target_key, type: :bytea },
+                                          { value: :position },
+                                          { column: 2, type: :text },
+                                          { value: :source_key }
+                                        ])
+
+      decoder = ActiveRecordCopy::Decoder.new(file: outfile_path, column_types: %i[bytea bigint text text])
+      results = []
+      decoder.each { |result| results << result }
+      expect(results).to match_array([
+                                       [["d2736c67cf4728de554175f2533dc6662522db5b"].pack("H*"), 7, nil, source_key],
+                                       [["6ea87ee6f25f25d1e14c442a890eda7c722bca7a"].pack("H*"), 0, "3", source_key]
+                                     ])
+    end
+
+    it "converts CSV fields to typed columns" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+      sorter.add_row(["a", "42", "-12.50", "yes", "03/02/2000", "{\"n\": 1}",
+                      "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11", "2000-01-01T00:00:01Z"], 0)
+      sorter.sort!
+      result = sorter.write_binary_postgres_file(outfile_path, columns: [
+                                                   { column: 1, type: :int4 },
+                                                   { column: 1, type: :int8 },
+                                                   { column: 2, type: :numeric },
+                                                   { column: 2, type: :float8 },
+                                                   { column: 3, type: :bool },
+                                                   { column: 4, type: :date, format: "%m/%d/%Y" },
+                                                   { column: 5, type: :jsonb },
+                                                   { column: 6, type: :uuid },
+                                                   { column: 7, type: :timestamptz }
+                                                 ])
+      expect(result).to eq(total_rows: 1, conversion_error_count: 0)
+
+      # Compare the raw binary encodings
+      decoder = ActiveRecordCopy::Decoder.new(file: outfile_path, column_types: [:bytea] * 9)
+      results = []
+      decoder.each { |row| results << row }
+      expect(results).to eq([[
+                              [42].pack("l>"),
+                              [42].pack("q>"),
+                              [2, 0, 0x4000, 2, 12, 5000].pack("s>s>S>S>s>s>"),
+                              [-12.5].pack("G"),
+                              "\x01",
+                              [61].pack("l>"),
+                              "\x01{\"n\": 1}",
+                              ["a0eebc999c0b4ef8bb6d6bb9bd380a11"].pack("H*"),
+                              [1_000_000].pack("q>")
+                            ]])
+    end
+
+    it "writes null tokens and unconvertible fields as NULL" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+      sorter.enable_validation([], error_log_path)
+      sorter.add_row(["a", "1", ""], 0)
+      sorter.add_row(["b", "NULL", "x"], 1)
+      sorter.add_row(["c", "oops", "y"], 2)
+      sorter.sort!
+      result = sorter.write_binary_postgres_file(outfile_path, null: "NULL", columns: [
+                                                   { column: 0 },
+                                                   { column: 1, type: :int4 },
+                                                   { column: 2, null: ["", "y"] }
+                                                 ])
+      expect(result).to include(total_rows: 3, conversion_error_count: 1)
+      expect(result[:validation]).to include(conversion_error_count: 1, error_count: 1)
+      expect(File.read(error_log_path)).to include("Column 2 (int4) could not be converted,3,2")
+
+      # Each write counts its own conversion errors
+      again = sorter.write_binary_postgres_file(outfile_path, null: "NULL", columns: [{ column: 1, type: :int4 }])
+      expect(again[:validation]).to include(conversion_error_count: 1, error_count: 1)
+
+      # A row with several unconvertible fields logs each one but counts once
+      twice = sorter.write_binary_postgres_file(outfile_path, null: "NULL", columns: [
+                                                  { column: 1, type: :int4 },
+                                                  { column: 1, type: :int8 }
+                                                ])
+      expect(twice).to include(conversion_error_count: 2)
+      expect(twice[:validation]).to include(conversion_error_count: 1, error_count: 1)
+      expect(File.read(error_log_path)).to include("Column 2 (int8) could not be converted,3,2")
+
+      decoder = ActiveRecordCopy::Decoder.new(file: outfile_path, column_types: %i[text bytea text])
+      results = []
+      decoder.each { |row| results << row }
+      expect(results).to contain_exactly(["a", [1].pack("l>"), nil], ["b", nil, "x"], ["c", nil, nil])
+    end
+
+    it "writes JSON that jsonb can't hold as NULL" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+      sorter.add_row(["a", '{"text": "x\\u0000y"}'], 0)
+      sorter.add_row(["b", '{"text": "x\\\\u0000y"}'], 1)
+      sorter.sort!
+      result = sorter.write_binary_postgres_file(outfile_path, columns: [{ column: 0 }, { column: 1, type: :jsonb }])
+      expect(result).to eq(total_rows: 2, conversion_error_count: 1)
+
+      decoder = ActiveRecordCopy::Decoder.new(file: outfile_path, column_types: %i[text bytea])
+      results = []
+      decoder.each { |row| results << row }
+      expect(results).to contain_exactly(["a", nil], ["b", "\x01{\"text\": \"x\\\\u0000y\"}"])
+    end
+
+    it "rejects unknown column values and types" do
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+      sorter.sort!
+      [
+        [{ value: :nope }],
+        [{ value: :position, type: :text }],
+        [{ value: :source_key, column: 1 }],
+        [{ column: 1, type: :int4, format: "%d" }],
+        [{ value: :position, null: "" }],
+        []
+      ].each do |columns|
+        expect { sorter.write_binary_postgres_file(outfile_path, columns: columns) }.to raise_error(ArgumentError)
+      end
+    end
+  end
+
+  describe "copy_to_postgres", :postgres do
+    include PostgresHelpers
+
+    let(:table) { "csv_utils_copy_#{Process.pid}" }
+
+    before do
+      psql("CREATE TABLE #{table} (key text, position int8, amount numeric, note text)")
+    end
+
+    after do
+      psql("DROP TABLE IF EXISTS #{table}") if database_url
+    end
+
+    def sorter_with_rows
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+      sorter.add_row(%w[a 1.50 x], 0)
+      sorter.add_row(%w[b 2 y], 1)
+      sorter.sort!
+      sorter
+    end
+
+    it "copies the sorted rows into a table" do
+      columns = [
+        { column: 0, type: :text },
+        { value: :position },
+        { column: 1, type: :numeric },
+        { column: 2, type: :text }
+      ]
+      result = sorter_with_rows.copy_to_postgres(database_url, table, columns: columns, statement_timeout: 30)
+      expect(result).to eq(total_rows: 2, conversion_error_count: 0)
+      expect(psql("SELECT key, position, amount, note FROM #{table} ORDER BY key"))
+        .to eq(["a|0|1.50|x", "b|1|2|y"])
+    end
+
+    it "copies into the target columns given" do
+      columns = [{ column: 2 }, { column: 0 }]
+      sorter_with_rows.copy_to_postgres(database_url, table, columns: columns, target_columns: %w[note key])
+      expect(psql("SELECT key, position, note FROM #{table} ORDER BY key")).to eq(["a||x", "b||y"])
+    end
+
+    it "leaves the table untouched when the copy fails" do
+      psql("INSERT INTO #{table} (key) VALUES ('existing')")
+      expect do
+        sorter_with_rows.copy_to_postgres(database_url, table, columns: [{ value: :record }])
+      end.to raise_error(CsvUtils::PostgresError)
+      expect(psql("SELECT key FROM #{table}")).to eq(["existing"])
+    end
+
+    it "cancels a statement waiting on the server" do
+      locker = Process.spawn({ "PGAPPNAME" => "csv_utils_locker" }, "psql", database_url, "-qc",
+                             "BEGIN; LOCK TABLE #{table}; SELECT pg_sleep(30)", %i[out err] => File::NULL)
+      sleep 0.1 while psql("SELECT count(*) FROM pg_locks JOIN pg_class ON pg_class.oid = relation " \
+                           "WHERE relname = '#{table}' AND granted") == ["0"]
+
+      sorter = sorter_with_rows
+      Thread.new do
+        sleep 0.5
+        sorter.cancel!
+      end
+      started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+      expect { sorter.copy_to_postgres(database_url, table) }.to raise_error(CsvUtils::CancelledError)
+      expect(Process.clock_gettime(Process::CLOCK_MONOTONIC) - started).to be < 5
+    ensure
+      psql("SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE application_name = 'csv_utils_locker'")
+      Process.wait(locker) if locker
+    end
+
+    it "raises PostgresError when it can't connect" do
+      expect do
+        sorter_with_rows.copy_to_postgres("host=/nonexistent port=1", table)
+      end.to raise_error(CsvUtils::PostgresError)
+    end
+  end
+
+  describe "load_into_postgres", :postgres do
+    include PostgresHelpers
+
+    let(:table) { "csv_utils_load_#{Process.pid}" }
+    let(:columns) { [{ value: :source_key }, { column: 0 }, { column: 1, type: :int4 }] }
+    let(:target_columns) { %w[source_key key amount] }
+
+    before do
+      psql("CREATE TABLE #{table} (id serial PRIMARY KEY, source_key text, key text, amount int4 CHECK (amount > 0))")
+      psql("INSERT INTO #{table} (source_key, key, amount) VALUES ('#{source_key}', 'old', 1), ('other', 'kept', 2)")
+    end
+
+    after do
+      psql("DROP TABLE IF EXISTS #{table}") if database_url
+    end
+
+    def sorter_with_rows(*rows)
+      sorter = CsvUtils::Sorter.new(source_id, source_key, [0], nil, 100)
+      rows.each_with_index { |row, index| sorter.add_row(row, index) }
+      sorter.sort!
+      sorter
+    end
+
+    def staging_tables
+      psql("SELECT relname FROM pg_class WHERE relname LIKE '#{table}_staging_%' AND relkind = 'r'")
+    end
+
+    it "replaces the rows for the source key" do
+      sorter = sorter_with_rows(%w[a 10], %w[b 11])
+      result = sorter.load_into_postgres(database_url, table, columns: columns, target_columns: target_columns,
+                                                              analyze: true)
+      expect(result).to eq(total_rows: 2, conversion_error_count: 0, removed_rows: 1)
+      expect(psql("SELECT source_key, key, amount FROM #{table} ORDER BY key"))
+        .to eq(["#{source_key}|a|10", "#{source_key}|b|11", "other|kept|2"])
+      expect(staging_tables).to be_empty
+    end
+
+    it "replaces the whole table" do
+      sorter = sorter_with_rows(%w[a 10])
+      result = sorter.load_into_postgres(database_url, table, columns: columns, target_columns: target_columns,
+                                                              replace: :table)
+      expect(result).to eq(total_rows: 1, conversion_error_count: 0, removed_rows: 2)
+      expect(psql("SELECT source_key, key, amount FROM #{table}")).to eq(["#{source_key}|a|10"])
+      psql("INSERT INTO #{table} (key, amount) VALUES ('c', 3)")
+      expect(psql("SELECT count(DISTINCT id) FROM #{table}")).to eq(["2"])
+      expect(staging_tables).to be_empty
+    end
+
+    it "keeps the table's identity column, grants and triggers when replacing it" do
+      psql("DROP TABLE #{table}")
+      psql("CREATE TABLE #{table} (id int GENERATED ALWAYS AS IDENTITY PRIMARY KEY, source_key text, key text, " \
+           "amount int4, updated boolean DEFAULT false)")
+      psql("GRANT SELECT ON #{table} TO PUBLIC")
+      psql("CREATE FUNCTION #{table}_mark() RETURNS trigger LANGUAGE plpgsql AS " \
+           "'BEGIN NEW.updated := true; RETURN NEW; END'")
+      psql("CREATE TRIGGER mark BEFORE INSERT ON #{table} FOR EACH ROW EXECUTE FUNCTION #{table}_mark()")
+
+      2.times do
+        sorter_with_rows(%w[a 10], %w[b 11]).load_into_postgres(database_url, table, columns: columns,
+                                                                                     target_columns: target_columns,
+                                                                                     replace: :table)
+      end
+      psql("INSERT INTO #{table} (key, amount) VALUES ('c', 3)")
+      expect(psql("SELECT id, key, updated FROM #{table} ORDER BY id")).to eq(%w[3|a|t 4|b|t 5|c|t])
+      expect(psql("SELECT has_table_privilege('public', '#{table}', 'SELECT')")).to eq(["t"])
+      expect(staging_tables).to be_empty
+    ensure
+      psql("DROP FUNCTION IF EXISTS #{table}_mark() CASCADE") if database_url
+    end
+
+    it "lets other sessions read the old rows while replacing the table" do
+      # Holds the load between deleting the old rows and committing the new ones
+      psql("CREATE FUNCTION #{table}_wait() RETURNS trigger LANGUAGE plpgsql AS " \
+           "'BEGIN PERFORM pg_sleep(2); RETURN NULL; END'")
+      psql("CREATE TRIGGER wait AFTER INSERT ON #{table} FOR EACH STATEMENT EXECUTE FUNCTION #{table}_wait()")
+
+      load = Thread.new do
+        sorter_with_rows(%w[a 10]).load_into_postgres(database_url, table, columns: columns,
+                                                                           target_columns: target_columns,
+                                                                           replace: :table)
+      end
+      sleep 1
+      expect(psql("SET statement_timeout = 500; SELECT key FROM #{table} ORDER BY key")).to eq(%w[kept old])
+      load.join
+      expect(psql("SELECT key FROM #{table}")).to eq(%w[a])
+    ensure
+      psql("DROP FUNCTION IF EXISTS #{table}_wait() CASCADE") if database_url
+    end
+
+    it "raises PostgresError when replacing rows another table references" do
+      psql("CREATE TABLE #{table}_ref (id int REFERENCES #{table})")
+      psql("INSERT INTO #{table}_ref SELECT id FROM #{table} WHERE key = 'kept'")
+      expect do
+        sorter_with_rows(%w[a 10]).load_into_postgres(database_url, table, columns: columns,
+                                                                           target_columns: target_columns,
+                                                                           replace: :table)
+      end.to raise_error(CsvUtils::PostgresError, /foreign key/)
+      expect(psql("SELECT key FROM #{table} ORDER BY key")).to eq(%w[kept old])
+    ensure
+      psql("DROP TABLE IF EXISTS #{table}_ref") if database_url
+    end
+
+    it "leaves the table untouched when the load fails" do
+      sorter = sorter_with_rows(%w[a -1])
+      %i[source_key table].each do |replace|
+        expect do
+          sorter.load_into_postgres(database_url, table, columns: columns, target_columns: target_columns,
+                                                         replace: replace)
+        end.to raise_error(CsvUtils::PostgresError, /amount_check/)
+      end
+      expect(psql("SELECT key FROM #{table} ORDER BY key")).to eq(%w[kept old])
+      expect(staging_tables).to be_empty
+    end
+
+    it "rejects an unknown replace option" do
+      expect do
+        sorter_with_rows(%w[a 1]).load_into_postgres(database_url, table, replace: :rows)
+      end.to raise_error(ArgumentError, /replace/)
+    end
   end
 end
@@ -2,6 +2,8 @@
 
 require "csv_utils"
 
+Dir[File.join(__dir__, "support", "*.rb")].each { |file| require file }
+
 RSpec.configure do |config|
   # Enable flags like --only-failures and --next-failure
   config.example_status_persistence_file_path = ".rspec_status"
@@ -12,4 +14,9 @@ RSpec.configure do |config|
   config.expect_with :rspec do |c|
     c.syntax = :expect
   end
+
+  # Examples tagged :postgres need a database to load into; they are reported as pending without one
+  config.before(:example, :postgres) do
+    skip "Set DATABASE_URL to a PostgreSQL database to run this example" unless ENV["DATABASE_URL"]
+  end
 end
@@ -0,0 +1,18 @@
+# frozen_string_literal: true
+
+require "open3"
+
+# For examples tagged :postgres, which run against the database in DATABASE_URL, using psql to set up and
+# inspect tables
+module PostgresHelpers
+  def database_url
+    ENV.fetch("DATABASE_URL", nil)
+  end
+
+  def psql(sql)
+    output, status = Open3.capture2("psql", database_url, "-Atqc", sql)
+    raise "psql failed: #{sql}" unless status.success?
+
+    output.lines.map(&:chomp)
+  end
+end